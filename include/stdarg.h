@@ -0,0 +1,15 @@
+#ifndef __STDARG_H
+#define __STDARG_H
+
+typedef __builtin_va_list va_list;
+
+#define va_start(ap, last) __builtin_va_start(ap, last)
+#define va_arg(ap, type) __builtin_va_arg(ap, type)
+#define va_copy(dest, src) __builtin_va_copy(dest, src)
+#define va_end(ap) __builtin_va_end(ap)
+
+/* The name glibc's headers use for va_list. */
+typedef va_list __gnuc_va_list;
+#define __GNUC_VA_LIST 1
+
+#endif
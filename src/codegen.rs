@@ -1,110 +1,1089 @@
+use std::cell::{Cell, RefCell};
+use std::convert::TryInto;
+
 use crate::parse::*;
+use crate::token::error;
+use crate::types::*;
 
-pub fn gen(node: Node) {
-    if let Node::Number { val } = node {
-        println!("  push {}", val);
-        return;
+const ARG_REG8: [&str; 6] = ["dil", "sil", "dl", "cl", "r8b", "r9b"];
+const ARG_REG16: [&str; 6] = ["di", "si", "dx", "cx", "r8w", "r9w"];
+const ARG_REG32: [&str; 6] = ["edi", "esi", "edx", "ecx", "r8d", "r9d"];
+const ARG_REG64: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+thread_local! {
+    static LABEL: Cell<usize> = const { Cell::new(0) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static CURRENT_FN: RefCell<String> = const { RefCell::new(String::new()) };
+    // Jump targets of `break` for the enclosing loops and switches.
+    static BREAK: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    // Stack depths at which the enclosing compound assignments pushed the
+    // address of their target.
+    static TARGETS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+fn count() -> usize {
+    LABEL.with(|c| {
+        c.set(c.get() + 1);
+        c.get()
+    })
+}
+
+fn push() {
+    println!("  push rax");
+    DEPTH.with(|d| d.set(d.get() + 1));
+}
+
+fn pop(reg: &str) {
+    println!("  pop {}", reg);
+    DEPTH.with(|d| d.set(d.get() - 1));
+}
+
+// A long double is computed in st(0) and takes two slots on the stack.
+fn push_ld() {
+    println!("  sub rsp, 16");
+    println!("  fstp tbyte ptr [rsp]");
+    DEPTH.with(|d| d.set(d.get() + 2));
+}
+
+fn pop_ld() {
+    println!("  fld tbyte ptr [rsp]");
+    println!("  add rsp, 16");
+    DEPTH.with(|d| d.set(d.get() - 2));
+}
+
+fn push_value(ty: &Type) {
+    if ty.kind == TypeKind::LDouble {
+        push_ld();
+    } else {
+        push();
+    }
+}
+
+// Pops a value of type `ty` into rax, or into st(0) for a long double.
+fn pop_value(ty: &Type) {
+    if ty.kind == TypeKind::LDouble {
+        pop_ld();
+    } else {
+        pop("rax");
+    }
+}
+
+pub fn codegen(prog: Program) {
+    println!(".intel_syntax noprefix");
+    emit_data(&prog.globals);
+    emit_text(prog.functions);
+}
+
+fn emit_data(globals: &[GVar]) {
+    for gvar in globals {
+        if !gvar.is_definition {
+            continue;
+        }
+        if !gvar.is_static {
+            println!("  .globl {}", gvar.name);
+        }
+        let init = match &gvar.init {
+            Some(init) => init,
+            None => {
+                println!("  .bss");
+                println!("  .align {}", gvar.ty.align().max(gvar.align));
+                println!("{}:", gvar.name);
+                println!("  .zero {}", gvar.ty.size());
+                continue;
+            }
+        };
+        println!("  .data");
+        println!("  .align {}", gvar.ty.align().max(gvar.align));
+        println!("{}:", gvar.name);
+        let mut relocs = gvar.relocs.iter().peekable();
+        let mut pos = 0;
+        while pos < init.len() {
+            match relocs.peek() {
+                Some(rel) if rel.offset == pos => {
+                    println!("  .quad {}{:+}", rel.label, rel.addend);
+                    relocs.next();
+                    pos += 8;
+                }
+                _ => {
+                    println!("  .byte {}", init[pos]);
+                    pos += 1;
+                }
+            }
+        }
     }
-    if let Node::Return(rhs) = node {
-        gen(*rhs);
-        println!("  pop rax");
+}
+
+fn emit_text(functions: Vec<Function>) {
+    println!("  .text");
+    for func in functions {
+        if !func.is_static {
+            println!("  .globl {}", func.name);
+        }
+        println!("{}:", func.name);
+        CURRENT_FN.with(|f| *f.borrow_mut() = func.name.clone());
+
+        println!("  push rbp");
+        println!("  mov rbp, rsp");
+        println!("  sub rsp, {}", func.stack_size);
+
+        // Parameters passed in memory are above the return address.
+        let tys: Vec<Type> = func.params.iter().map(|(_, ty)| ty.clone()).collect();
+        for ((offset, ty), loc) in func.params.iter().zip(classify_args(&tys).0) {
+            match loc {
+                ArgLoc::Gp(i) => store_reg(i, *offset, ty),
+                ArgLoc::Fp(i) => {
+                    println!("  {} [rbp-{}], xmm{}", if ty.kind == TypeKind::Float { "movss" } else { "movsd" }, offset, i);
+                }
+                ArgLoc::Stack(arg_offset) if ty.kind == TypeKind::LDouble => {
+                    println!("  fld tbyte ptr [rbp+{}]", 16 + arg_offset);
+                    println!("  fstp tbyte ptr [rbp-{}]", offset);
+                }
+                ArgLoc::Stack(arg_offset) => {
+                    println!("  mov rax, [rbp+{}]", 16 + arg_offset);
+                    println!("  mov [rbp-{}], {}", offset, reg_ax(ty.size()));
+                }
+            }
+        }
+
+        if let Some(offset) = func.va_area {
+            for (i, reg) in ARG_REG64.iter().enumerate() {
+                println!("  mov [rbp-{}], {}", offset as usize - i * 8, reg);
+            }
+            for i in 0..8 {
+                println!("  movsd [rbp-{}], xmm{}", offset as usize - 48 - i * 16, i);
+            }
+        }
+
+        gen(func.body);
+        if DEPTH.with(|d| d.get()) != 0 {
+            error("internal error: unbalanced stack");
+        }
+
+        // main() implicitly returns 0 when control reaches the end of it.
+        if func.name == "main" {
+            println!("  mov rax, 0");
+        }
+        println!(".L.return.{}:", func.name);
         println!("  mov rsp, rbp");
         println!("  pop rbp");
         println!("  ret");
+    }
+}
+
+fn store_reg(r: usize, offset: i32, ty: &Type) {
+    let reg = match ty.size() {
+        1 => ARG_REG8[r],
+        2 => ARG_REG16[r],
+        4 => ARG_REG32[r],
+        _ => ARG_REG64[r],
+    };
+    println!("  mov [rbp-{}], {}", offset, reg);
+}
+
+fn reg_ax(size: usize) -> &'static str {
+    match size {
+        1 => "al",
+        2 => "ax",
+        4 => "eax",
+        _ => "rax",
+    }
+}
+
+// Loads the value at the address in rax into rax. Every load and store is
+// emitted where the source has it, which is what volatile objects need; any
+// future optimization must leave accesses to `ty.is_volatile` objects alone.
+fn load(ty: &Type) {
+    match ty.kind {
+        // An array decays to a pointer to its first element and a function
+        // designator to the function's address, so the address is the value.
+        TypeKind::Array(..) | TypeKind::Func { .. } | TypeKind::Struct(_) => {}
+        TypeKind::Bool => println!("  movzx eax, byte ptr [rax]"),
+        TypeKind::Char if ty.is_unsigned => println!("  movzx eax, byte ptr [rax]"),
+        TypeKind::Char => println!("  movsx rax, byte ptr [rax]"),
+        TypeKind::Short if ty.is_unsigned => println!("  movzx eax, word ptr [rax]"),
+        TypeKind::Short => println!("  movsx rax, word ptr [rax]"),
+        TypeKind::Int if ty.is_unsigned => println!("  mov eax, dword ptr [rax]"),
+        TypeKind::Int => println!("  movsxd rax, dword ptr [rax]"),
+        // Floating values are kept in rax as their bit patterns.
+        TypeKind::Float => println!("  mov eax, dword ptr [rax]"),
+        _ => println!("  mov rax, [rax]"),
+    }
+}
+
+// Loads the value of type `ty` at the address in rax into rax, or into
+// st(0) for a long double. A bit-field is extracted from its storage unit.
+fn load_value(ty: &Type, bit_field: Option<(usize, usize)>) {
+    if let Some((bit_offset, width)) = bit_field {
+        load(ty);
+        // Move the field to the top bits, then shift it back down, which
+        // also sign or zero extends it.
+        println!("  shl rax, {}", 64 - width - bit_offset);
+        extract_bit_field(ty, width);
+    } else if ty.kind == TypeKind::LDouble {
+        println!("  fld tbyte ptr [rax]");
+    } else {
+        load(ty);
+    }
+}
+
+// Evaluates the address of `lhs` and the value `rhs`, and stores the value.
+// For a compound assignment the address stays on the stack while `rhs` is
+// evaluated, for its `AssignTarget` to load the current value from; with
+// `post`, that value is loaded first and is the result.
+fn gen_assign(lhs: Node, rhs: Node, ty: &Type, compound: bool, post: bool) {
+    let bit_field = match lhs {
+        Node::Member { bit_field, .. } => bit_field,
+        _ => None,
+    };
+    gen_lval(lhs);
+    if compound {
+        TARGETS.with(|t| t.borrow_mut().push(DEPTH.with(|d| d.get())));
+    }
+    if post {
+        gen(Node::AssignTarget { bit_field, ty: ty.clone() });
+    }
+    gen(rhs);
+    if compound {
+        TARGETS.with(|t| t.borrow_mut().pop());
+    }
+    if ty.kind == TypeKind::LDouble {
+        pop_ld();
+        if post {
+            pop_ld();
+            println!("  fxch st(1)");
+        }
+        pop("rax");
+        println!("  fstp tbyte ptr [rax]");
+        if !post {
+            println!("  fld tbyte ptr [rax]");
+        }
+        push_ld();
         return;
     }
-    if let Node::If { cond, i_st, e_st } = node {
-        gen(*cond);
-        println!("  pop rax");
-        println!("  cmp rax, 0");
-        if let Node::Number { val } = *e_st {
-            println!("  je  .Lend001");
-            gen(*i_st);
-            println!(".Lend001:");
-            return;
+    pop("rdi");
+    if post {
+        pop("rsi");
+    }
+    pop("rax");
+    match bit_field {
+        Some((bit_offset, width)) => {
+            // Replace the field's bits in its storage unit and write the unit back.
+            println!("  mov r8, rax");
+            println!("  mov r9, rdi");
+            println!("  mov r10, {}", u64::MAX >> (64 - width));
+            println!("  and rdi, r10");
+            println!("  shl rdi, {}", bit_offset);
+            load(ty);
+            println!("  mov r10, {}", !((u64::MAX >> (64 - width)) << bit_offset));
+            println!("  and rax, r10");
+            println!("  or rdi, rax");
+            println!("  mov rax, r8");
+            store(ty);
+            // The value of the assignment is the one the field now holds.
+            println!("  mov rax, r9");
+            println!("  shl rax, {}", 64 - width);
+            extract_bit_field(ty, width);
+        }
+        None => {
+            store(ty);
+            println!("  mov rax, rdi");
+        }
+    }
+    if post {
+        println!("  mov rax, rsi");
+    }
+    push();
+}
+
+// Stores rdi to the address in rax. A struct value is represented by its
+// address, so storing one copies it byte by byte and leaves its new address in rdi.
+fn store(ty: &Type) {
+    if ty.is_struct() {
+        for i in 0..ty.size() {
+            println!("  mov r8b, [rdi+{}]", i);
+            println!("  mov [rax+{}], r8b", i);
+        }
+        println!("  mov rdi, rax");
+        return;
+    }
+    let reg = match ty.size() {
+        1 => "dil",
+        2 => "di",
+        4 => "edi",
+        _ => "rdi",
+    };
+    println!("  mov [rax], {}", reg);
+}
+
+// Shifts a bit-field that occupies the top `width` bits of rax down to the
+// bottom, extending it according to its type.
+fn extract_bit_field(ty: &Type, width: usize) {
+    if ty.is_unsigned {
+        println!("  shr rax, {}", 64 - width);
+    } else {
+        println!("  sar rax, {}", 64 - width);
+    }
+}
+
+// Values narrower than 64 bits are kept in rax sign- or zero-extended
+// according to their type. This truncates rax to the width of `ty` and
+// extends it back, which converts any integer or pointer value to `ty`.
+fn extend(ty: &Type) {
+    match ty.kind {
+        TypeKind::Char if ty.is_unsigned => println!("  movzx eax, al"),
+        TypeKind::Char => println!("  movsx rax, al"),
+        TypeKind::Short if ty.is_unsigned => println!("  movzx eax, ax"),
+        TypeKind::Short => println!("  movsx rax, ax"),
+        TypeKind::Int if ty.is_unsigned => println!("  mov eax, eax"),
+        TypeKind::Int => println!("  movsxd rax, eax"),
+        _ => {}
+    }
+}
+
+// Converts the value in rax, or st(0) for a long double, from type `from`
+// to type `to`. Float and double values travel through xmm0 for the
+// conversion instructions.
+fn cast(from: &Type, to: &Type) {
+    if to.kind == TypeKind::Void {
+        if from.kind == TypeKind::LDouble {
+            println!("  fstp st(0)");
         }
-        println!("  je  .Lelse001");
-        gen(*i_st);
-        println!("  jmp .Lend001");
-        println!(".Lelse001:");
-        gen(*e_st);
-        println!(".Lend001:");
         return;
     }
-    if let Node::LVar { offset: _ } = node {
-        gen_lval(node);
-        println!("  pop rax");
-        println!("  mov rax, [rax]");
-        println!("  push rax");
+    if to.kind == TypeKind::Bool {
+        cast_bool(from);
         return;
     }
-    if let Node::Operator { kind: NodeKind::NdAssign, lhs, rhs } = node {
-        gen_lval(*lhs);
-        gen(*rhs);
-        println!("  pop rdi");
-        println!("  pop rax");
-        println!("  mov [rax], rdi");
-        println!("  push rdi");
+    if from.kind == TypeKind::LDouble || to.kind == TypeKind::LDouble {
+        cast_ld(from, to);
         return;
     }
-    if let Node::Block(stmts) = node {
-        for stmt in stmts {
-            gen(stmt);
-            println!("  pop rax");
+    let suffix = |ty: &Type| if ty.kind == TypeKind::Float { "ss" } else { "sd" };
+    match (from.is_flonum(), to.is_flonum()) {
+        (false, false) => extend(to),
+        (false, true) => {
+            if from.is_unsigned && from.size() == 8 {
+                // cvtsi2sd is signed, so a value with the top bit set is
+                // halved (keeping the low bit for rounding) and doubled back.
+                let c = count();
+                println!("  test rax, rax");
+                println!("  js .L.cast.{}", c);
+                println!("  cvtsi2{} xmm0, rax", suffix(to));
+                println!("  jmp .L.cast.end.{}", c);
+                println!(".L.cast.{}:", c);
+                println!("  mov rdi, rax");
+                println!("  and eax, 1");
+                println!("  shr rdi, 1");
+                println!("  or rdi, rax");
+                println!("  cvtsi2{} xmm0, rdi", suffix(to));
+                println!("  add{} xmm0, xmm0", suffix(to));
+                println!(".L.cast.end.{}:", c);
+            } else {
+                println!("  cvtsi2{} xmm0, rax", suffix(to));
+            }
+            from_xmm0(to);
+        }
+        (true, false) => {
+            println!("  movq xmm0, rax");
+            if to.is_unsigned && to.size() == 8 {
+                // cvttsd2si is signed, so a value of 2^63 or more has 2^63
+                // subtracted before the conversion and the top bit xored back in.
+                let c = count();
+                if from.kind == TypeKind::Float {
+                    println!("  mov eax, {}", 2f32.powi(63).to_bits());
+                } else {
+                    println!("  mov rax, {}", 2f64.powi(63).to_bits());
+                }
+                println!("  movq xmm1, rax");
+                println!("  comi{} xmm0, xmm1", suffix(from));
+                println!("  jae .L.cast.{}", c);
+                println!("  cvtt{}2si rax, xmm0", suffix(from));
+                println!("  jmp .L.cast.end.{}", c);
+                println!(".L.cast.{}:", c);
+                println!("  sub{} xmm0, xmm1", suffix(from));
+                println!("  cvtt{}2si rax, xmm0", suffix(from));
+                println!("  btc rax, 63");
+                println!(".L.cast.end.{}:", c);
+            } else {
+                println!("  cvtt{}2si rax, xmm0", suffix(from));
+            }
+            extend(to);
+        }
+        (true, true) => {
+            if from.kind != to.kind {
+                println!("  movq xmm0, rax");
+                println!("  cvt{}2{} xmm0, xmm0", suffix(from), suffix(to));
+                from_xmm0(to);
+            }
+        }
+    }
+}
+
+// Any nonzero scalar converts to a `_Bool` of 1, including a NaN.
+fn cast_bool(from: &Type) {
+    match from.kind {
+        TypeKind::Bool => {}
+        TypeKind::LDouble => {
+            println!("  fldz");
+            gen_ld_op(NodeKind::NdNEq);
+        }
+        TypeKind::Float | TypeKind::Double => {
+            println!("  xor edi, edi");
+            gen_float_op(NodeKind::NdNEq, from, &Type::int());
+        }
+        _ => {
+            println!("  cmp rax, 0");
+            println!("  setne al");
+            println!("  movzx eax, al");
+        }
+    }
+}
+
+// Conversions to and from long double go through memory below the stack
+// pointer, where the x87 load and store instructions can reach them.
+fn cast_ld(from: &Type, to: &Type) {
+    let width = |ty: &Type| if ty.kind == TypeKind::Float { "dword" } else { "qword" };
+    if from.kind == to.kind {
+        return;
+    }
+    println!("  sub rsp, 8");
+    if from.kind == TypeKind::LDouble {
+        if to.is_flonum() {
+            println!("  fstp {} ptr [rsp]", width(to));
+        } else if to.is_unsigned && to.size() == 8 {
+            // fisttp is signed, so a value of 2^63 or more has 2^63
+            // subtracted before the conversion and the top bit xored back in.
+            let c = count();
+            println!("  mov rax, {}", 2f64.powi(63).to_bits());
+            println!("  mov [rsp], rax");
+            println!("  fld qword ptr [rsp]");
+            println!("  fcomip st, st(1)");
+            println!("  ja .L.cast.{}", c);
+            println!("  fsub qword ptr [rsp]");
+            println!("  fisttp qword ptr [rsp]");
+            println!("  btc qword ptr [rsp], 63");
+            println!("  jmp .L.cast.end.{}", c);
+            println!(".L.cast.{}:", c);
+            println!("  fisttp qword ptr [rsp]");
+            println!(".L.cast.end.{}:", c);
+        } else {
+            println!("  fisttp qword ptr [rsp]");
+        }
+        println!("  mov rax, [rsp]");
+        println!("  add rsp, 8");
+        if !to.is_flonum() {
+            extend(to);
         }
         return;
     }
-    if let Node::Operator { kind, lhs, rhs } = node {
-        gen(*lhs);
-        gen(*rhs);
-        println!("  pop rdi");
-        println!("  pop rax");
-        match kind {
-            NodeKind::NdAdd => {
-                println!("  add rax, rdi");
+    println!("  mov [rsp], rax");
+    if from.is_flonum() {
+        println!("  fld {} ptr [rsp]", width(from));
+    } else {
+        println!("  fild qword ptr [rsp]");
+        if from.is_unsigned && from.size() == 8 {
+            // fild is signed: a value with the top bit set needs 2^64 added.
+            let c = count();
+            println!("  test rax, rax");
+            println!("  jns .L.cast.end.{}", c);
+            println!("  mov rax, {}", 2f64.powi(64).to_bits());
+            println!("  mov [rsp], rax");
+            println!("  fadd qword ptr [rsp]");
+            println!(".L.cast.end.{}:", c);
+        }
+    }
+    println!("  add rsp, 8");
+}
+
+// Moves a floating value of type `ty` from xmm0 to rax.
+fn from_xmm0(ty: &Type) {
+    if ty.kind == TypeKind::Float {
+        println!("  movd eax, xmm0");
+    } else {
+        println!("  movq rax, xmm0");
+    }
+}
+
+pub fn gen(node: Node) {
+    match node {
+        Node::Number { val, .. } => {
+            println!("  mov rax, {}", val);
+            push();
+        }
+        Node::FNumber { val, x87, ty } => {
+            if ty.kind == TypeKind::LDouble {
+                println!("  sub rsp, 16");
+                println!("  mov rax, {}", u64::from_le_bytes(x87[..8].try_into().unwrap()));
+                println!("  mov [rsp], rax");
+                println!("  mov word ptr [rsp+8], {}", u16::from_le_bytes([x87[8], x87[9]]));
+                println!("  fld tbyte ptr [rsp]");
+                println!("  add rsp, 16");
+                push_ld();
+                return;
+            }
+            let bits = if ty.kind == TypeKind::Float { (val as f32).to_bits() as u64 } else { val.to_bits() };
+            println!("  mov rax, {}", bits);
+            push();
+        }
+        Node::LVar { ref ty, .. } | Node::GVar { ref ty, .. } | Node::Deref { ref ty, .. } | Node::Member { ref ty, .. } => {
+            let ty = ty.clone();
+            let bit_field = match node {
+                Node::Member { bit_field, .. } => bit_field,
+                _ => None,
+            };
+            gen_lval(node);
+            pop("rax");
+            load_value(&ty, bit_field);
+            push_value(&ty);
+        }
+        Node::AssignTarget { ty, bit_field } => {
+            // The address was pushed by the enclosing compound assignment.
+            let slots = DEPTH.with(|d| d.get()) - TARGETS.with(|t| *t.borrow().last().unwrap());
+            println!("  mov rax, [rsp+{}]", slots * 8);
+            load_value(&ty, bit_field);
+            push_value(&ty);
+        }
+        Node::Addr { expr, .. } => {
+            gen_lval(*expr);
+        }
+        Node::CompoundLiteral { init, var } => {
+            gen(*init);
+            gen(*var);
+        }
+        Node::Cast { expr, ty } => {
+            let from = expr.ty();
+            gen(*expr);
+            pop_value(&from);
+            cast(&from, &ty);
+            push_value(&ty);
+        }
+        Node::VaStart { ap, gp_offset, fp_offset, overflow_offset, save_area } => {
+            gen(*ap);
+            pop("rax");
+            println!("  mov dword ptr [rax], {}", gp_offset);
+            println!("  mov dword ptr [rax+4], {}", fp_offset);
+            println!("  lea rdx, [rbp+{}]", overflow_offset);
+            println!("  mov [rax+8], rdx");
+            println!("  lea rdx, [rbp-{}]", save_area);
+            println!("  mov [rax+16], rdx");
+            push();
+        }
+        // The sign bit is flipped with a mask for float and double, which
+        // live in rax, and with fchs for long double.
+        Node::Neg { expr, ty } => {
+            gen(*expr);
+            if ty.kind == TypeKind::LDouble {
+                pop_ld();
+                println!("  fchs");
+                push_ld();
+            } else {
+                pop("rax");
+                if ty.kind == TypeKind::Float {
+                    println!("  xor eax, 0x80000000");
+                } else {
+                    println!("  mov rdx, 0x8000000000000000");
+                    println!("  xor rax, rdx");
+                }
+                push();
+            }
+        }
+        Node::Bswap { expr, ty } => {
+            gen(*expr);
+            pop("rax");
+            match ty.size() {
+                2 => {
+                    println!("  rol ax, 8");
+                    println!("  movzx eax, ax");
+                }
+                4 => println!("  bswap eax"),
+                _ => println!("  bswap rax"),
+            }
+            push();
+        }
+        Node::VaArg { ap, ty } => {
+            // Take the next argument from the register save area while
+            // there are registers left, then from the stack.
+            // Integers use the six 8-byte general-purpose slots tracked by
+            // gp_offset, floating values the 16-byte XMM slots after them
+            // tracked by fp_offset.
+            let (field, limit, step) = if ty.is_flonum() { ("rax+4", 176, 16) } else { ("rax", 48, 8) };
+            let c = count();
+            gen(*ap);
+            pop("rax");
+            if ty.kind == TypeKind::LDouble {
+                // Always passed in memory, 16-byte aligned.
+                println!("  mov rdi, [rax+8]");
+                println!("  add rdi, 15");
+                println!("  and rdi, -16");
+                println!("  lea rdx, [rdi+16]");
+                println!("  mov [rax+8], rdx");
+                println!("  fld tbyte ptr [rdi]");
+                push_ld();
+                return;
+            }
+            println!("  mov edx, dword ptr [{}]", field);
+            println!("  cmp edx, {}", limit);
+            println!("  jae .L.va_arg.stack.{}", c);
+            println!("  lea ecx, [edx+{}]", step);
+            println!("  mov dword ptr [{}], ecx", field);
+            println!("  mov rdi, [rax+16]");
+            println!("  add rdi, rdx");
+            println!("  jmp .L.va_arg.end.{}", c);
+            println!(".L.va_arg.stack.{}:", c);
+            println!("  mov rdi, [rax+8]");
+            println!("  lea rdx, [rdi+8]");
+            println!("  mov [rax+8], rdx");
+            println!(".L.va_arg.end.{}:", c);
+            println!("  mov rax, rdi");
+            load(&ty);
+            push();
+        }
+        Node::Call { func, args, ty } => {
+            // The upper bits of a narrow return value are unspecified.
+            gen_call(*func, args);
+            if ty.kind == TypeKind::LDouble {
+                push_ld();
+                return;
+            }
+            if ty.is_flonum() {
+                from_xmm0(&ty);
+            } else if ty.kind == TypeKind::Bool {
+                // Only al holds a `_Bool`.
+                println!("  movzx eax, al");
+            } else {
+                extend(&ty);
+            }
+            push();
+        }
+        Node::Return(rhs) => {
+            // A long double is returned in st(0), where popping it leaves it.
+            if let Some(rhs) = rhs {
+                let ty = rhs.ty();
+                gen(*rhs);
+                pop_value(&ty);
+                if ty.is_flonum() && ty.kind != TypeKind::LDouble {
+                    println!("  movq xmm0, rax");
+                }
+            }
+            println!("  jmp .L.return.{}", CURRENT_FN.with(|f| f.borrow().clone()));
+        }
+        Node::If { cond, i_st, e_st } => {
+            let c = count();
+            gen(*cond);
+            pop("rax");
+            println!("  cmp rax, 0");
+            println!("  je  .L.else.{}", c);
+            gen(*i_st);
+            println!("  jmp .L.end.{}", c);
+            println!(".L.else.{}:", c);
+            if let Some(e_st) = e_st {
+                gen(*e_st);
             }
-            NodeKind::NdSub => {
-                println!("  sub rax, rdi");
+            println!(".L.end.{}:", c);
+        }
+        Node::While { cond, st } => {
+            let c = count();
+            println!(".L.begin.{}:", c);
+            gen(*cond);
+            pop("rax");
+            println!("  cmp rax, 0");
+            println!("  je  .L.end.{}", c);
+            BREAK.with(|b| b.borrow_mut().push(format!(".L.end.{}", c)));
+            gen(*st);
+            BREAK.with(|b| b.borrow_mut().pop());
+            println!("  jmp .L.begin.{}", c);
+            println!(".L.end.{}:", c);
+        }
+        Node::For { cond_1, cond_2, cond_3, st } => {
+            let c = count();
+            if let Some(init) = cond_1 {
+                gen(*init);
+                pop("rax");
             }
-            NodeKind::NdMul => {
-                println!("  imul rax, rdi");
+            println!(".L.begin.{}:", c);
+            if let Some(cond) = cond_2 {
+                gen(*cond);
+                pop("rax");
+                println!("  cmp rax, 0");
+                println!("  je  .L.end.{}", c);
             }
-            NodeKind::NdDiv => {
-                println!("  cqo");
-                println!("  idiv rdi");
+            BREAK.with(|b| b.borrow_mut().push(format!(".L.end.{}", c)));
+            gen(*st);
+            BREAK.with(|b| b.borrow_mut().pop());
+            if let Some(inc) = cond_3 {
+                gen(*inc);
+                pop("rax");
             }
-            NodeKind::NdEq => {
-                println!("  cmp rax, rdi");
-                println!("  sete al");
-                println!("  movzb rax, al");
+            println!("  jmp .L.begin.{}", c);
+            println!(".L.end.{}:", c);
+        }
+        Node::Switch { cond, st, id, cases, has_default } => {
+            // The cases are compared at the width of the promoted condition.
+            let (ax, di) = if cond.ty().size() == 8 { ("rax", "rdi") } else { ("eax", "edi") };
+            gen(*cond);
+            pop("rax");
+            for (i, val) in cases.iter().enumerate() {
+                println!("  mov rdi, {}", val);
+                println!("  cmp {}, {}", ax, di);
+                println!("  je  .L.switch.{}.case.{}", id, i);
             }
-            NodeKind::NdNEq => {
-                println!("  cmp rax, rdi");
-                println!("  setne al");
-                println!("  movzb rax, al");
+            if has_default {
+                println!("  jmp .L.switch.{}.default", id);
+            } else {
+                println!("  jmp .L.switch.{}.end", id);
             }
-            NodeKind::NdLe | NodeKind::NdGe => {
-                println!("  cmp rax, rdi");
-                println!("  setle al");
-                println!("  movzb rax, al");
+            BREAK.with(|b| b.borrow_mut().push(format!(".L.switch.{}.end", id)));
+            gen(*st);
+            BREAK.with(|b| b.borrow_mut().pop());
+            println!(".L.switch.{}.end:", id);
+        }
+        Node::Case { id, idx, st } => {
+            match idx {
+                Some(i) => println!(".L.switch.{}.case.{}:", id, i),
+                None => println!(".L.switch.{}.default:", id),
             }
-            NodeKind::NdLt | NodeKind::NdGt => {
-                println!("  cmp rax, rdi");
-                println!("  satl al");
-                println!("  movzb rax, al");
+            gen(*st);
+        }
+        Node::Break => {
+            println!("  jmp {}", BREAK.with(|b| b.borrow().last().unwrap().clone()));
+        }
+        Node::Block(stmts) => {
+            for stmt in stmts {
+                gen(stmt);
             }
-            _ => (),
         }
-        println!("  push rax");
+        Node::MemZero { offset, size } => {
+            println!("  mov rcx, {}", size);
+            println!("  lea rdi, [rbp-{}]", offset);
+            println!("  mov al, 0");
+            println!("  rep stosb");
+        }
+        Node::ExprStmt(expr) => {
+            let ty = expr.ty();
+            gen(*expr);
+            pop_value(&ty);
+            if ty.kind == TypeKind::LDouble {
+                println!("  fstp st(0)");
+            }
+        }
+        Node::Operator { kind: NodeKind::NdAssign, lhs, rhs, ty } => {
+            gen_assign(*lhs, *rhs, &ty, false, false);
+        }
+        Node::OpAssign { lhs, rhs, ty, post } => {
+            gen_assign(*lhs, *rhs, &ty, true, post);
+        }
+        Node::Operator { kind: kind @ (NodeKind::NdLogAnd | NodeKind::NdLogOr), lhs, rhs, .. } => {
+            // Jump out with the result as soon as one operand decides it.
+            let c = count();
+            let (jump, short) = if let NodeKind::NdLogAnd = kind { ("je", 0) } else { ("jne", 1) };
+            gen(*lhs);
+            pop("rax");
+            println!("  cmp rax, 0");
+            println!("  {} .L.short.{}", jump, c);
+            gen(*rhs);
+            pop("rax");
+            println!("  cmp rax, 0");
+            println!("  {} .L.short.{}", jump, c);
+            println!("  mov rax, {}", 1 - short);
+            println!("  jmp .L.end.{}", c);
+            println!(".L.short.{}:", c);
+            println!("  mov rax, {}", short);
+            println!(".L.end.{}:", c);
+            push();
+        }
+        Node::Cond { cond, then, els, .. } => {
+            let c = count();
+            gen(*cond);
+            pop("rax");
+            println!("  cmp rax, 0");
+            println!("  je  .L.else.{}", c);
+            // Each branch leaves one value on the stack.
+            let depth = DEPTH.with(|d| d.get());
+            gen(*then);
+            println!("  jmp .L.end.{}", c);
+            DEPTH.with(|d| d.set(depth));
+            println!(".L.else.{}:", c);
+            gen(*els);
+            println!(".L.end.{}:", c);
+        }
+        Node::Comma { lhs, rhs } => {
+            gen(Node::ExprStmt(lhs));
+            gen(*rhs);
+        }
+        Node::Operator { kind, lhs, rhs, ty } if lhs.ty().kind == TypeKind::LDouble => {
+            gen(*lhs);
+            gen(*rhs);
+            pop_ld();
+            pop_ld();
+            gen_ld_op(kind);
+            push_value(&ty);
+        }
+        Node::Operator { kind, lhs, rhs, ty } => {
+            // Comparisons look at the operands' type, everything else at the result's.
+            let lty = lhs.ty();
+            let unsigned_cmp = lty.is_unsigned || lty.base().is_some();
+            gen(*lhs);
+            gen(*rhs);
+            pop("rdi");
+            pop("rax");
+            if lty.is_flonum() {
+                gen_float_op(kind, &lty, &ty);
+                push();
+                return;
+            }
+            match kind {
+                NodeKind::NdAdd => {
+                    println!("  add rax, rdi");
+                }
+                NodeKind::NdSub => {
+                    println!("  sub rax, rdi");
+                }
+                NodeKind::NdMul => {
+                    println!("  imul rax, rdi");
+                }
+                NodeKind::NdDiv | NodeKind::NdMod => {
+                    if ty.is_unsigned {
+                        println!("  xor edx, edx");
+                        println!("  div rdi");
+                    } else {
+                        println!("  cqo");
+                        println!("  idiv rdi");
+                    }
+                    if let NodeKind::NdMod = kind {
+                        println!("  mov rax, rdx");
+                    }
+                }
+                NodeKind::NdBitAnd => {
+                    println!("  and rax, rdi");
+                }
+                NodeKind::NdBitOr => {
+                    println!("  or rax, rdi");
+                }
+                NodeKind::NdBitXor => {
+                    println!("  xor rax, rdi");
+                }
+                NodeKind::NdShl => {
+                    println!("  mov rcx, rdi");
+                    println!("  shl rax, cl");
+                }
+                NodeKind::NdShr => {
+                    println!("  mov rcx, rdi");
+                    if ty.is_unsigned {
+                        println!("  shr rax, cl");
+                    } else {
+                        println!("  sar rax, cl");
+                    }
+                }
+                NodeKind::NdEq => {
+                    println!("  cmp rax, rdi");
+                    println!("  sete al");
+                    println!("  movzb rax, al");
+                }
+                NodeKind::NdNEq => {
+                    println!("  cmp rax, rdi");
+                    println!("  setne al");
+                    println!("  movzb rax, al");
+                }
+                NodeKind::NdLe | NodeKind::NdGe => {
+                    println!("  cmp rax, rdi");
+                    println!("  {} al", if unsigned_cmp { "setbe" } else { "setle" });
+                    println!("  movzb rax, al");
+                }
+                NodeKind::NdLt | NodeKind::NdGt => {
+                    println!("  cmp rax, rdi");
+                    println!("  {} al", if unsigned_cmp { "setb" } else { "setl" });
+                    println!("  movzb rax, al");
+                }
+                NodeKind::NdLogAnd | NodeKind::NdLogOr | NodeKind::NdAssign => unreachable!(),
+            }
+            // Wrap arithmetic to the width of the result.
+            extend(&ty);
+            push();
+        }
+    }
+}
+
+// Applies a binary operator to the floating values in rax and rdi, both of
+// type `ty`, leaving a result of type `result` in rax.
+fn gen_float_op(kind: NodeKind, ty: &Type, result: &Type) {
+    let sfx = if ty.kind == TypeKind::Float { "ss" } else { "sd" };
+    println!("  movq xmm0, rax");
+    println!("  movq xmm1, rdi");
+    let op = match kind {
+        NodeKind::NdAdd => "add",
+        NodeKind::NdSub => "sub",
+        NodeKind::NdMul => "mul",
+        NodeKind::NdDiv => "div",
+        // An unordered comparison, with a NaN operand, sets the parity flag:
+        // NaN compares unequal to everything and is not less than anything.
+        NodeKind::NdEq => {
+            println!("  ucomi{} xmm0, xmm1", sfx);
+            println!("  sete al");
+            println!("  setnp dl");
+            println!("  and al, dl");
+            println!("  movzx eax, al");
+            return;
+        }
+        NodeKind::NdNEq => {
+            println!("  ucomi{} xmm0, xmm1", sfx);
+            println!("  setne al");
+            println!("  setp dl");
+            println!("  or al, dl");
+            println!("  movzx eax, al");
+            return;
+        }
+        NodeKind::NdLt | NodeKind::NdGt | NodeKind::NdLe | NodeKind::NdGe => {
+            let set = if let NodeKind::NdLt | NodeKind::NdGt = kind { "seta" } else { "setae" };
+            println!("  ucomi{} xmm1, xmm0", sfx);
+            println!("  {} al", set);
+            println!("  movzx eax, al");
+            return;
+        }
+        _ => unreachable!(),
+    };
+    println!("  {}{} xmm0, xmm1", op, sfx);
+    from_xmm0(result);
+}
+
+// Applies a binary operator to the long doubles in st(0) and st(1), popping
+// both. An arithmetic result is left in st(0), a comparison result in rax.
+fn gen_ld_op(kind: NodeKind) {
+    let op = match kind {
+        NodeKind::NdAdd => "fadd",
+        NodeKind::NdSub => "fsub",
+        NodeKind::NdMul => "fmul",
+        NodeKind::NdDiv => "fdiv",
+        _ => {
+            // Compare the right operand against the left so that "above"
+            // means "less than", which is false for unordered operands.
+            println!("  fxch st(1)");
+            println!("  fcomip st, st(1)");
+            println!("  fstp st(0)");
+            match kind {
+                NodeKind::NdEq => {
+                    println!("  sete al");
+                    println!("  setnp dl");
+                    println!("  and al, dl");
+                }
+                NodeKind::NdNEq => {
+                    println!("  setne al");
+                    println!("  setp dl");
+                    println!("  or al, dl");
+                }
+                NodeKind::NdLt | NodeKind::NdGt => println!("  seta al"),
+                NodeKind::NdLe | NodeKind::NdGe => println!("  setae al"),
+                _ => unreachable!(),
+            }
+            println!("  movzx eax, al");
+            return;
+        }
+    };
+    println!("  {} st, st(1)", op);
+    println!("  fstp st(1)");
+}
+
+fn gen_call(func: Node, args: Vec<Node>) {
+    // A function designator is called directly, anything else through a
+    // pointer kept on the stack below the arguments.
+    let direct = match &func {
+        Node::GVar { name, ty } if matches!(ty.kind, TypeKind::Func { .. }) => Some(name.clone()),
+        _ => None,
+    };
+    if direct.is_none() {
+        gen(func);
+    }
+
+    let tys: Vec<Type> = args.iter().map(|arg| arg.ty()).collect();
+    let (locs, stack_size) = classify_args(&tys);
+    let stack_slots = stack_size / 8;
+    // The stack must be 16-byte aligned at the call instruction.
+    let pad = (DEPTH.with(|d| d.get()) + stack_slots) % 2 == 1;
+    if pad {
+        println!("  sub rsp, 8");
+        DEPTH.with(|d| d.set(d.get() + 1));
+    }
+    // Arguments passed in memory are stored into an area reserved at the
+    // bottom of the stack; the others are pushed and then popped into
+    // their registers.
+    if stack_size > 0 {
+        println!("  sub rsp, {}", stack_size);
+        DEPTH.with(|d| d.set(d.get() + stack_slots));
+    }
+    let mut reg_args = Vec::new();
+    for ((arg, loc), ty) in args.into_iter().zip(locs).zip(tys) {
+        match loc {
+            ArgLoc::Stack(offset) => {
+                gen(arg);
+                pop_value(&ty);
+                if ty.kind == TypeKind::LDouble {
+                    println!("  fstp tbyte ptr [rsp+{}]", offset);
+                } else {
+                    println!("  mov [rsp+{}], rax", offset);
+                }
+            }
+            _ => reg_args.push((arg, loc)),
+        }
+    }
+    let mut fp = 0;
+    let regs: Vec<ArgLoc> = reg_args.iter().rev().map(|(_, loc)| match loc {
+        ArgLoc::Gp(i) => ArgLoc::Gp(*i),
+        ArgLoc::Fp(i) => ArgLoc::Fp(*i),
+        ArgLoc::Stack(_) => unreachable!(),
+    }).collect();
+    for (arg, _) in reg_args {
+        gen(arg);
+    }
+    for loc in regs {
+        match loc {
+            ArgLoc::Gp(i) => pop(ARG_REG64[i]),
+            ArgLoc::Fp(i) => {
+                pop("rax");
+                println!("  movq xmm{}, rax", i);
+                fp += 1;
+            }
+            ArgLoc::Stack(_) => unreachable!(),
+        }
+    }
+    // A variadic callee learns from al how many vector registers are used.
+    println!("  mov eax, {}", fp);
+    let mut cleanup = stack_slots + pad as usize;
+    match direct {
+        Some(name) => println!("  call {}", name),
+        None => {
+            println!("  mov r10, [rsp+{}]", cleanup * 8);
+            println!("  call r10");
+            cleanup += 1;
+        }
+    }
+    if cleanup > 0 {
+        println!("  add rsp, {}", cleanup * 8);
+        DEPTH.with(|d| d.set(d.get() - cleanup));
     }
 }
 
 fn gen_lval(node: Node) {
-    if let Node::LVar { offset } = node {
-        println!("  mov rax, rbp");
-        println!("  sub rax, {}", offset);
-        println!("  push rax");
-    } else {
-        return;
+    match node {
+        Node::LVar { offset, .. } => {
+            println!("  lea rax, [rbp-{}]", offset);
+            push();
+        }
+        Node::GVar { name, .. } => {
+            println!("  lea rax, {}[rip]", name);
+            push();
+        }
+        Node::Deref { expr, .. } => {
+            gen(*expr);
+        }
+        Node::CompoundLiteral { init, var } => {
+            gen(*init);
+            gen_lval(*var);
+        }
+        Node::Member { expr, offset, .. } => {
+            gen_lval(*expr);
+            pop("rax");
+            println!("  add rax, {}", offset);
+            push();
+        }
+        _ => error("not an lvalue"),
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,19 @@
+//! Headers that come with the compiler. They describe the compiler's own
+//! type sizes and builtins, so they are searched before the system include
+//! directories, whose versions of these headers belong to another compiler.
+
+const HEADERS: [(&str, &str); 8] = [
+    ("float.h", include_str!("../include/float.h")),
+    ("limits.h", include_str!("../include/limits.h")),
+    ("stdalign.h", include_str!("../include/stdalign.h")),
+    ("stdarg.h", include_str!("../include/stdarg.h")),
+    ("stdbool.h", include_str!("../include/stdbool.h")),
+    ("stddef.h", include_str!("../include/stddef.h")),
+    ("stdint.h", include_str!("../include/stdint.h")),
+    ("stdnoreturn.h", include_str!("../include/stdnoreturn.h")),
+];
+
+/// The contents of the bundled header included as `<name>`, if there is one.
+pub fn find(name: &str) -> Option<&'static str> {
+    HEADERS.iter().find(|(n, _)| *n == name).map(|(_, src)| *src)
+}
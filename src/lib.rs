@@ -1,6 +1,9 @@
 pub mod token;
 pub mod codegen;
+pub mod headers;
 pub mod parse;
+pub mod preprocess;
+pub mod types;
 
 #[cfg(test)]
 mod tests {
@@ -10,4 +13,383 @@ mod tests {
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn global_initializers() {
+        let mut tokens = token::tokenize("int g[3] = {1, 2}; int *p = &g[1] + 1; char s[] = \"hi\";".to_string());
+        let prog = parse::program(&mut tokens);
+        let g = &prog.globals[0];
+        assert_eq!(g.init.as_ref().unwrap(), &vec![1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        let p = &prog.globals[1];
+        assert_eq!(p.relocs[0].label, "g");
+        assert_eq!(p.relocs[0].addend, 8);
+        let s = &prog.globals[2];
+        assert_eq!(s.ty.size(), 3);
+    }
+
+    #[test]
+    fn struct_layout() {
+        let mut tokens = token::tokenize("struct S { char a; long b; short c; } s; union U { char c[5]; int i; } u;".to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].ty.size(), 24);
+        assert_eq!(prog.globals[0].ty.align(), 8);
+        assert_eq!(prog.globals[1].ty.size(), 8);
+        assert_eq!(prog.globals[1].ty.align(), 4);
+
+        // `_Alignas` raises the alignment of a member and of its struct.
+        let mut tokens = token::tokenize("struct A { _Alignas(8) char c; char d; _Alignas(double) int e, f; _Alignas(16) struct { int i; }; } a;".to_string());
+        let prog = parse::program(&mut tokens);
+        let ty = &prog.globals[0].ty;
+        assert_eq!((ty.size(), ty.align()), (48, 16));
+        if let types::TypeKind::Struct(agg) = &ty.kind {
+            let offsets: Vec<usize> = agg.borrow().members.iter().map(|m| m.offset).collect();
+            assert_eq!(offsets, vec![0, 1, 8, 16, 32]);
+        }
+    }
+
+    #[test]
+    fn designated_initializers() {
+        let src = "struct P { char x; char y; }; struct P ps[] = {[1] = {.y = 2}, 3}; char a[2][2] = {1, 2, {3}};";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &vec![0, 0, 0, 2, 3, 0]);
+        assert_eq!(prog.globals[1].init.as_ref().unwrap(), &vec![1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn braced_string_initializers() {
+        let src = "char g[] = {\"abc\"}; char h[2][3] = {{\"x\"}, \"yz\"};
+                   int f(void) { char s[] = {\"abcd\",}; _Static_assert(sizeof s == 5, \"local size\"); return s[0]; }";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].ty.size(), 4);
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &b"abc\0".to_vec());
+        assert_eq!(prog.globals[1].init.as_ref().unwrap(), &b"x\0\0yz\0".to_vec());
+    }
+
+    #[test]
+    fn function_pointer_declarators() {
+        use types::TypeKind;
+        let src = "int (*fp)(int); int (*tab[3])(int, char *); struct S { long (*op)(void); } s;";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        let fp = &prog.globals[0].ty;
+        assert_eq!(fp.size(), 8);
+        assert!(matches!(&fp.base().unwrap().kind, TypeKind::Func { params, .. } if params.len() == 1));
+        let tab = &prog.globals[1].ty;
+        assert_eq!(tab.size(), 24);
+        assert!(matches!(&tab.base().unwrap().base().unwrap().kind, TypeKind::Func { params, .. } if params.len() == 2));
+        assert_eq!(prog.globals[2].ty.size(), 8);
+    }
+
+    #[test]
+    fn storage_classes() {
+        let src = "extern int e; static int f(void); int f(void) { static int n = 7; return n; }";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert!(!prog.globals[0].is_definition);
+        assert!(prog.functions[0].is_static);
+        let n = prog.globals.iter().find(|g| g.name.starts_with("f.n.")).unwrap();
+        assert!(n.is_static && n.is_definition);
+        assert_eq!(n.init.as_ref().unwrap(), &vec![7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn tentative_definitions() {
+        let src = "int x = 3; int x; static int y; static int y = 4; static int y; int z; int z;";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals.len(), 3);
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &vec![3, 0, 0, 0]);
+        assert_eq!(prog.globals[1].init.as_ref().unwrap(), &vec![4, 0, 0, 0]);
+        assert!(prog.globals[1].is_static);
+        assert!(prog.globals[2].is_definition && prog.globals[2].init.is_none());
+    }
+
+    #[test]
+    fn type_qualifiers() {
+        let mut tokens = token::tokenize("int const *p; int *const volatile q = 0;".to_string());
+        let prog = parse::program(&mut tokens);
+        let p = &prog.globals[0].ty;
+        assert!(!p.is_const && p.base().unwrap().is_const);
+        let q = &prog.globals[1].ty;
+        assert!(q.is_const && q.is_volatile && !q.base().unwrap().is_const);
+    }
+
+    #[test]
+    fn usual_arithmetic_conversions() {
+        let src = "long a = -1 / 2u; long b = -1 >> 1; long c = -1u >> 1; unsigned char d = 255;";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        let value = |i: usize| prog.globals[i].init.clone().unwrap();
+        assert_eq!(value(0), 0x7fffffffi64.to_le_bytes());
+        assert_eq!(value(1), (-1i64).to_le_bytes());
+        assert_eq!(value(2), 0x7fffffffi64.to_le_bytes());
+        assert!(prog.globals[3].ty.is_unsigned);
+    }
+
+    #[test]
+    fn casts() {
+        let src = "int x = (char)300; unsigned long y = (unsigned char)-1; long *p = (long *)&x;";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &vec![44, 0, 0, 0]);
+        assert_eq!(prog.globals[1].init.as_ref().unwrap(), &vec![255, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(prog.globals[2].relocs[0].label, "x");
+    }
+
+    #[test]
+    fn floating_constants() {
+        let tokens = token::tokenize("1.5 .25f 1e3 0x1.8p1".to_string());
+        let vals: Vec<f64> = tokens.iter().map(|t| match t {
+            token::Token::Float { val, .. } => *val,
+            _ => panic!("not a float: {:?}", t),
+        }).collect();
+        assert_eq!(vals, vec![1.5, 0.25, 1000.0, 3.0]);
+
+        let mut tokens = token::tokenize("double d = 1 + 0.5; float f = 2; int i = 2.9;".to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &1.5f64.to_le_bytes().to_vec());
+        assert_eq!(prog.globals[1].init.as_ref().unwrap(), &2f32.to_le_bytes().to_vec());
+        assert_eq!(prog.globals[2].init.as_ref().unwrap(), &vec![2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn long_double_data() {
+        let mut tokens = token::tokenize("long double a = 1; long double b = -0.75L;".to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].ty.size(), 16);
+        let a = prog.globals[0].init.as_ref().unwrap();
+        assert_eq!(a[..10], [0, 0, 0, 0, 0, 0, 0, 0x80, 0xff, 0x3f]);
+        let b = prog.globals[1].init.as_ref().unwrap();
+        assert_eq!(b[..10], [0, 0, 0, 0, 0, 0, 0, 0xc0, 0xfe, 0xbf]);
+    }
+
+    #[test]
+    fn long_double_constants() {
+        let src = "long double a = 0.1L; long double b = -0.1L; int c = 0.1L == 0.1; long double d = 18446744073709551615.0L;
+                   long double e = 1.18973149535723176502e+4932L; long double f = 0x1p-16445L;";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        let value = |i: usize| prog.globals[i].init.as_ref().unwrap()[..10].to_vec();
+        assert_eq!(value(0), [0xcd, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xfb, 0x3f]);
+        assert_eq!(value(1), [0xcd, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xfb, 0xbf]);
+        assert_eq!(prog.globals[2].init.as_ref().unwrap(), &vec![0, 0, 0, 0]);
+        assert_eq!(value(3), [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3e, 0x40]);
+        assert_eq!(value(4), [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0x7f]);
+        assert_eq!(value(5), [1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bit_fields() {
+        let src = "struct B { unsigned a : 3; int b : 5; char c; int d : 30; } b = {5, -1, 1, 2}; struct Z { char a; int : 0; char b; } z;";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].ty.size(), 8);
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &vec![0xfd, 1, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(prog.globals[1].ty.size(), 5);
+        // A bit-field narrower than `int` promotes to `int` whatever its type.
+        let src = "struct L { unsigned long x : 5, y : 33; } l; char s[sizeof(l.x + 0)][sizeof(+l.y)];";
+        let prog = parse::program(&mut token::tokenize(src.to_string()));
+        assert_eq!(prog.globals[1].ty.size(), 32);
+    }
+
+    #[test]
+    fn bool_and_compound_literals() {
+        let src = "_Bool a = 256; _Bool b = 0.5; int *p = (int[]){1, 2};";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &vec![1]);
+        assert_eq!(prog.globals[1].init.as_ref().unwrap(), &vec![1]);
+        let lit = prog.globals.iter().find(|g| g.name == prog.globals[3].relocs[0].label).unwrap();
+        assert_eq!(lit.init.as_ref().unwrap(), &vec![1, 0, 0, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn constant_expressions() {
+        let src = "struct S { char c; long l; }; _Static_assert(sizeof(struct S) == 16, \"size\");
+                   enum { A = 1 ? 4 : 5, B = !0 + (0 || 2) }; int a[A << 1];
+                   long off = (long)&((struct S *)0)->l; int *p = &a[B];";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].ty.size(), 32);
+        assert_eq!(prog.globals[1].init.as_ref().unwrap(), &8i64.to_le_bytes().to_vec());
+        assert_eq!(prog.globals[2].relocs[0].addend, 8);
+    }
+
+    #[test]
+    fn bundled_headers() {
+        // Without the directives, the declarations in a header must parse.
+        let src: String = headers::find("stdint.h").unwrap().lines().filter(|l| !l.starts_with('#')).collect::<Vec<_>>().join("\n");
+        let mut tokens = token::tokenize(src + "int64_t i; uintptr_t p;");
+        let prog = parse::program(&mut tokens);
+        assert_eq!(prog.globals[0].ty.size(), 8);
+        assert!(prog.globals[1].ty.is_unsigned);
+        assert!(headers::find("stddef.h").unwrap().contains("typedef unsigned long size_t;"));
+        assert!(headers::find("stdio.h").is_none());
+    }
+
+    #[test]
+    fn gnu_extensions() {
+        let src = "__extension__ typedef long long int __q; typedef int reg __attribute__ ((__mode__ (__word__)));
+                   extern int scan (const char *__restrict __f, ...) __asm__ (\"\" \"__isoc99_scan\") __attribute__ ((__nothrow__));
+                   struct __attribute__((aligned(4))) S { __extension__ __q a; } __attribute__((unused)) s;
+                   static __inline unsigned f(unsigned x) { return __builtin_bswap32(x) + scan(\"\"); }
+                   reg r; int *__attribute__((unused)) p;
+                   struct __attribute__((packed)) P { char c; int i; } pk;
+                   struct A { char c; } __attribute__((aligned(16))) al;
+                   struct M { char c; int i __attribute__((__packed__)); char d __attribute__((aligned(8))); } m;";
+        let mut tokens = token::tokenize(src.to_string());
+        let prog = parse::program(&mut tokens);
+        assert!(prog.globals.iter().any(|g| g.name == "__isoc99_scan"));
+        assert!(!prog.globals.iter().any(|g| g.name == "scan"));
+        assert_eq!(prog.globals.iter().find(|g| g.name == "s").unwrap().ty.size(), 8);
+        assert_eq!(prog.globals.iter().find(|g| g.name == "r").unwrap().ty.size(), 8);
+        // `packed` and `aligned` change the layout.
+        let ty = |name: &str| prog.globals.iter().find(|g| g.name == name).unwrap().ty.clone();
+        assert_eq!((ty("pk").size(), ty("pk").align()), (5, 1));
+        assert_eq!((ty("al").size(), ty("al").align()), (16, 16));
+        assert_eq!(ty("m").size(), 16);
+        if let types::TypeKind::Struct(agg) = &ty("m").kind {
+            let offsets: Vec<usize> = agg.borrow().members.iter().map(|m| m.offset).collect();
+            assert_eq!(offsets, vec![0, 1, 8]);
+        }
+        assert!(prog.functions[0].is_static);
+    }
+
+    #[test]
+    fn includes_and_object_like_macros() {
+        use std::rc::Rc;
+        let dir = std::env::temp_dir().join(format!("rcc1-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("n.h"), "#ifndef N_H\n#define N_H\n#define N (M + 1)\nint n = N;\n#endif\n").unwrap();
+        // A header that replaces the bundled one can still include it.
+        std::fs::write(dir.join("stddef.h"), "int wrapped;\n#include_next <stddef.h>\n").unwrap();
+        let mut env = preprocess::PpEnv::new();
+        env.include_paths.push(dir.clone());
+        let src = "#define M 2\n#include \"n.h\"\n#include <n.h>\n#define STD <stddef.h>\n#include STD\nint m = M;\n#undef M\nint M;";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("t.c")), &mut env);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(tokens.iter().all(|t| t.text != "#"));
+        assert_eq!(tokens.iter().filter(|t| t.text == "n").count(), 1);
+        assert!(tokens[0].file.ends_with("n.h") && tokens[0].line == 4);
+        assert!(tokens.iter().any(|t| &*t.file == "<built-in>/stddef.h"));
+        assert!(tokens.iter().any(|t| t.text == "wrapped"));
+        let text: Vec<&str> = tokens.iter().rev().take(7).map(|t| t.text.as_str()).collect();
+        assert_eq!(text, vec![";", "M", "int", ";", "2", "=", "m"]);
+    }
+
+    #[test]
+    fn function_like_macros() {
+        use std::rc::Rc;
+        let spell = |src: &str| -> Vec<String> {
+            let tokens = token::tokenize_source(src, &Rc::from("t.c"));
+            let tokens = preprocess::preprocess(tokens, &mut preprocess::PpEnv::new());
+            tokens.into_iter().map(|t| t.text).collect()
+        };
+        // The examples of C11 6.10.3.5 and C23 6.10.5.1.
+        let defs = "#define x 3\n#define f(a) f(x * (a))\n#undef x\n#define x 2\n#define g f\n#define z z[0]\n#define h g(~\n#define m(a) a(w)\n#define w 0,1\n#define t(a) a\n#define p() int\n#define q(x) x\n#define r(x,y) x ## y\n#define str(x) # x\n";
+        let src = "f(y+1) + f(f(z)) % t(t(g)(0) + t)(1);\ng(x+(3,4)-w) | h 5) & m\n(f)^m(m);\np() i[q()] = { q(1), r(2,3), r(4,), r(,5), r(,) };\nchar c[2][6] = { str(hello), str() };";
+        let expected = "f(2 * (y+1)) + f(2 * (f(2 * (z[0])))) % f(2 * (0)) + t(1);\nf(2 * (2+(3,4)-0,1)) | f(2 * (~ 5)) & f(2 * (0,1))^m(0,1);\nint i[] = { 1, 23, 4, 5, };\nchar c[2][6] = { \"hello\", \"\" };";
+        assert_eq!(spell(&(defs.to_string() + src)), spell(expected));
+
+        let defs = "#define str(s) # s\n#define xstr(s) str(s)\n#define debug(s, t) printf(\"x\" # s \"= %d, x\" # t \"= %s\", x ## s, x ## t)\n#define INCFILE(n) vers ## n\n#define glue(a, b) a ## b\n#define xglue(a, b) glue(a, b)\n#define HIGHLOW \"hello\"\n#define LOW LOW \", world\"\n";
+        let src = "debug(1, 2);\nfputs(str(strncmp(\"abc\\0d\", \"abc\", '\\4') // this goes away\n == 0) str(: @\\n), s);\nxstr(INCFILE(2).h)\nglue(HIGH, LOW);\nxglue(HIGH, LOW)";
+        let expected = "printf(\"x\" \"1\" \"= %d, x\" \"2\" \"= %s\", x1, x2);\nfputs(\"strncmp(\\\"abc\\\\0d\\\", \\\"abc\\\", '\\\\4') == 0\" \": @\\n\", s);\n\"vers2.h\"\n\"hello\";\n\"hello\" \", world\"";
+        assert_eq!(spell(&(defs.to_string() + src)), spell(expected));
+
+        let src = "#define t(x,y,z) x ## y ## z\nint j[] = { t(1,2,3), t(,4,5), t(6,,7), t(8,9,),\nt(10,,), t(,11,), t(,,12), t(,,) };";
+        assert_eq!(spell(src), spell("int j[] = { 123, 45, 67, 89, 10, 11, 12, };"));
+
+        let defs = "#define debug(...) fprintf(stderr, __VA_ARGS__)\n#define showlist(...) puts(#__VA_ARGS__)\n#define report(test, ...) ((test)?puts(#test): printf(__VA_ARGS__))\n";
+        let src = "debug(\"Flag\");\ndebug(\"X = %d\\n\", x);\nshowlist(The first, second, and third items.);\nreport(x>y, \"x is %d but y is %d\", x, y);";
+        let expected = "fprintf(stderr, \"Flag\");\nfprintf(stderr, \"X = %d\\n\", x);\nputs(\"The first, second, and third items.\");\n((x>y)?puts(\"x>y\"): printf(\"x is %d but y is %d\", x, y));";
+        assert_eq!(spell(&(defs.to_string() + src)), spell(expected));
+
+        let defs = "#define F(...) f(0 __VA_OPT__(,) __VA_ARGS__)\n#define G(X, ...) f(0, X __VA_OPT__(,) __VA_ARGS__)\n#define SDEF(sname, ...) S sname __VA_OPT__(= { __VA_ARGS__ })\n#define EMP\n";
+        let src = "F(a,b,c) F() F(EMP) G(a,b,c) G(a,) G(a) SDEF(foo); SDEF(bar, 1, 2);";
+        let expected = "f(0, a,b,c) f(0) f(0) f(0, a, b, c) f(0, a) f(0, a) S foo; S bar = { 1, 2 };";
+        assert_eq!(spell(&(defs.to_string() + src)), spell(expected));
+
+        // Pasting numbers makes preprocessing numbers, whatever their parts were.
+        let src = "#define P(a, b) a ## b\n#define Q(a, b, c) a ## b ## c\nP(0x, 1f) Q(1, e, +) P(1., 5f) P(., 5) P(1, .2.3) P(x, 1)";
+        assert_eq!(spell(src), vec!["0x1f", "1e+", "1.5f", ".5", "1.2.3", "x1"]);
+        let src = "#define P(a, b) a ## b\nint x = P(0x, 1f) + P(1, e2);";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("t.c")), &mut preprocess::PpEnv::new());
+        let prog = parse::program(&mut token::convert_tokens(tokens));
+        assert_eq!(prog.globals[0].init.as_ref().unwrap(), &vec![131, 0, 0, 0]);
+    }
+
+    #[test]
+    fn conditional_directives() {
+        use std::rc::Rc;
+        let src = "#define A 2\n#if -1 < 0u || 0x7fffffffffffffff + 1 > 0 || 0 && 1 / 0\nint a;\n#elif defined(A) && A * 3 == 6 && !defined B\n# if 0\n#  bogus\n# endif\nint b;\n#elif 1 / 0\n#else\n#error unreachable\n#endif\n#ifndef A\nint c;\n#elifdef A\nint d;\n#endif\n#if 0xffffffff + 1 == 0x100000000 && '\\377' < 0 && (2 || 1 / 0) == 1 && (0 ? 1u : -1) > 0\nint e;\n#endif\n";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("t.c")), &mut preprocess::PpEnv::new());
+        let names: Vec<&str> = tokens.iter().filter(|t| t.text.len() == 1 && t.text != ";").map(|t| t.text.as_str()).collect();
+        assert_eq!(names, vec!["b", "d", "e"]);
+    }
+
+    #[test]
+    fn predefined_macros() {
+        use std::rc::Rc;
+        let mut env = preprocess::PpEnv::new();
+        env.define_macro("ONE");
+        env.define_macro("SQ(x)=((x) * (x))");
+        env.define_macro("GONE=1");
+        env.undef_macro("GONE");
+        let src = "__FILE__ __LINE__\n#if __STDC_VERSION__ >= 201112L && __x86_64__ && __LP64__ && __SIZEOF_LONG__ == 8 && !defined GONE\n__COUNTER__ __COUNTER__ SQ(ONE) __LINE__\n#endif\n";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("dir/t.c")), &mut env);
+        let text: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(text, vec!["\"dir/t.c\"", "1", "0", "1", "(", "(", "1", ")", "*", "(", "1", ")", ")", "3"]);
+        // The limits that system headers build their own macros from.
+        let src = "#if __WCHAR_MAX__ == 2147483647 && __WCHAR_MIN__ < 0 && __WINT_MIN__ - 1 > 0 && __SIZE_MAX__ == -1 && __INT_MAX__ + 1L == 1L << 31 && __LONG_MAX__ == __INTMAX_MAX__ && __SIG_ATOMIC_MIN__ == -__SIG_ATOMIC_MAX__ - 1\n__WCHAR_TYPE__ a; __WINT_TYPE__ b; __CHAR16_TYPE__ c; __CHAR32_TYPE__ d; __SIZE_TYPE__ e;\n#endif\n";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("t.c")), &mut preprocess::PpEnv::new());
+        let prog = parse::program(&mut token::convert_tokens(tokens));
+        let types: Vec<(usize, bool)> = prog.globals.iter().map(|g| (g.ty.size(), g.ty.is_unsigned)).collect();
+        assert_eq!(types, vec![(4, false), (4, true), (2, true), (4, true), (8, true)]);
+    }
+
+    #[test]
+    fn preprocessed_text() {
+        use std::rc::Rc;
+        let src = "#include <stdbool.h>\n#define M -\n#define CAT(a, b) a ## b\nbool b = true;   int x = -M 1, y = CAT(1, 2).5;\n\nint  z;\n\n\n\n\n\n\n\n\nint w;";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("t.c")), &mut preprocess::PpEnv::new());
+        let expected = "# 4 \"t.c\"\n_Bool b = 1; int x = - - 1, y = 12 .5;\n\nint z;\n# 15 \"t.c\"\nint w;\n";
+        assert_eq!(preprocess::to_text(&tokens), expected);
+        // The line markers make the output reproduce itself.
+        let again = preprocess::preprocess(token::tokenize_source(expected, &Rc::from("t.i")), &mut preprocess::PpEnv::new());
+        assert_eq!(preprocess::to_text(&again), expected);
+    }
+
+    #[test]
+    fn line_splicing() {
+        use std::rc::Rc;
+        let src = "#define F(x) \\\r\n  x * 2\r\nint a = F(1) +\\\n\\\n 3;\nint b = __LINE__;\nin\\\nt c;\n";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("t.c")), &mut preprocess::PpEnv::new());
+        let text: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(text, vec!["int", "a", "=", "1", "*", "2", "+", "3", ";", "int", "b", "=", "6", ";", "int", "c", ";"]);
+        // Tokens keep the physical line they start on.
+        let lines: Vec<usize> = tokens.iter().map(|t| t.line).collect();
+        assert_eq!(lines, vec![3, 3, 3, 3, 3, 3, 3, 5, 5, 6, 6, 6, 6, 6, 7, 8, 8]);
+        assert_eq!(token::replace_trigraphs("??=if ??(a??) ??/ ??' ??< ??! ??> ??- ???= ??x"), "#if [a] \\ ^ { | } ~ ?# ??x");
+    }
+
+    #[test]
+    fn preprocessing_numbers() {
+        use std::rc::Rc;
+        // Skipped groups and unused macros may hold text that is no C token.
+        let src = "#if 0\nisn't 1e 1.2.3 08 \"open\n#elif '\\'' == 39 && 0x1f == 31\nint a = 1;\n#endif\n#define V 1.2.3\n#define E(x) x ## e\nint b = 0x1e + 1, c = 1.5e+1f, e = 'a' + 010;\n";
+        let tokens = preprocess::preprocess(token::tokenize_source(src, &Rc::from("t.c")), &mut preprocess::PpEnv::new());
+        let mut tokens = token::convert_tokens(tokens);
+        let prog = parse::program(&mut tokens);
+        let value = |name: &str| prog.globals.iter().find(|g| g.name == name).unwrap().init.clone().unwrap();
+        assert_eq!(value("a"), vec![1, 0, 0, 0]);
+        assert_eq!(value("b"), vec![31, 0, 0, 0]);
+        assert_eq!(value("c"), vec![15, 0, 0, 0]);
+        assert_eq!(value("e"), vec![105, 0, 0, 0]);
+        // A preprocessing number runs on through signed exponents and dots.
+        let tokens = token::tokenize_source("0x1e+1 1..5 1e-x 0x1p-2 .5.", &Rc::from("t.c"));
+        let text: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(text, vec!["0x1e+1", "1..5", "1e-x", "0x1p-2", ".5."]);
+    }
+}
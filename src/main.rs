@@ -1,31 +1,61 @@
 use std::env;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 extern crate rcc1;
-use rcc1::codegen::gen;
-use rcc1::token::tokenize;
+use rcc1::codegen::codegen;
+use rcc1::token::{convert_tokens, error, replace_trigraphs, tokenize_source, Token};
 use rcc1::parse::program;
+use rcc1::preprocess::{join_strings, preprocess, to_text, PpEnv};
+
+// The argument of the option `flag`, attached as in `-Idir` or in the next word.
+fn option_arg(args: &[String], i: &mut usize, flag: &str) -> Option<String> {
+    let rest = args[*i].strip_prefix(flag)?;
+    if !rest.is_empty() {
+        return Some(rest.to_string());
+    }
+    *i += 1;
+    match args.get(*i) {
+        Some(arg) => Some(arg.clone()),
+        None => error(&format!("missing argument to '{}'", flag)),
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    println!(".intel_syntax noprefix");
-    println!(".global main");
-    println!("main:");
-
-    println!("  push rbp");
-    println!("  mov rbp, rsp");
-    println!("  sub rsp, 208");
-    let s = args[1].clone();
-   // let s = "if(1==0)return 5;else return 3;".to_string();
-    let mut v = tokenize(s);
-
-    let nodes = program(&mut v);
-    for i in nodes{
-
-        gen(i);
-        println!("  pop rax");
+    let mut pp = PpEnv::new();
+    let mut input = None;
+    // With -E, the preprocessed source is printed instead of compiled.
+    let mut preprocess_only = false;
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "-E" {
+            preprocess_only = true;
+        } else if args[i] == "-trigraphs" {
+            pp.trigraphs = true;
+        } else if let Some(dir) = option_arg(&args, &mut i, "-I") {
+            pp.include_paths.push(PathBuf::from(dir));
+        } else if let Some(def) = option_arg(&args, &mut i, "-D") {
+            pp.define_macro(&def);
+        } else if let Some(name) = option_arg(&args, &mut i, "-U") {
+            pp.undef_macro(&name);
+        } else {
+            input = Some(args[i].clone());
+        }
+        i += 1;
+    }
+    let s = match input {
+        Some(s) => s,
+        None => error("no input"),
+    };
+    let s = if pp.trigraphs { replace_trigraphs(&s) } else { s };
+    let tokens = preprocess(tokenize_source(&s, &Rc::from("<input>")), &mut pp);
+    if preprocess_only {
+        print!("{}", to_text(&tokens));
+        return;
     }
+    let mut v: Vec<Token> = convert_tokens(join_strings(tokens));
 
-    println!("  mov rsp, rbp");
-    println!("  pop rbp");
-    println!("  ret");
+    let prog = program(&mut v);
+    codegen(prog);
 }
@@ -1,34 +1,154 @@
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::rc::Rc;
+
 use crate::token::*;
+use crate::types::*;
 
 pub enum Node {
     Operator {
         kind: NodeKind,
         lhs: Box<Node>,
         rhs: Box<Node>,
+        ty: Type,
     },
     Number {
-        val: i32,
+        val: i64,
+        ty: Type,
+    },
+    FNumber {
+        val: f64,
+        x87: [u8; 10],
+        ty: Type,
     },
     LVar {
         offset: i32,
+        ty: Type,
+    },
+    GVar {
+        name: String,
+        ty: Type,
+    },
+    Addr {
+        expr: Box<Node>,
+        ty: Type,
+    },
+    // Converts the value of `expr` to `ty`.
+    Cast {
+        expr: Box<Node>,
+        ty: Type,
+    },
+    Deref {
+        expr: Box<Node>,
+        ty: Type,
+    },
+    Member {
+        expr: Box<Node>,
+        offset: usize,
+        // The bit offset and width of a bit-field, whose `ty` is the type of
+        // the storage unit at `offset`.
+        bit_field: Option<(usize, usize)>,
+        ty: Type,
+    },
+    VaStart {
+        ap: Box<Node>,
+        gp_offset: usize,
+        fp_offset: usize,
+        // Offset from rbp of the first variadic argument passed on the stack.
+        overflow_offset: usize,
+        save_area: i32,
     },
-    Return(Box<Node>),
+    VaArg {
+        ap: Box<Node>,
+        ty: Type,
+    },
+    // Unary minus on a floating value, which flips the sign even of zero
+    // and NaN. Integers are negated as `0 - x`.
+    Neg {
+        expr: Box<Node>,
+        ty: Type,
+    },
+    // `__builtin_bswap16/32/64`, reversing the bytes of an unsigned integer.
+    Bswap {
+        expr: Box<Node>,
+        ty: Type,
+    },
+    // A call through `func`, which is a function designator or a pointer to a function.
+    Call {
+        func: Box<Node>,
+        args: Vec<Node>,
+        ty: Type,
+    },
+    Return(Option<Box<Node>>),
     If {
         cond: Box<Node>,
         i_st: Box<Node>,
-        e_st: Box<Node>,
+        e_st: Option<Box<Node>>,
     },
     While {
         cond: Box<Node>,
         st: Box<Node>,
     },
     For {
-        cond_1: Box<Node>,
-        cond_2: Box<Node>,
-        cond_3: Box<Node>,
+        cond_1: Option<Box<Node>>,
+        cond_2: Option<Box<Node>>,
+        cond_3: Option<Box<Node>>,
+        st: Box<Node>,
+    },
+    Switch {
+        cond: Box<Node>,
+        st: Box<Node>,
+        id: usize,
+        cases: Vec<i64>,
+        has_default: bool,
+    },
+    // A `case` or `default` label of the switch statement `id`; `idx` is
+    // the position in the switch's case list, or None for `default`.
+    Case {
+        id: usize,
+        idx: Option<usize>,
         st: Box<Node>,
     },
+    Break,
     Block(Vec<Node>),
+    ExprStmt(Box<Node>),
+    // `cond ? then : els`, where only the selected operand is evaluated.
+    Cond {
+        cond: Box<Node>,
+        then: Box<Node>,
+        els: Box<Node>,
+        ty: Type,
+    },
+    // Evaluates `lhs` for its side effects, then `rhs` for the value.
+    Comma {
+        lhs: Box<Node>,
+        rhs: Box<Node>,
+    },
+    // A compound assignment or increment, which evaluates the address of
+    // `lhs` once. `rhs` computes the new value from an `AssignTarget`
+    // standing for the value currently stored there. A postfix increment
+    // or decrement yields the old value instead of the new one.
+    OpAssign {
+        lhs: Box<Node>,
+        rhs: Box<Node>,
+        ty: Type,
+        post: bool,
+    },
+    AssignTarget {
+        bit_field: Option<(usize, usize)>,
+        ty: Type,
+    },
+    // A compound literal in a function: `init` initializes the unnamed
+    // object that `var` refers to each time the literal is evaluated.
+    CompoundLiteral {
+        init: Box<Node>,
+        var: Box<Node>,
+    },
+    // Zero-fills a local variable before its initializer is applied.
+    MemZero {
+        offset: i32,
+        size: usize,
+    },
 }
 
 pub enum NodeKind {
@@ -36,371 +156,2794 @@ pub enum NodeKind {
     NdSub,
     NdMul,
     NdDiv,
+    NdMod,
+    NdBitAnd,
+    NdBitOr,
+    NdBitXor,
+    NdShl,
+    NdShr,
     NdEq,
     NdNEq,
     NdLt,
     NdGt,
     NdLe,
     NdGe,
+    // Short-circuiting `&&` and `||`, whose operands are compared against zero.
+    NdLogAnd,
+    NdLogOr,
     NdAssign,
-    NdExprEnd,
 }
 
-struct LVar {
-    offset: i32,
-    name: String,
+/// An address of a global symbol embedded in static data.
+pub struct Reloc {
+    pub offset: usize,
+    pub label: String,
+    pub addend: i64,
+}
+
+pub struct GVar {
+    pub name: String,
+    pub ty: Type,
+    pub is_static: bool,
+    // False for a variable that is only declared `extern` here, and for functions.
+    pub is_definition: bool,
+    pub init: Option<Vec<u8>>,
+    pub relocs: Vec<Reloc>,
+    // The alignment requested with `_Alignas`, if stricter than the type's.
+    pub align: usize,
+}
+
+pub struct Function {
+    pub name: String,
+    pub params: Vec<(i32, Type)>,
+    pub body: Node,
+    pub stack_size: i32,
+    pub is_static: bool,
+    // Offset of the register save area of a variadic function.
+    pub va_area: Option<i32>,
+}
+
+pub struct Program {
+    pub globals: Vec<GVar>,
+    pub functions: Vec<Function>,
+}
+
+enum Var {
+    Local { offset: i32, ty: Type },
+    Global { name: String, ty: Type },
+    EnumConst(i64),
+    Typedef(Type),
+}
+
+// Storage-class specifiers seen in a declspec.
+#[derive(Default, Clone, Copy)]
+struct VarAttr {
+    is_static: bool,
+    is_extern: bool,
+    is_typedef: bool,
+    // The alignment requested with `_Alignas` or `__attribute__((aligned))`, or 0.
+    align: usize,
+    // `__attribute__((packed))`, which gives struct members alignment 1.
+    packed: bool,
+}
+
+// The switch statement whose body is being parsed.
+struct SwitchCtx {
+    id: usize,
+    ty: Type,
+    cases: Vec<i64>,
+    has_default: bool,
+}
+
+// The keyword a tag is declared with. A tag must be used with the same one.
+#[derive(Clone, Copy, PartialEq)]
+enum TagKind {
+    Struct,
+    Union,
+    Enum,
+}
+
+// Ordinary identifiers and struct, union and enum tags live in separate
+// name spaces but share block scoping.
+struct Scope {
+    vars: Vec<(String, Var)>,
+    tags: Vec<(String, TagKind, Type)>,
 }
 
-impl LVar {
-    fn new(offset: i32, name: String) -> LVar {
-        LVar { offset, name }
+impl Scope {
+    fn new() -> Scope {
+        Scope { vars: Vec::new(), tags: Vec::new() }
     }
 }
 
-fn find_duplication_lvar(name: String, locals: &mut Vec<LVar>) -> Option<i32> {
-    for l in locals {
-        if name == l.name {
-            return Some(l.offset);
+struct Env {
+    scopes: Vec<Scope>,
+    stack_size: i32,
+    globals: Vec<GVar>,
+    strings: usize,
+    statics: usize,
+    // Name and return type of the function being parsed.
+    func_name: String,
+    ret_ty: Type,
+    // Parameter names of the most recently parsed function declarator.
+    param_names: Vec<String>,
+    // The `packed` and `aligned` attributes of the most recently parsed
+    // declarator, which apply to the object it declares.
+    decl_attr: VarAttr,
+    switches: Vec<SwitchCtx>,
+    switch_count: usize,
+    // Number of enclosing loops and switches that a `break` may leave.
+    breakable: usize,
+    // The register save area of the variadic function being parsed and the
+    // types of its named parameters.
+    va_area: Option<(i32, Vec<Type>)>,
+}
+
+impl Env {
+    fn new() -> Env {
+        Env {
+            scopes: vec![Scope::new()],
+            stack_size: 0,
+            globals: Vec::new(),
+            strings: 0,
+            statics: 0,
+            func_name: String::new(),
+            ret_ty: Type::void(),
+            param_names: Vec::new(),
+            decl_attr: VarAttr::default(),
+            switches: Vec::new(),
+            switch_count: 0,
+            breakable: 0,
+            va_area: None,
         }
     }
-    None
-}
 
-fn find_lvar(name: String, locals: &mut Vec<LVar>) -> i32 {
-    let dup = find_duplication_lvar(name.clone(), locals);
-    match dup {
-        Some(i) => {
-            i
+    fn enter_scope(&mut self) {
+        self.scopes.push(Scope::new());
+    }
+
+    fn leave_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn find_var(&self, name: &str) -> Option<&Var> {
+        for scope in self.scopes.iter().rev() {
+            for (n, v) in scope.vars.iter().rev() {
+                if n == name {
+                    return Some(v);
+                }
+            }
         }
-        None => {
-            let offset = (locals.len() as i32 + 1) * 8;
-            let lvar = LVar::new(offset, name);
-            let offset_lvar = lvar.offset;
-            locals.push(lvar);
-            offset_lvar
+        None
+    }
+
+    // Finds the innermost tag `name`, in any scope or, with `local`, in the
+    // current one. It is an error if it was declared with another keyword.
+    fn find_tag(&self, name: &str, kind: TagKind, local: bool) -> Option<&Type> {
+        let scopes = if local { &self.scopes[self.scopes.len() - 1..] } else { &self.scopes[..] };
+        for scope in scopes.iter().rev() {
+            if let Some((_, k, ty)) = scope.tags.iter().rev().find(|(n, ..)| n == name) {
+                if *k != kind {
+                    error(&format!("'{}' defined as wrong kind of tag", name));
+                }
+                return Some(ty);
+            }
         }
+        None
+    }
+
+    fn push_tag(&mut self, name: String, kind: TagKind, ty: Type) {
+        self.scopes.last_mut().unwrap().tags.push((name, kind, ty));
+    }
+
+    fn new_lvar(&mut self, name: String, ty: Type) -> i32 {
+        self.new_lvar_aligned(name, ty, 0)
     }
-}
 
+    // Allocates a local variable aligned to at least `align`. The frame is
+    // only 16-byte aligned, so a local cannot ask for more.
+    fn new_lvar_aligned(&mut self, name: String, ty: Type, align: usize) -> i32 {
+        let align = ty.align().max(align);
+        if align > 16 {
+            error(&format!("alignment of local variable '{}' to {} bytes is not supported", name, align));
+        }
+        let size = align_to(self.stack_size as usize + ty.size(), align);
+        self.stack_size = size as i32;
+        let offset = self.stack_size;
+        self.scopes.last_mut().unwrap().vars.push((name, Var::Local { offset, ty }));
+        offset
+    }
+
+    // Records a declaration of a symbol with linkage. Repeated declarations
+    // of the same symbol are merged: it has internal linkage if any of them
+    // says `static`, and is defined if any of them is a definition.
+    fn declare_gvar(&mut self, name: String, ty: Type, is_static: bool, is_definition: bool) -> &mut GVar {
+        let idx = match self.globals.iter().position(|g| g.name == name) {
+            Some(i) => {
+                let gvar = &mut self.globals[i];
+                let unsized_array = matches!(ty.kind, TypeKind::Array(_, 0));
+                if (ty.is_complete() && !unsized_array) || !gvar.ty.is_complete() {
+                    gvar.ty = ty;
+                }
+                gvar.is_static |= is_static;
+                gvar.is_definition |= is_definition;
+                i
+            }
+            None => {
+                self.globals.push(GVar { name, ty, is_static, is_definition, init: None, relocs: Vec::new(), align: 0 });
+                self.globals.len() - 1
+            }
+        };
+        &mut self.globals[idx]
+    }
+
+    fn new_gvar(&mut self, name: String, ty: Type, is_static: bool, is_definition: bool) -> &mut GVar {
+        self.new_gvar_labeled(name.clone(), name, ty, is_static, is_definition)
+    }
+
+    // Declares a global that is emitted as the symbol `label`.
+    fn new_gvar_labeled(&mut self, name: String, label: String, ty: Type, is_static: bool, is_definition: bool) -> &mut GVar {
+        self.scopes[0].vars.push((name, Var::Global { name: label.clone(), ty: ty.clone() }));
+        self.declare_gvar(label, ty, is_static, is_definition)
+    }
+
+    fn new_string(&mut self, val: Vec<u8>, elem: Type) -> Node {
+        let name = format!(".L..{}", self.strings);
+        self.strings += 1;
+        let mut init = val;
+        init.resize(init.len() + elem.size(), 0);
+        let ty = Type::array_of(elem.clone(), init.len() / elem.size());
+        self.globals.push(GVar {
+            name: name.clone(),
+            ty: ty.clone(),
+            is_static: true,
+            is_definition: true,
+            init: Some(init),
+            relocs: Vec::new(),
+            align: 0,
+        });
+        Node::GVar { name, ty }
+    }
+}
 
 impl Node {
+    // Integer operands are converted as C requires: both operands of a shift
+    // are promoted separately and the result has the type of the left one;
+    // other operators apply the usual arithmetic conversions.
     fn new(kind: NodeKind, lhs: Box<Node>, rhs: Box<Node>) -> Box<Node> {
+        let (lty, rty) = (lhs.ty(), rhs.ty());
+        let is_cmp = matches!(
+            kind,
+            NodeKind::NdEq | NodeKind::NdNEq | NodeKind::NdLt | NodeKind::NdGt | NodeKind::NdLe | NodeKind::NdGe
+        );
+        let (lhs, rhs, ty) = match kind {
+            NodeKind::NdAssign => (lhs, rhs, lty),
+            _ if !lty.is_arith() || !rty.is_arith() => {
+                let ty = if is_cmp {
+                    Type::int()
+                } else if lty.size() == 8 || rty.size() == 8 {
+                    Type::long()
+                } else {
+                    Type::int()
+                };
+                (lhs, rhs, ty)
+            }
+            NodeKind::NdShl | NodeKind::NdShr => {
+                let ty = lhs.promoted_ty();
+                let rty = rhs.promoted_ty();
+                (new_cast(lhs, ty.clone()), new_cast(rhs, rty), ty)
+            }
+            _ => {
+                let common = common_type(&lhs.promoted_ty(), &rhs.promoted_ty());
+                let ty = if is_cmp { Type::int() } else { common.clone() };
+                (new_cast(lhs, common.clone()), new_cast(rhs, common), ty)
+            }
+        };
         let node = Node::Operator {
-            kind: kind,
-            lhs: lhs,
-            rhs: rhs,
+            kind,
+            lhs,
+            rhs,
+            ty,
         };
-        let node = Box::new(node);
-        node
-    }
-
-    fn new_node_num(val: i32) -> Box<Node> {
-        let node = Node::Number { val: val };
-        let node = Box::new(node);
-        node
-    }
-    fn new_node_lvar(val: i32) -> Box<Node> {
-        let node = Node::LVar { offset: val };
-        let node = Box::new(node);
-        node
-    }
-
-    fn new_keyword(rhs: Box<Node>) -> Box<Node> {
-        let node = Node::Return(rhs);
-        let node = Box::new(node);
-        node
-    }
-}
-
-// program    = stmt*
-// stmt       = expr ";"
-//              | "{" stmt* "}"
-//              | "return" expr ";"
-//              | "if" "(" expr ")" stmt ("else" stmt)?
-//              | "while" "(" expr ")" stmt
-//              | "for" "(" expr? ";" expr? ";" expr? ")" stmt
-// expr       = assign
-// assign     = equality ("=" assign)?
-// equality   = relational ("==" relational | "!=" relational)*
-// relational = add ("<" add | "<=" add | ">" add | ">=" add)*
-// add        = mul ("+" mul | "-" mul)*
-// mul        = unary ("*" unary | "/" unary)*
-// unary      = ("+" | "-")? primary
-// primary    = num | ident | "(" expr ")"
-
-pub fn program(tokens: &mut Vec<Token>) -> Vec<Node> {
-    let mut lvars: Vec<LVar> = Vec::new();
-    let mut code: Vec<Node> = Vec::new();
-    loop {
-        if tokens.len() == 0 {
-            break;
+        Box::new(node)
+    }
+
+    fn new_node_num(val: i64) -> Box<Node> {
+        let ty = if val == val as i32 as i64 { Type::int() } else { Type::long() };
+        Box::new(Node::Number { val, ty })
+    }
+
+    fn new_node_long(val: i64) -> Box<Node> {
+        Box::new(Node::Number { val, ty: Type::long() })
+    }
+
+    fn new_node_ulong(val: i64) -> Box<Node> {
+        Box::new(Node::Number { val, ty: Type::ulong() })
+    }
+
+    fn new_node_deref(expr: Box<Node>) -> Box<Node> {
+        let ty = match expr.ty().base() {
+            Some(base) => base.clone(),
+            None => error("invalid pointer dereference"),
+        };
+        Box::new(Node::Deref { expr, ty })
+    }
+
+    // The type of the value after the integer promotions. Like GCC, a
+    // bit-field is promoted by its width: one narrower than `int` becomes
+    // `int` even if its type is unsigned or wider.
+    fn promoted_ty(&self) -> Type {
+        match self {
+            Node::Member { bit_field: Some((_, width)), ty, .. } | Node::AssignTarget { bit_field: Some((_, width)), ty }
+                if *width <= 32 =>
+            {
+                Type { is_unsigned: ty.is_unsigned && *width == 32, ..Type::int() }
+            }
+            _ => self.ty().promoted(),
+        }
+    }
+
+    pub fn ty(&self) -> Type {
+        match self {
+            Node::Operator { ty, .. }
+            | Node::Number { ty, .. }
+            | Node::FNumber { ty, .. }
+            | Node::LVar { ty, .. }
+            | Node::GVar { ty, .. }
+            | Node::Addr { ty, .. }
+            | Node::Cast { ty, .. }
+            | Node::Deref { ty, .. }
+            | Node::Member { ty, .. }
+            | Node::VaArg { ty, .. }
+            | Node::Neg { ty, .. }
+            | Node::Bswap { ty, .. }
+            | Node::Call { ty, .. }
+            | Node::Cond { ty, .. }
+            | Node::OpAssign { ty, .. }
+            | Node::AssignTarget { ty, .. } => ty.clone(),
+            Node::Comma { rhs, .. } => rhs.ty(),
+            Node::CompoundLiteral { var, .. } => var.ty(),
+            _ => Type::void(),
         }
-        code.push(stmt(tokens, &mut lvars));
     }
-    code
 }
 
-fn stmt(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Node {
-    match &tokens[0] {
-        Token::Return => {
+fn consume(tokens: &mut Vec<Token>, op: OperatorKind) -> bool {
+    match tokens.first() {
+        Some(Token::Operator { kind }) if *kind == op => {
             tokens.remove(0);
-            let node = *Node::new_keyword(Box::new(expr(tokens, lvars)));
-            if let Token::Operator { kind: OperatorKind::TkExprEnd } = &tokens[0] {
-                tokens.remove(0);
-            }
-            node
+            true
         }
-        Token::If => {
+        _ => false,
+    }
+}
+
+fn expect(tokens: &mut Vec<Token>, op: OperatorKind) {
+    if !consume(tokens, op.clone()) {
+        error(&format!("expected {:?}, but got {:?}", op, tokens.first()));
+    }
+}
+
+fn peek(tokens: &[Token], op: OperatorKind) -> bool {
+    matches!(tokens.first(), Some(Token::Operator { kind }) if *kind == op)
+}
+
+fn consume_keyword(tokens: &mut Vec<Token>, kw: KeywordKind) -> bool {
+    match tokens.first() {
+        Some(Token::Keyword { kind }) if *kind == kw => {
             tokens.remove(0);
-            if let Token::Operator { kind: OperatorKind::TkPrSt } = &tokens[0] {
-                tokens.remove(0);
-            }
-            let node_cond = expr(tokens, lvars);
-            if let Token::Operator { kind: OperatorKind::TkPrEd } = &tokens[0] {
-                tokens.remove(0);
-            }
-            let i_st = stmt(tokens, lvars);
-            if let Token::Else = tokens[0] {
-                tokens.remove(0);
-                let e_st = stmt(tokens, lvars);
-                let node = Node::If { cond: Box::new(node_cond), i_st: Box::new(i_st), e_st: Box::new(e_st) };
-                node
-            } else {
-                let node = Node::If { cond: Box::new(node_cond), i_st: Box::new(i_st), e_st: Box::new(Node::Number { val: 0 }) };
-                node
-            }
+            true
         }
-        // Token::While => {
-        //
-        // }
-        // Token::For => {
-        //
-        // }
-        Token::Operator { kind: OperatorKind::TkBrSt } => {
+        _ => false,
+    }
+}
+
+fn expect_ident(tokens: &mut Vec<Token>) -> String {
+    match tokens.first() {
+        Some(Token::Ident { name }) => {
+            let name = name.clone();
             tokens.remove(0);
-            let mut stmts: Vec<Node> = Vec::new();
-            loop {
-                if let Token::Operator { kind: OperatorKind::TkBrEd } = &tokens[0] {
-                    tokens.remove(0);
-                    break;
-                } else {
-                    stmts.push(stmt(tokens, lvars));
-                }
+            name
+        }
+        t => error(&format!("expected an identifier, but got {:?}", t)),
+    }
+}
+
+fn typedef_name(tokens: &[Token], env: &Env) -> Option<Type> {
+    match tokens.first() {
+        Some(Token::Ident { name }) => match env.find_var(name) {
+            Some(Var::Typedef(ty)) => Some(ty.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Whether the tokens start a declaration. An identifier starts one only if
+// it names a typedef that is not shadowed by an ordinary identifier.
+fn is_typename(tokens: &[Token], env: &Env) -> bool {
+    if let Some(Token::Keyword { kind: KeywordKind::TkExtension }) = tokens.first() {
+        return is_typename(&tokens[1..], env);
+    }
+    matches!(
+        tokens.first(),
+        Some(Token::Keyword {
+            kind: KeywordKind::TkVoid
+                | KeywordKind::TkChar
+                | KeywordKind::TkShort
+                | KeywordKind::TkInt
+                | KeywordKind::TkLong
+                | KeywordKind::TkStatic
+                | KeywordKind::TkStruct
+                | KeywordKind::TkUnion
+                | KeywordKind::TkEnum
+                | KeywordKind::TkTypedef
+                | KeywordKind::TkExtern
+                | KeywordKind::TkConst
+                | KeywordKind::TkVolatile
+                | KeywordKind::TkRestrict
+                | KeywordKind::TkUnsigned
+                | KeywordKind::TkSigned
+                | KeywordKind::TkFloat
+                | KeywordKind::TkDouble
+                | KeywordKind::TkBool
+                | KeywordKind::TkAlignas
+                | KeywordKind::TkNoreturn
+                | KeywordKind::TkInline
+                | KeywordKind::TkAttribute
+        })
+    ) || typedef_name(tokens, env).is_some()
+}
+
+// program     = (function | declaration)*
+// function    = declspec declarator "{" compound_stmt
+// declaration = declspec (declarator ("=" initializer)? ("," declarator ("=" initializer)?)*)? ";"
+// declspec    = ("typedef" | "static" | "extern" | "const" | "volatile" | "restrict" | "_Noreturn"
+//                | "_Alignas" "(" (type_name | const_expr) ")"
+//                | "signed" | "unsigned" | "void" | "_Bool" | "char" | "short" | "int" | "long" | "float" | "double"
+//                | struct_decl | enum_decl | typedef_name)+
+// struct_decl = ("struct" | "union") ident? ("{" (declspec declarator? ("," declarator)* ";")* "}")?
+// enum_decl   = "enum" ident? ("{" ident ("=" const_expr)? ("," ident ("=" const_expr)?)* ","? "}")?
+// declarator  = ("*" ("const" | "volatile" | "restrict")*)* ("(" declarator ")" | ident) type_suffix
+// type_suffix = "(" func_params | "[" const_expr? "]" type_suffix | ε
+// stmt        = expr ";"
+//               | "{" compound_stmt
+//               | "return" expr? ";"
+//               | "if" "(" expr ")" stmt ("else" stmt)?
+//               | "while" "(" expr ")" stmt
+//               | "for" "(" expr? ";" expr? ";" expr? ")" stmt
+//               | "switch" "(" expr ")" stmt
+//               | "case" const_expr ":" stmt
+//               | "default" ":" stmt
+//               | "break" ";"
+// compound_stmt = (declaration | static_assert | stmt)* "}"
+// static_assert = "_Static_assert" "(" const_expr ("," str)? ")" ";"
+// expr        = assign ("," assign)*
+// assign      = conditional (("=" | "+=" | "-=" | "*=" | "/=" | "%=" | "&=" | "|=" | "^=" | "<<=" | ">>=") assign)?
+// conditional = logor ("?" expr ":" conditional)?
+// logor       = logand ("||" logand)*
+// logand      = bitor ("&&" bitor)*
+// bitor       = bitxor ("|" bitxor)*
+// bitxor      = bitand ("^" bitand)*
+// bitand      = equality ("&" equality)*
+// equality    = relational ("==" relational | "!=" relational)*
+// relational  = shift ("<" shift | "<=" shift | ">" shift | ">=" shift)*
+// shift       = add ("<<" add | ">>" add)*
+// add         = mul ("+" mul | "-" mul)*
+// mul         = cast ("*" cast | "/" cast | "%" cast)*
+// cast        = "(" type_name ")" cast | unary
+// unary       = ("+" | "-" | "*" | "&" | "~" | "!" | "++" | "--") cast
+//               | "sizeof" "(" type_name ")"
+//               | "sizeof" unary
+//               | "_Alignof" "(" type_name ")"
+//               | "_Alignof" unary
+//               | postfix
+// postfix     = (compound_literal | primary) ("[" expr "]" | "(" args? ")" | "." ident | "->" ident | "++" | "--")*
+// compound_literal = "(" type_name ")" "{" initializer_list "}"
+// primary     = num | str | ident | "(" expr ")"
+
+pub fn program(tokens: &mut Vec<Token>) -> Program {
+    let mut env = Env::new();
+    env.scopes[0].vars.push(("__builtin_va_list".to_string(), Var::Typedef(Type::va_list())));
+    let mut functions: Vec<Function> = Vec::new();
+    loop {
+        if tokens.is_empty() {
+            break;
+        }
+        if consume_keyword(tokens, KeywordKind::TkStaticAssert) {
+            static_assert(tokens, &mut env);
+            continue;
+        }
+        let (base, attr) = declspec(tokens, &mut env);
+        if attr.is_typedef {
+            parse_typedef(tokens, &mut env, base);
+            continue;
+        }
+        if consume(tokens, OperatorKind::TkExprEnd) {
+            continue;
+        }
+        let (mut ty, mut name) = declarator(tokens, &mut env, base.clone());
+        let mut label = asm_label(tokens, &mut env);
+        if let TypeKind::Func { .. } = ty.kind {
+            if peek(tokens, OperatorKind::TkBrSt) {
+                let label = label.unwrap_or_else(|| name.clone());
+                let is_static = env.new_gvar_labeled(name, label.clone(), ty.clone(), attr.is_static, false).is_static;
+                functions.push(function(tokens, &mut env, label, ty, is_static));
+                continue;
+            }
+        }
+        loop {
+            if let TypeKind::Func { .. } = ty.kind {
+                let label = label.unwrap_or_else(|| name.clone());
+                env.new_gvar_labeled(name, label, ty, attr.is_static, false);
+            } else if let Some(label) = label {
+                global_variable(tokens, &mut env, label, ty, &attr);
+                env.scopes[0].vars.last_mut().unwrap().0 = name;
+            } else {
+                global_variable(tokens, &mut env, name, ty, &attr);
+            }
+            if !consume(tokens, OperatorKind::TkComma) {
+                break;
             }
-            Node::Block(stmts)
+            let (t, n) = declarator(tokens, &mut env, base.clone());
+            ty = t;
+            name = n;
+            label = asm_label(tokens, &mut env);
         }
-        _ => {
-            let node = expr(tokens, lvars);
-            if let Token::Operator { kind: OperatorKind::TkExprEnd } = &tokens[0] {
-                tokens.remove(0);
+        expect(tokens, OperatorKind::TkExprEnd);
+    }
+    Program { globals: env.globals, functions }
+}
+
+fn function(tokens: &mut Vec<Token>, env: &mut Env, name: String, ty: Type, is_static: bool) -> Function {
+    env.stack_size = 0;
+    env.func_name = name.clone();
+    if let TypeKind::Func { ret, .. } = &ty.kind {
+        env.ret_ty = (**ret).clone();
+    }
+    env.enter_scope();
+    let names = std::mem::take(&mut env.param_names);
+    let mut params = Vec::new();
+    let mut variadic = false;
+    if let TypeKind::Func { params: ptys, variadic: v, .. } = &ty.kind {
+        // A definition with an empty parameter list takes no arguments.
+        variadic = *v && !ptys.is_empty();
+        for (pname, pty) in names.into_iter().zip(ptys.iter()) {
+            if pname.is_empty() {
+                error(&format!("parameter name omitted in definition of '{}'", name));
             }
-            node
+            let offset = env.new_lvar(pname, pty.clone());
+            params.push((offset, pty.clone()));
         }
     }
+    // A variadic function saves the argument registers to a 176-byte area:
+    // six general-purpose registers followed by eight XMM registers.
+    let va_area = if variadic {
+        let offset = env.new_lvar("__va_area__".to_string(), Type::array_of(Type::long(), 22));
+        env.va_area = Some((offset, params.iter().map(|(_, ty)| ty.clone()).collect()));
+        Some(offset)
+    } else {
+        None
+    };
+    expect(tokens, OperatorKind::TkBrSt);
+    let body = compound_stmt(tokens, env);
+    env.leave_scope();
+    env.va_area = None;
+    Function {
+        name,
+        params,
+        body,
+        stack_size: align_to(env.stack_size as usize, 16) as i32,
+        is_static,
+        va_area,
+    }
 }
 
+fn global_variable(tokens: &mut Vec<Token>, env: &mut Env, name: String, ty: Type, attr: &VarAttr) {
+    let is_static = attr.is_static;
+    let align = attr.align.max(env.decl_attr.align);
+    if !consume(tokens, OperatorKind::TkAssign) {
+        // An extern declaration may have an incomplete type; the
+        // definition is elsewhere.
+        if attr.is_extern {
+            env.new_gvar(name, ty, is_static, false);
+            return;
+        }
+        if let TypeKind::Array(_, 0) = ty.kind {
+            error(&format!("array size of '{}' is unknown", name));
+        }
+        if !ty.is_complete() {
+            error(&format!("storage size of '{}' isn't known", name));
+        }
+        let gvar = env.new_gvar(name, ty, is_static, true);
+        gvar.align = gvar.align.max(align);
+        return;
+    }
+    let init = initializer(tokens, env, ty);
+    let (data, relocs) = gvar_data(&init);
+    let gvar = env.new_gvar(name.clone(), init.ty, is_static, true);
+    // Tentative definitions like `int x;` may be repeated around the one
+    // definition with an initializer.
+    if gvar.init.is_some() {
+        error(&format!("redefinition of '{}'", name));
+    }
+    gvar.init = Some(data);
+    gvar.relocs = relocs;
+    gvar.align = gvar.align.max(align);
+}
 
-fn expr(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Node {
-    let node = assign(tokens, lvars);
+// Lays out the static data of an initialized global.
+fn gvar_data(init: &Initializer) -> (Vec<u8>, Vec<Reloc>) {
+    let mut data = vec![0u8; init.ty.size()];
+    let mut relocs = Vec::new();
+    write_gvar_data(init, &mut data, &mut relocs, 0);
+    (data, relocs)
+}
 
-    node
+/// An initializer shaped like the object it initializes: aggregates have
+/// one child per element or member, and initialized scalars hold their
+/// expression. Subobjects without an initializer are zero.
+struct Initializer {
+    ty: Type,
+    expr: Option<Node>,
+    children: Vec<Initializer>,
+    // An array whose length is taken from its initializer.
+    flexible: bool,
+    // The member of a union that is initialized.
+    union_member: usize,
+    // The bit offset and width when initializing a bit-field member.
+    bit_field: Option<(usize, usize)>,
 }
 
-fn assign(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Node {
-    let node = equality(tokens, lvars);
-    match &tokens[0] {
-        Token::Operator { kind: OperatorKind::TkAssign } => {
-            tokens.remove(0);
-            *Node::new(NodeKind::NdAssign, Box::new(node), Box::new(assign(tokens, lvars)))
+impl Initializer {
+    fn new(ty: Type, flexible: bool) -> Initializer {
+        let mut children = Vec::new();
+        match &ty.kind {
+            TypeKind::Array(base, len) if !flexible => {
+                for _ in 0..*len {
+                    children.push(Initializer::new((**base).clone(), false));
+                }
+            }
+            TypeKind::Struct(agg) => {
+                for mem in &agg.borrow().members {
+                    let flexible = matches!(mem.ty.kind, TypeKind::Array(_, 0));
+                    let mut child = Initializer::new(mem.ty.clone(), flexible);
+                    child.bit_field = mem.bit_field;
+                    children.push(child);
+                }
+            }
+            _ => {}
         }
-        _ => node,
+        Initializer { ty, expr: None, children, flexible, union_member: 0, bit_field: None }
     }
-}
 
-fn equality(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Node {
-    let mut node = relational(tokens, lvars);
+    fn is_aggregate(&self) -> bool {
+        matches!(self.ty.kind, TypeKind::Array(..) | TypeKind::Struct(_))
+    }
 
-    loop {
-        if tokens.len() == 0 {
-            break;
+    fn is_union(&self) -> bool {
+        match &self.ty.kind {
+            TypeKind::Struct(agg) => agg.borrow().is_union,
+            _ => false,
         }
-        match &tokens[0] {
-            Token::Operator { kind: OperatorKind::TkEq } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdEq,
-                    Box::new(node),
-                    Box::new(relational(tokens, lvars)),
-                );
-                node = node_i;
+    }
+
+    // The number of initializers that a brace-enclosed list of this object takes.
+    fn slots(&self) -> usize {
+        if self.flexible {
+            usize::MAX
+        } else if self.is_union() {
+            1
+        } else {
+            self.children.len()
+        }
+    }
+
+    fn at(&mut self, path: &[usize]) -> &mut Initializer {
+        let mut init = self;
+        for &i in path {
+            if init.flexible && i >= init.children.len() {
+                let base = init.ty.base().unwrap().clone();
+                while init.children.len() <= i {
+                    init.children.push(Initializer::new(base.clone(), false));
+                }
             }
-            Token::Operator { kind: OperatorKind::TkNEq } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdNEq,
-                    Box::new(node),
-                    Box::new(relational(tokens, lvars)),
-                );
-                node = node_i;
+            if init.is_union() {
+                init.union_member = i;
             }
-            _ => break,
+            init = &mut init.children[i];
         }
+        init
     }
-    node
-}
 
+    // Whether a string literal with elements of type `elem` can initialize
+    // the object: a char array for a narrow string, and an array of the
+    // integer type of the same rank for a wide one.
+    fn is_string_array(&self, elem: &Type) -> bool {
+        matches!(&self.ty.kind, TypeKind::Array(base, _) if base.kind == elem.kind)
+    }
+
+    fn string(&mut self, val: Vec<u8>, ty: &Type) {
+        let size = ty.size();
+        if self.flexible {
+            self.at(&[val.len() / size]);
+        }
+        for (child, c) in self.children.iter_mut().zip(val.chunks(size)) {
+            let mut bytes = [0; 8];
+            bytes[..size].copy_from_slice(c);
+            child.expr = Some(*Node::new_node_num(truncate(i64::from_le_bytes(bytes), ty)));
+        }
+    }
 
-fn relational(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Node {
-    let mut node = add(tokens, lvars);
+    fn set_expr(&mut self, expr: Node) {
+        check_qualifiers(&self.ty, &expr.ty(), "initialization");
+        self.expr = Some(expr);
+    }
 
-    loop {
-        if tokens.len() == 0 {
-            break;
+    // Fixes the length of an array whose size came from its initializer.
+    fn complete(&mut self) {
+        if self.flexible {
+            let base = self.ty.base().unwrap().clone();
+            self.ty = Type::array_of(base, self.children.len());
+            self.flexible = false;
         }
-        match &tokens[0] {
-            Token::Operator { kind: OperatorKind::TkLt } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdLt,
-                    Box::new(node),
-                    Box::new(add(tokens, lvars)),
-                );
-                node = node_i;
-            }
-            Token::Operator { kind: OperatorKind::TkLe } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdLe,
-                    Box::new(node),
-                    Box::new(add(tokens, lvars)),
-                );
-                node = node_i;
-            }
-            Token::Operator { kind: OperatorKind::TkGt } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdGt,
-                    Box::new(add(tokens, lvars)),
-                    Box::new(node),
-                );
-                node = node_i;
-            }
-            Token::Operator { kind: OperatorKind::TkGe } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdGe,
-                    Box::new(add(tokens, lvars)),
-                    Box::new(node),
-                );
-                node = node_i;
-            }
-            _ => break,
+    }
+}
+
+// initializer = "{" init_list "}" | str | assign
+// init_list   = (designation? initializer ("," designation? initializer)* ","?)?
+// designation = ("[" const_expr "]" | "." ident)+ "="
+fn initializer(tokens: &mut Vec<Token>, env: &mut Env, ty: Type) -> Initializer {
+    let flexible = matches!(ty.kind, TypeKind::Array(_, 0));
+    let mut init = Initializer::new(ty, flexible);
+    if string_initializer(tokens, &mut init) {
+        init.complete();
+        return init;
+    }
+    if consume(tokens, OperatorKind::TkBrSt) {
+        if init.is_aggregate() {
+            init_list(tokens, env, &mut init);
+        } else {
+            init.set_expr(assign(tokens, env));
+            consume(tokens, OperatorKind::TkComma);
+            expect(tokens, OperatorKind::TkBrEd);
         }
+        init.complete();
+        return init;
     }
-    node
+    let expr = assign(tokens, env);
+    match &init.ty.kind {
+        TypeKind::Array(..) => error("array must be initialized with a brace-enclosed initializer"),
+        TypeKind::Struct(_) if init.ty.kind != expr.ty().kind => error("invalid initializer for a struct or union"),
+        _ => {}
+    }
+    init.set_expr(expr);
+    init
 }
 
-fn add(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Node {
-    let mut node = mul(tokens, lvars);
+// Parses the elements of a brace-enclosed list initializing `obj`, up to and
+// including the closing brace. `pos` is the path of the current subobject;
+// initializers without braces fill nested aggregates element by element.
+fn init_list(tokens: &mut Vec<Token>, env: &mut Env, obj: &mut Initializer) {
+    let mut pos = vec![0];
+    let mut first = true;
+    while !consume(tokens, OperatorKind::TkBrEd) {
+        if !first {
+            expect(tokens, OperatorKind::TkComma);
+            if consume(tokens, OperatorKind::TkBrEd) {
+                break;
+            }
+        }
+        first = false;
+
+        if peek(tokens, OperatorKind::TkSqSt) || peek(tokens, OperatorKind::TkDot) {
+            pos = designation(tokens, env, obj);
+        } else if pos[0] >= obj.slots() {
+            error("excess elements in initializer");
+        }
 
-    loop {
-        if tokens.len() == 0 {
-            break;
+        let target = obj.at(&pos);
+        if string_initializer(tokens, target) {
+            // A string, perhaps in braces, initializes the whole array.
+        } else if consume(tokens, OperatorKind::TkBrSt) {
+            if target.is_aggregate() {
+                init_list(tokens, env, target);
+            } else {
+                target.set_expr(assign(tokens, env));
+                consume(tokens, OperatorKind::TkComma);
+                expect(tokens, OperatorKind::TkBrEd);
+            }
+        } else if let Some(Token::Str { ty, .. }) = tokens.first() {
+            let elem = ty.clone();
+            let mut target = target;
+            while target.is_aggregate() && !target.is_string_array(&elem) {
+                pos.push(0);
+                target = target.at(&[0]);
+            }
+            if target.is_string_array(&elem) {
+                if let Token::Str { val, ty } = tokens.remove(0) {
+                    target.string(val, &ty);
+                }
+            } else {
+                target.set_expr(assign(tokens, env));
+            }
+        } else {
+            let expr = assign(tokens, env);
+            let mut target = target;
+            // An expression of the same struct type initializes the whole
+            // struct; anything else initializes its first scalar.
+            while target.is_aggregate() && !(target.ty.is_struct() && target.ty.kind == expr.ty().kind) {
+                pos.push(0);
+                target = target.at(&[0]);
+            }
+            target.set_expr(expr);
         }
-        match &tokens[0] {
-            Token::Operator { kind: OperatorKind::TkAdd } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdAdd,
-                    Box::new(node),
-                    Box::new(mul(tokens, lvars)),
-                );
-                node = node_i;
+
+        // Move to the next subobject, leaving aggregates that are full.
+        loop {
+            *pos.last_mut().unwrap() += 1;
+            if pos.len() == 1 {
+                break;
             }
-            Token::Operator { kind: OperatorKind::TkSub } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdSub,
-                    Box::new(node),
-                    Box::new(mul(tokens, lvars)),
-                );
-                node = node_i;
+            let parent = obj.at(&pos[..pos.len() - 1]);
+            if pos[pos.len() - 1] < parent.slots() {
+                break;
             }
-            _ => break,
+            pos.pop();
         }
     }
-    node
 }
 
-fn mul(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Node {
-    let mut node = unary(tokens, lvars).unwrap();
+// Initializes a char array with a string literal, which may be enclosed in
+// braces as in `char s[] = {"abc"};`, or a wchar_t array with a wide one.
+// Returns false, consuming nothing, if `init` is no such array or the tokens
+// are no string initializer.
+fn string_initializer(tokens: &mut Vec<Token>, init: &mut Initializer) -> bool {
+    let (braced, elem) = match &tokens[..] {
+        [Token::Str { ty, .. }, ..] => (false, ty),
+        [Token::Operator { kind: OperatorKind::TkBrSt }, Token::Str { ty, .. }, ..] => (true, ty),
+        _ => return false,
+    };
+    if !init.is_string_array(elem) {
+        return false;
+    }
+    if braced {
+        tokens.remove(0);
+    }
+    if let Token::Str { val, ty } = tokens.remove(0) {
+        init.string(val, &ty);
+    }
+    if braced {
+        consume(tokens, OperatorKind::TkComma);
+        expect(tokens, OperatorKind::TkBrEd);
+    }
+    true
+}
 
+fn designation(tokens: &mut Vec<Token>, env: &mut Env, obj: &mut Initializer) -> Vec<usize> {
+    let mut path = Vec::new();
     loop {
-        if tokens.len() == 0 {
+        let cur = obj.at(&path);
+        if consume(tokens, OperatorKind::TkSqSt) {
+            if !matches!(cur.ty.kind, TypeKind::Array(..)) {
+                error("array index in non-array initializer");
+            }
+            let idx = const_expr(tokens, env);
+            expect(tokens, OperatorKind::TkSqEd);
+            if idx < 0 || (!cur.flexible && idx as usize >= cur.children.len()) {
+                error("array index in initializer exceeds array bounds");
+            }
+            path.push(idx as usize);
+        } else if consume(tokens, OperatorKind::TkDot) {
+            let name = expect_ident(tokens);
+            let found = match &cur.ty.kind {
+                TypeKind::Struct(agg) => agg.borrow().find_member_path(&name),
+                _ => error(&format!("field name '{}' not in record or union initializer", name)),
+            };
+            match found {
+                Some(p) => path.extend(p),
+                None => error(&format!("unknown field '{}' specified in initializer", name)),
+            }
+        } else {
             break;
         }
-        match &tokens[0] {
-            Token::Operator { kind: OperatorKind::TkMul } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdMul,
-                    Box::new(node),
-                    Box::new(unary(tokens, lvars).unwrap()),
-                );
-                node = node_i;
+    }
+    expect(tokens, OperatorKind::TkAssign);
+    path
+}
+
+// Lowers an initializer for a local variable to assignments into its stack slot.
+fn lvar_initializer(init: Initializer, offset: i32, ty: &Type, stmts: &mut Vec<Node>) {
+    if let Some(expr) = init.expr {
+        let lhs = Box::new(Node::LVar { offset, ty: ty.clone() });
+        let rhs = convert(Box::new(expr), ty);
+        stmts.push(Node::ExprStmt(Node::new(NodeKind::NdAssign, lhs, rhs)));
+        return;
+    }
+    stmts.push(Node::MemZero { offset, size: ty.size() });
+    lvar_init_leaves(init, offset, ty, 0, stmts);
+}
+
+fn lvar_init_leaves(init: Initializer, var_offset: i32, var_ty: &Type, offset: usize, stmts: &mut Vec<Node>) {
+    if let Some(expr) = init.expr {
+        let var = Box::new(Node::LVar { offset: var_offset, ty: var_ty.clone() });
+        let rhs = convert(Box::new(expr), &init.ty);
+        let lhs = Box::new(Node::Member { expr: var, offset, bit_field: init.bit_field, ty: init.ty });
+        stmts.push(Node::ExprStmt(Node::new(NodeKind::NdAssign, lhs, rhs)));
+        return;
+    }
+    let union_member = init.union_member;
+    for (i, (child, child_offset)) in child_offsets(init).into_iter().enumerate() {
+        if union_member == i || child_offset.is_some() {
+            lvar_init_leaves(child, var_offset, var_ty, offset + child_offset.unwrap_or(0), stmts);
+        }
+    }
+}
+
+// Pairs each child of an aggregate initializer with its offset in the
+// aggregate. Union members have no offset of their own.
+fn child_offsets(init: Initializer) -> Vec<(Initializer, Option<usize>)> {
+    let offsets: Vec<Option<usize>> = match &init.ty.kind {
+        TypeKind::Array(base, _) => (0..init.children.len()).map(|i| Some(i * base.size())).collect(),
+        TypeKind::Struct(agg) => {
+            let agg = agg.borrow();
+            agg.members.iter().map(|m| if agg.is_union { None } else { Some(m.offset) }).collect()
+        }
+        _ => Vec::new(),
+    };
+    init.children.into_iter().zip(offsets).collect()
+}
+
+fn write_gvar_data(init: &Initializer, data: &mut Vec<u8>, relocs: &mut Vec<Reloc>, offset: usize) {
+    if let Some(expr) = &init.expr {
+        if expr.ty().is_struct() {
+            error("initializer element is not constant");
+        }
+        let size = init.ty.size();
+        if init.ty.is_flonum() {
+            let bytes = match init.ty.kind {
+                TypeKind::Float => (eval_double(expr) as f32).to_le_bytes().to_vec(),
+                TypeKind::Double => eval_double(expr).to_le_bytes().to_vec(),
+                _ => eval_x87(expr).to_vec(),
+            };
+            data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            return;
+        }
+        let val = if expr.ty().is_flonum() {
+            double_to_int(eval_double(expr), &init.ty)
+        } else {
+            let (label, val) = eval_reloc(expr);
+            if let Some(label) = label {
+                if init.ty.size() != 8 || init.bit_field.is_some() {
+                    error("initializer element is not computable at load time");
+                }
+                relocs.push(Reloc { offset, label, addend: val });
+                return;
+            }
+            truncate(val, &init.ty)
+        };
+        if let Some((bit_offset, width)) = init.bit_field {
+            // Merge the field into the bits of its storage unit already written.
+            let mut unit = [0; 8];
+            unit[..size].copy_from_slice(&data[offset..offset + size]);
+            let mask = (u64::MAX >> (64 - width)) << bit_offset;
+            let unit = (u64::from_le_bytes(unit) & !mask) | ((val as u64) << bit_offset & mask);
+            data[offset..offset + size].copy_from_slice(&unit.to_le_bytes()[..size]);
+            return;
+        }
+        data[offset..offset + size].copy_from_slice(&val.to_le_bytes()[..size]);
+        return;
+    }
+    match &init.ty.kind {
+        TypeKind::Array(base, _) => {
+            for (i, child) in init.children.iter().enumerate() {
+                write_gvar_data(child, data, relocs, offset + i * base.size());
             }
-            Token::Operator { kind: OperatorKind::TkDiv } => {
-                tokens.remove(0);
-                let node_i = *Node::new(
-                    NodeKind::NdDiv,
-                    Box::new(node),
-                    Box::new(unary(tokens, lvars).unwrap()),
-                );
-                node = node_i;
+        }
+        TypeKind::Struct(agg) => {
+            let agg = agg.borrow();
+            for (i, (child, mem)) in init.children.iter().zip(agg.members.iter()).enumerate() {
+                if !agg.is_union || i == init.union_member {
+                    write_gvar_data(child, data, relocs, offset + mem.offset);
+                }
             }
-            _ => break,
         }
+        _ => {}
     }
-    node
 }
 
-fn unary(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Option<Node> {
-    match &tokens[0] {
-        Token::Operator { kind: OperatorKind::TkAdd } => {
-            tokens.remove(0);
-            primary(tokens, lvars)
+// Parses an integer constant expression, as required for array sizes, case
+// labels, enumerators, bit-field widths and static assertions.
+fn const_expr(tokens: &mut Vec<Token>, env: &mut Env) -> i64 {
+    let node = conditional(tokens, env);
+    if !node.ty().is_integer() {
+        error("expression is not an integer constant expression");
+    }
+    eval(&node)
+}
+
+fn static_assert(tokens: &mut Vec<Token>, env: &mut Env) {
+    expect(tokens, OperatorKind::TkPrSt);
+    let val = const_expr(tokens, env);
+    let msg = if consume(tokens, OperatorKind::TkComma) {
+        match tokens.remove(0) {
+            Token::Str { val, .. } => Some(String::from_utf8_lossy(&val).into_owned()),
+            _ => error("expected a string literal"),
         }
-        Token::Operator { kind: OperatorKind::TkSub } => {
-            tokens.remove(0);
-            Some(*Node::new(NodeKind::NdSub, Node::new_node_num(0), Box::new(primary(tokens, lvars).unwrap())))
+    } else {
+        None
+    };
+    expect(tokens, OperatorKind::TkPrEd);
+    expect(tokens, OperatorKind::TkExprEnd);
+    if val == 0 {
+        match msg {
+            Some(msg) => error(&format!("static assertion failed: \"{}\"", msg)),
+            None => error("static assertion failed"),
         }
-        _ => primary(tokens, lvars),
     }
 }
 
-fn primary(tokens: &mut Vec<Token>, lvars: &mut Vec<LVar>) -> Option<Node> {
-    let first_token = (&tokens).first().unwrap().clone();
-    match first_token {
-        Token::Operator { kind: OperatorKind::TkPrSt } => {
-            tokens.remove(0);
-            let node = expr(tokens, lvars);
-            if let Token::Operator { kind: OperatorKind::TkPrEd } = &tokens[0] {
-                tokens.remove(0);
+fn eval(node: &Node) -> i64 {
+    match eval_reloc(node) {
+        (None, val) => val,
+        (Some(_), _) => error("not a constant expression"),
+    }
+}
+
+// Evaluates a constant expression of arithmetic type as a double.
+fn eval_double(node: &Node) -> f64 {
+    let ty = node.ty();
+    if ty.is_integer() {
+        let val = eval(node);
+        return if ty.is_unsigned { val as u64 as f64 } else { val as f64 };
+    }
+    match node {
+        Node::FNumber { val, .. } => *val,
+        Node::Cast { expr, ty } if ty.kind == TypeKind::Float => eval_double(expr) as f32 as f64,
+        Node::Cond { cond, then, els, .. } => {
+            if eval(cond) != 0 {
+                eval_double(then)
+            } else {
+                eval_double(els)
             }
-            Some(node)
         }
-        Token::Ident { name } => {
-            let offset = find_lvar(name.to_string(), lvars);
-            tokens.remove(0);
-            Some(*Node::new_node_lvar(offset))
+        Node::Cast { expr, .. } => eval_double(expr),
+        Node::Neg { expr, .. } => -eval_double(expr),
+        Node::Operator { kind, lhs, rhs, ty } => {
+            let (l, r) = (eval_double(lhs), eval_double(rhs));
+            let val = match kind {
+                NodeKind::NdAdd => l + r,
+                NodeKind::NdSub => l - r,
+                NodeKind::NdMul => l * r,
+                NodeKind::NdDiv => l / r,
+                _ => error("not a constant expression"),
+            };
+            if ty.kind == TypeKind::Float {
+                val as f32 as f64
+            } else {
+                val
+            }
         }
-        Token::Number { val } => {
-            let v = *val;
-            tokens.remove(0);
-            Some(*Node::new_node_num(v))
+        _ => error("not a constant expression"),
+    }
+}
+
+// Encodes a double in the 80-bit x87 extended format: a sign bit, a 15-bit
+// exponent with bias 16383, and a 64-bit significand with an explicit
+// integer bit.
+fn x87_bytes(val: f64) -> [u8; 10] {
+    let bits = val.to_bits();
+    let sign = (bits >> 63) as u16;
+    let exp = ((bits >> 52) & 0x7ff) as i32;
+    let frac = bits & ((1 << 52) - 1);
+    let (exp, mant) = match exp {
+        0 if frac == 0 => (0, 0),
+        // Subnormal doubles are normal in the wider format.
+        0 => {
+            let shift = frac.leading_zeros() as i32;
+            (16383 + 63 - 1074 - shift, frac << shift)
         }
-        _ => {
-            None
+        0x7ff => (0x7fff, (1 << 63) | (frac << 11)),
+        _ => (exp - 1023 + 16383, (1 << 63) | (frac << 11)),
+    };
+    let mut bytes = [0; 10];
+    bytes[..8].copy_from_slice(&mant.to_le_bytes());
+    bytes[8..].copy_from_slice(&((sign << 15) | exp as u16).to_le_bytes());
+    bytes
+}
+
+// Evaluates a constant expression of type long double in the x87 format.
+// Constants, integers and negation are exact; other arithmetic is done in
+// double precision.
+fn eval_x87(node: &Node) -> [u8; 10] {
+    match node {
+        Node::FNumber { x87, ty, .. } if ty.kind == TypeKind::LDouble => *x87,
+        Node::Cast { expr, .. } if expr.ty().is_integer() => {
+            let val = eval(expr);
+            let (neg, abs) = if !expr.ty().is_unsigned && val < 0 { (true, val.unsigned_abs()) } else { (false, val as u64) };
+            let mut bytes = [0; 10];
+            if abs != 0 {
+                let shift = abs.leading_zeros();
+                bytes[..8].copy_from_slice(&(abs << shift).to_le_bytes());
+                bytes[8..].copy_from_slice(&((neg as u16) << 15 | (16383 + 63 - shift) as u16).to_le_bytes());
+            }
+            bytes
+        }
+        Node::Cast { expr, .. } if expr.ty().kind == TypeKind::LDouble => eval_x87(expr),
+        Node::Neg { expr, .. } => {
+            let mut bytes = eval_x87(expr);
+            bytes[9] ^= 0x80;
+            bytes
+        }
+        Node::Cond { cond, then, els, .. } => {
+            if eval(cond) != 0 {
+                eval_x87(then)
+            } else {
+                eval_x87(els)
+            }
+        }
+        _ => x87_bytes(eval_double(node)),
+    }
+}
+
+// A key ordering x87 values like the numbers they stand for, or None for a NaN.
+fn x87_order(bytes: &[u8; 10]) -> Option<i128> {
+    let mant = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let se = u16::from_le_bytes([bytes[8], bytes[9]]);
+    let exp = se & 0x7fff;
+    if exp == 0x7fff && mant << 1 != 0 {
+        return None;
+    }
+    let key = (exp as i128) << 64 | mant as i128;
+    Some(if se >> 15 != 0 { -key } else { key })
+}
+
+// Converts a floating value to an integer type, truncating toward zero.
+fn double_to_int(val: f64, ty: &Type) -> i64 {
+    if ty.kind == TypeKind::Bool {
+        (val != 0.0) as i64
+    } else if ty.is_unsigned {
+        truncate(val as u64 as i64, ty)
+    } else {
+        truncate(val as i64, ty)
+    }
+}
+
+// Evaluates a constant expression that may be the address of a global plus an offset.
+fn eval_reloc(node: &Node) -> (Option<String>, i64) {
+    match node {
+        Node::Number { val, .. } => (None, *val),
+        Node::Cast { expr, ty } if expr.ty().is_flonum() => (None, double_to_int(eval_double(expr), ty)),
+        Node::Operator { kind, lhs, rhs, .. } if lhs.ty().kind == TypeKind::LDouble => {
+            let (l, r) = (x87_order(&eval_x87(lhs)), x87_order(&eval_x87(rhs)));
+            let val = match kind {
+                NodeKind::NdEq => l.is_some() && l == r,
+                NodeKind::NdNEq => l.is_none() || l != r,
+                NodeKind::NdLt | NodeKind::NdGt => l.is_some() && r.is_some() && l < r,
+                NodeKind::NdLe | NodeKind::NdGe => l.is_some() && r.is_some() && l <= r,
+                _ => error("not a constant expression"),
+            };
+            (None, val as i64)
+        }
+        Node::Operator { kind, lhs, rhs, .. } if lhs.ty().is_flonum() => {
+            let (l, r) = (eval_double(lhs), eval_double(rhs));
+            let val = match kind {
+                NodeKind::NdEq => l == r,
+                NodeKind::NdNEq => l != r,
+                NodeKind::NdLt | NodeKind::NdGt => l < r,
+                NodeKind::NdLe | NodeKind::NdGe => l <= r,
+                _ => error("not a constant expression"),
+            };
+            (None, val as i64)
+        }
+        // An array or function designator decays to its address.
+        Node::GVar { ty, .. } | Node::Member { ty, .. } | Node::Deref { ty, .. }
+            if matches!(ty.kind, TypeKind::Array(..) | TypeKind::Func { .. }) =>
+        {
+            eval_addr(node)
+        }
+        Node::Addr { expr, .. } => eval_addr(expr),
+        Node::Cond { cond, then, els, .. } => {
+            if eval(cond) != 0 {
+                eval_reloc(then)
+            } else {
+                eval_reloc(els)
+            }
+        }
+        Node::Operator { kind: NodeKind::NdLogAnd, lhs, rhs, .. } => (None, (eval(lhs) != 0 && eval(rhs) != 0) as i64),
+        Node::Operator { kind: NodeKind::NdLogOr, lhs, rhs, .. } => (None, (eval(lhs) != 0 || eval(rhs) != 0) as i64),
+        Node::Cast { expr, ty } => match eval_reloc(expr) {
+            (None, val) => (None, truncate(val, ty)),
+            (Some(_), _) if ty.size() < 8 => error("not a constant expression"),
+            reloc => reloc,
+        },
+        Node::Operator { kind, lhs, rhs, ty } => {
+            let (label, l) = eval_reloc(lhs);
+            match kind {
+                NodeKind::NdAdd => {
+                    let r = eval(rhs);
+                    if label.is_none() {
+                        check_overflow(l as i128 + r as i128, ty);
+                    }
+                    (label, truncate(l.wrapping_add(r), ty))
+                }
+                NodeKind::NdSub => match eval_reloc(rhs) {
+                    (None, r) => {
+                        if label.is_none() {
+                            check_overflow(l as i128 - r as i128, ty);
+                        }
+                        (label, truncate(l.wrapping_sub(r), ty))
+                    }
+                    (Some(_), _) => error("not a constant expression"),
+                },
+                _ => {
+                    if label.is_some() {
+                        error("not a constant expression");
+                    }
+                    let r = eval(rhs);
+                    let unsigned = lhs.ty().is_unsigned;
+                    if let NodeKind::NdShl | NodeKind::NdShr = kind {
+                        if r < 0 {
+                            warn("shift count is negative");
+                        } else if r as usize >= ty.size() * 8 {
+                            warn("shift count >= width of type");
+                        }
+                    }
+                    match kind {
+                        NodeKind::NdMul => check_overflow(l as i128 * r as i128, ty),
+                        NodeKind::NdDiv if r != 0 => check_overflow(l as i128 / r as i128, ty),
+                        NodeKind::NdShl if r >= 0 && (r as usize) < ty.size() * 8 && l >= 0 => check_overflow((l as i128) << r, ty),
+                        _ => {}
+                    }
+                    let val = match kind {
+                        NodeKind::NdMul => l.wrapping_mul(r),
+                        NodeKind::NdDiv | NodeKind::NdMod if r == 0 => error("division by zero in a constant expression"),
+                        NodeKind::NdDiv if unsigned => ((l as u64) / (r as u64)) as i64,
+                        NodeKind::NdDiv => l.wrapping_div(r),
+                        NodeKind::NdMod if unsigned => ((l as u64) % (r as u64)) as i64,
+                        NodeKind::NdMod => l.wrapping_rem(r),
+                        NodeKind::NdBitAnd => l & r,
+                        NodeKind::NdBitOr => l | r,
+                        NodeKind::NdBitXor => l ^ r,
+                        NodeKind::NdShl => l.wrapping_shl(r as u32),
+                        NodeKind::NdShr if unsigned => ((l as u64).wrapping_shr(r as u32)) as i64,
+                        NodeKind::NdShr => l.wrapping_shr(r as u32),
+                        NodeKind::NdEq => (l == r) as i64,
+                        NodeKind::NdNEq => (l != r) as i64,
+                        NodeKind::NdLt | NodeKind::NdGt if unsigned => ((l as u64) < (r as u64)) as i64,
+                        NodeKind::NdLt | NodeKind::NdGt => (l < r) as i64,
+                        NodeKind::NdLe | NodeKind::NdGe if unsigned => ((l as u64) <= (r as u64)) as i64,
+                        NodeKind::NdLe | NodeKind::NdGe => (l <= r) as i64,
+                        _ => error("not a constant expression"),
+                    };
+                    (None, truncate(val, ty))
+                }
+            }
+        }
+        _ => error("not a constant expression"),
+    }
+}
+
+// Signed arithmetic that overflows is undefined at run time, so it is
+// diagnosed in constant expressions; unsigned arithmetic wraps.
+fn check_overflow(exact: i128, ty: &Type) {
+    if !ty.is_unsigned && truncate(exact as i64, ty) as i128 != exact {
+        warn("integer overflow in constant expression");
+    }
+}
+
+fn eval_addr(node: &Node) -> (Option<String>, i64) {
+    match node {
+        Node::GVar { name, .. } => (Some(name.clone()), 0),
+        Node::Deref { expr, .. } => eval_reloc(expr),
+        Node::Member { expr, offset, bit_field: None, .. } => {
+            let (label, val) = eval_addr(expr);
+            (label, val + *offset as i64)
+        }
+        _ => error("not a constant expression"),
+    }
+}
+
+fn declspec(tokens: &mut Vec<Token>, env: &mut Env) -> (Type, VarAttr) {
+    let mut attr = VarAttr::default();
+    let mut base = None;
+    let mut longs = 0;
+    let (mut is_const, mut is_volatile) = (false, false);
+    let (mut is_unsigned, mut is_signed) = (false, false);
+    loop {
+        if let Some((c, v)) = type_qualifier(tokens) {
+            is_const |= c;
+            is_volatile |= v;
+        } else if consume_keyword(tokens, KeywordKind::TkStatic) {
+            attr.is_static = true;
+        } else if consume_keyword(tokens, KeywordKind::TkExtern) {
+            attr.is_extern = true;
+        } else if consume_keyword(tokens, KeywordKind::TkTypedef) {
+            attr.is_typedef = true;
+        } else if consume_keyword(tokens, KeywordKind::TkNoreturn) {
+            // A function that does not return needs no different code.
+        } else if consume_keyword(tokens, KeywordKind::TkInline) || consume_keyword(tokens, KeywordKind::TkExtension) {
+            // Inline functions are emitted like any other; `__extension__`
+            // only silences pedantic warnings.
+        } else if let Some(body) = attribute(tokens) {
+            layout_attribute(&body, env, &mut attr);
+        } else if consume_keyword(tokens, KeywordKind::TkAlignas) {
+            expect(tokens, OperatorKind::TkPrSt);
+            let align = if is_typename(tokens, env) {
+                type_name(tokens, env).align() as i64
+            } else {
+                const_expr(tokens, env)
+            };
+            expect(tokens, OperatorKind::TkPrEd);
+            if align < 0 || align & (align - 1) != 0 {
+                error("requested alignment is not a positive power of 2");
+            }
+            attr.align = attr.align.max(align as usize);
+        } else if consume_keyword(tokens, KeywordKind::TkUnsigned) {
+            is_unsigned = true;
+        } else if consume_keyword(tokens, KeywordKind::TkSigned) {
+            is_signed = true;
+        } else if consume_keyword(tokens, KeywordKind::TkFloat) {
+            base = Some(Type::float());
+        } else if consume_keyword(tokens, KeywordKind::TkDouble) {
+            base = Some(Type::double());
+        } else if consume_keyword(tokens, KeywordKind::TkVoid) {
+            base = Some(Type::void());
+        } else if consume_keyword(tokens, KeywordKind::TkBool) {
+            base = Some(Type::bool());
+        } else if consume_keyword(tokens, KeywordKind::TkChar) {
+            base = Some(Type::char());
+        } else if consume_keyword(tokens, KeywordKind::TkShort) {
+            base = Some(Type::short());
+        } else if consume_keyword(tokens, KeywordKind::TkInt) {
+            if base.is_none() {
+                base = Some(Type::int());
+            }
+        } else if consume_keyword(tokens, KeywordKind::TkLong) {
+            longs += 1;
+            if !matches!(&base, Some(ty) if ty.kind == TypeKind::Double) {
+                base = Some(Type::long());
+            }
+        } else if consume_keyword(tokens, KeywordKind::TkStruct) {
+            base = Some(struct_decl(tokens, env, false));
+        } else if consume_keyword(tokens, KeywordKind::TkUnion) {
+            base = Some(struct_decl(tokens, env, true));
+        } else if consume_keyword(tokens, KeywordKind::TkEnum) {
+            base = Some(enum_decl(tokens, env));
+        } else if let (None, false, false, Some(ty)) = (&base, is_unsigned, is_signed, typedef_name(tokens, env)) {
+            // Once a type specifier has been seen, an identifier is the declarator.
+            tokens.remove(0);
+            base = Some(ty);
+        } else {
+            break;
+        }
+    }
+    if attr.is_static as u8 + attr.is_extern as u8 + attr.is_typedef as u8 > 1 {
+        error("multiple storage classes in declaration specifiers");
+    }
+    if longs > 2 {
+        error("too many 'long's in declaration");
+    }
+    if let Some(ty) = &base {
+        if ty.kind == TypeKind::Double && longs > 0 {
+            if longs > 1 {
+                error("too many 'long's in declaration");
+            }
+            base = Some(Type::ldouble());
+        }
+    }
+    if is_unsigned || is_signed {
+        if is_unsigned && is_signed {
+            error("both 'signed' and 'unsigned' in declaration specifiers");
+        }
+        let ty = base.unwrap_or_else(Type::int);
+        if !ty.is_integer() || ty.is_unsigned {
+            error("'signed' or 'unsigned' used with a non-integer type");
+        }
+        base = Some(if is_unsigned { ty.make_unsigned() } else { ty });
+    }
+    match base {
+        Some(ty) => (ty.qualified(is_const, is_volatile), attr),
+        None => error(&format!("expected a type name, but got {:?}", tokens.first())),
+    }
+}
+
+// Skips a GNU `__attribute__((...))`, returning its contents if there was
+// one. Most attributes do not change the code we generate and are ignored.
+fn attribute(tokens: &mut Vec<Token>) -> Option<Vec<Token>> {
+    if !consume_keyword(tokens, KeywordKind::TkAttribute) {
+        return None;
+    }
+    if !peek(tokens, OperatorKind::TkPrSt) {
+        error(&format!("expected {:?}, but got {:?}", OperatorKind::TkPrSt, tokens.first()));
+    }
+    let close = matching_paren(tokens);
+    let mut body: Vec<Token> = tokens.drain(..=close).collect();
+    body.pop();
+    body.remove(0);
+    Some(body)
+}
+
+// Reads the `packed` and `aligned` attributes from the body of an
+// `__attribute__`, which change the layout of structs and the alignment of
+// objects. `aligned` without an argument asks for the largest alignment
+// of the target, 16. Other attributes are ignored.
+fn layout_attribute(body: &[Token], env: &mut Env, attr: &mut VarAttr) {
+    let mut i = 1;
+    while i + 1 < body.len() {
+        let name = match &body[i] {
+            Token::Ident { name } => name.trim_matches('_').to_string(),
+            _ => String::new(),
+        };
+        i += 1;
+        let mut args = Vec::new();
+        if peek(&body[i..], OperatorKind::TkPrSt) {
+            let close = i + matching_paren(&body[i..]);
+            args = body[i + 1..close].to_vec();
+            i = close + 1;
+        }
+        match name.as_str() {
+            "packed" => attr.packed = true,
+            "aligned" => {
+                let align = if args.is_empty() { 16 } else { const_expr(&mut args, env) };
+                if align <= 0 || align & (align - 1) != 0 || !args.is_empty() {
+                    error("requested alignment is not a positive power of 2");
+                }
+                attr.align = attr.align.max(align as usize);
+            }
+            _ => {}
+        }
+        // Skip the comma before the next attribute.
+        i += 1;
+    }
+}
+
+// Reads a run of attributes, keeping their `packed` and `aligned`.
+fn attributes(tokens: &mut Vec<Token>, env: &mut Env, attr: &mut VarAttr) {
+    while let Some(body) = attribute(tokens) {
+        layout_attribute(&body, env, attr);
+    }
+}
+
+// An asm label after a declarator, as in `int f(void) __asm__("g")`, makes
+// the declaration refer to the symbol `g`. Attributes may surround it.
+fn asm_label(tokens: &mut Vec<Token>, env: &mut Env) -> Option<String> {
+    let mut attr = env.decl_attr;
+    attributes(tokens, env, &mut attr);
+    env.decl_attr = attr;
+    if !consume_keyword(tokens, KeywordKind::TkAsm) {
+        return None;
+    }
+    expect(tokens, OperatorKind::TkPrSt);
+    let mut label = Vec::new();
+    while let Some(Token::Str { .. }) = tokens.first() {
+        if let Token::Str { val, .. } = tokens.remove(0) {
+            label.extend(val);
+        }
+    }
+    expect(tokens, OperatorKind::TkPrEd);
+    let mut attr = env.decl_attr;
+    attributes(tokens, env, &mut attr);
+    env.decl_attr = attr;
+    if label.is_empty() {
+        error("expected a string literal in asm label");
+    }
+    Some(String::from_utf8_lossy(&label).into_owned())
+}
+
+// The attributes after a declarator. `__mode__` gives an integer type
+// another width, as in
+// `typedef int register_t __attribute__((__mode__(__word__)))`, and
+// `packed` and `aligned` are left in `env.decl_attr` for the declaration.
+fn declarator_attributes(tokens: &mut Vec<Token>, env: &mut Env, mut ty: Type) -> Type {
+    while let Some(body) = attribute(tokens) {
+        let mut attr = env.decl_attr;
+        layout_attribute(&body, env, &mut attr);
+        env.decl_attr = attr;
+        for w in body.windows(3) {
+            let (attr, mode) = match w {
+                [Token::Ident { name: attr }, Token::Operator { kind: OperatorKind::TkPrSt }, Token::Ident { name: mode }] => (attr, mode),
+                _ => continue,
+            };
+            if attr != "__mode__" && attr != "mode" {
+                continue;
+            }
+            if !ty.is_integer() {
+                error("mode attribute on a non-integer type");
+            }
+            let mut int = match mode.trim_matches('_') {
+                "QI" | "byte" => Type::char(),
+                "HI" => Type::short(),
+                "SI" => Type::int(),
+                "DI" | "word" | "pointer" => Type::long(),
+                _ => error(&format!("unknown machine mode '{}'", mode)),
+            };
+            int.is_unsigned = ty.is_unsigned;
+            ty = int.qualified(ty.is_const, ty.is_volatile);
+        }
+    }
+    ty
+}
+
+// Consumes a type qualifier, returning whether it is `const` and whether it
+// is `volatile`. `restrict` is accepted but has no effect.
+fn type_qualifier(tokens: &mut Vec<Token>) -> Option<(bool, bool)> {
+    if consume_keyword(tokens, KeywordKind::TkConst) {
+        Some((true, false))
+    } else if consume_keyword(tokens, KeywordKind::TkVolatile) {
+        Some((false, true))
+    } else if consume_keyword(tokens, KeywordKind::TkRestrict) {
+        Some((false, false))
+    } else {
+        None
+    }
+}
+
+fn struct_decl(tokens: &mut Vec<Token>, env: &mut Env, is_union: bool) -> Type {
+    let kind = if is_union { TagKind::Union } else { TagKind::Struct };
+    let mut struct_attr = VarAttr::default();
+    attributes(tokens, env, &mut struct_attr);
+    let tag = match tokens.first() {
+        Some(Token::Ident { .. }) => Some(expect_ident(tokens)),
+        _ => None,
+    };
+    if !consume(tokens, OperatorKind::TkBrSt) {
+        let tag = match tag {
+            Some(tag) => tag,
+            None => error("expected a struct or union tag"),
+        };
+        // `struct T;` on its own declares a new incomplete type in the
+        // current scope, unless the tag is already declared there.
+        let local = peek(tokens, OperatorKind::TkExprEnd);
+        if let Some(ty) = env.find_tag(&tag, kind, local) {
+            return ty.clone();
+        }
+        let ty = Type::new(TypeKind::Struct(Rc::new(RefCell::new(Aggregate::new(Some(tag.clone()), is_union)))));
+        env.push_tag(tag, kind, ty.clone());
+        return ty;
+    }
+
+    // A definition completes an earlier declaration of the tag in the same scope.
+    let ty = match tag.as_ref().and_then(|tag| env.find_tag(tag, kind, true)) {
+        Some(ty) => {
+            if let TypeKind::Struct(agg) = &ty.kind {
+                if agg.borrow().complete {
+                    error(&format!("redefinition of '{}'", tag.unwrap()));
+                }
+            }
+            ty.clone()
+        }
+        None => {
+            let ty = Type::new(TypeKind::Struct(Rc::new(RefCell::new(Aggregate::new(tag.clone(), is_union)))));
+            if let Some(tag) = tag {
+                env.push_tag(tag, kind, ty.clone());
+            }
+            ty
+        }
+    };
+
+    // The members with their `_Alignas`, `aligned` and `packed`, which are
+    // resolved once the attributes after the body are known.
+    let mut members = Vec::new();
+    while !consume(tokens, OperatorKind::TkBrEd) {
+        if consume_keyword(tokens, KeywordKind::TkStaticAssert) {
+            static_assert(tokens, env);
+            continue;
+        }
+        let (base, attr) = declspec(tokens, env);
+        // An anonymous struct or union member makes its members visible in the enclosing one.
+        if base.is_struct() && consume(tokens, OperatorKind::TkExprEnd) {
+            members.push((None, base.clone(), None, attr));
+            continue;
+        }
+        loop {
+            // An unnamed bit-field has no declarator.
+            if consume(tokens, OperatorKind::TkColon) {
+                let width = bit_field_width(tokens, env, &base, None);
+                members.push((None, base.clone(), Some(width), attr));
+            } else {
+                let (mty, name) = declarator(tokens, env, base.clone());
+                let mut attr = VarAttr { align: attr.align.max(env.decl_attr.align), packed: attr.packed || env.decl_attr.packed, ..attr };
+                if consume(tokens, OperatorKind::TkColon) {
+                    let width = bit_field_width(tokens, env, &mty, Some(&name));
+                    attributes(tokens, env, &mut attr);
+                    if attr.align != 0 {
+                        error(&format!("alignment specified for bit-field '{}'", name));
+                    }
+                    members.push((Some(name), mty, Some(width), attr));
+                } else {
+                    let flexible = matches!(mty.kind, TypeKind::Array(_, 0)) && peek(tokens, OperatorKind::TkExprEnd);
+                    if !mty.is_complete() && !flexible {
+                        error(&format!("field '{}' has incomplete type", name));
+                    }
+                    members.push((Some(name), mty, None, attr));
+                }
+            }
+            if !consume(tokens, OperatorKind::TkComma) {
+                break;
+            }
+        }
+        expect(tokens, OperatorKind::TkExprEnd);
+    }
+    attributes(tokens, env, &mut struct_attr);
+    // `_Alignas` and `aligned` raise a member's alignment, but cannot lower
+    // it; `packed` lowers it to 1 first.
+    let members = members.into_iter().map(|(name, ty, width, attr)| {
+        let packed = attr.packed || struct_attr.packed;
+        let align = match width {
+            Some(_) => packed.then_some(1),
+            None if packed => Some(attr.align.max(1)),
+            None => (attr.align > ty.align()).then_some(attr.align),
+        };
+        (name, ty, width, align)
+    }).collect();
+    if let TypeKind::Struct(agg) = &ty.kind {
+        let mut agg = agg.borrow_mut();
+        agg.layout(members, struct_attr.align);
+        for mem in &agg.members {
+            if let Some((bit_offset, width)) = mem.bit_field {
+                if bit_offset + width > mem.ty.size() * 8 {
+                    error(&format!("packed bit-field '{}' crosses the storage unit of its type", mem.name.as_deref().unwrap_or_default()));
+                }
+            }
+        }
+    }
+    ty
+}
+
+// Parses the width of a bit-field after the colon. Only a named bit-field
+// may have zero width, and none may be wider than its type.
+fn bit_field_width(tokens: &mut Vec<Token>, env: &mut Env, ty: &Type, name: Option<&str>) -> usize {
+    let named = name.is_some();
+    let name = name.unwrap_or("<anonymous>");
+    if !ty.is_integer() {
+        error(&format!("bit-field '{}' has invalid type", name));
+    }
+    let width = const_expr(tokens, env);
+    if width < 0 {
+        error(&format!("negative width in bit-field '{}'", name));
+    }
+    if width == 0 && named {
+        error(&format!("zero width for bit-field '{}'", name));
+    }
+    if width as usize > ty.size() * 8 {
+        error(&format!("width of '{}' exceeds its type", name));
+    }
+    width as usize
+}
+
+// Enumeration types are compatible with int, so they are represented as int.
+fn enum_decl(tokens: &mut Vec<Token>, env: &mut Env) -> Type {
+    let tag = match tokens.first() {
+        Some(Token::Ident { .. }) => Some(expect_ident(tokens)),
+        _ => None,
+    };
+    if !consume(tokens, OperatorKind::TkBrSt) {
+        match tag {
+            Some(tag) => match env.find_tag(&tag, TagKind::Enum, false) {
+                Some(ty) => return ty.clone(),
+                None => error(&format!("use of undeclared enum '{}'", tag)),
+            },
+            None => error("expected an enum tag"),
+        }
+    }
+    if let Some(tag) = &tag {
+        if env.find_tag(tag, TagKind::Enum, true).is_some() {
+            error(&format!("redefinition of '{}'", tag));
+        }
+    }
+
+    let mut val: i64 = 0;
+    while !consume(tokens, OperatorKind::TkBrEd) {
+        let name = expect_ident(tokens);
+        if consume(tokens, OperatorKind::TkAssign) {
+            val = const_expr(tokens, env);
+        }
+        if val != val as i32 as i64 {
+            error(&format!("enumerator value for '{}' is not an integer constant", name));
+        }
+        env.scopes.last_mut().unwrap().vars.push((name, Var::EnumConst(val)));
+        val += 1;
+        if !consume(tokens, OperatorKind::TkComma) {
+            expect(tokens, OperatorKind::TkBrEd);
+            break;
+        }
+    }
+    if let Some(tag) = tag {
+        env.push_tag(tag, TagKind::Enum, Type::int());
+    }
+    Type::int()
+}
+
+fn declarator(tokens: &mut Vec<Token>, env: &mut Env, base: Type) -> (Type, String) {
+    env.decl_attr = VarAttr::default();
+    match any_declarator(tokens, env, base) {
+        (ty, Some(name)) => (ty, name),
+        _ => error(&format!("expected an identifier, but got {:?}", tokens.first())),
+    }
+}
+
+fn abstract_declarator(tokens: &mut Vec<Token>, env: &mut Env, base: Type) -> Type {
+    env.decl_attr = VarAttr::default();
+    match any_declarator(tokens, env, base) {
+        (_, None) if env.decl_attr.align != 0 => error("'aligned' attribute in a type name is not supported"),
+        (ty, None) => ty,
+        (_, Some(name)) => error(&format!("unexpected identifier '{}' in type name", name)),
+    }
+}
+
+// type_name = declspec abstract_declarator
+fn type_name(tokens: &mut Vec<Token>, env: &mut Env) -> Type {
+    let (base, attr) = declspec(tokens, env);
+    if attr.is_static || attr.is_extern || attr.is_typedef {
+        error("storage class specified in a type name");
+    }
+    abstract_declarator(tokens, env, base)
+}
+
+// Whether the "(" at the start of the tokens opens a nested declarator, as
+// in `int (*fp)(int)`, rather than a parameter list.
+fn is_nested_declarator(tokens: &[Token], env: &Env) -> bool {
+    if !peek(tokens, OperatorKind::TkPrSt) {
+        return false;
+    }
+    match tokens.get(1) {
+        Some(Token::Operator { kind: OperatorKind::TkMul | OperatorKind::TkPrSt | OperatorKind::TkSqSt }) => true,
+        Some(Token::Ident { .. }) => typedef_name(&tokens[1..], env).is_none(),
+        _ => false,
+    }
+}
+
+// declarator = ("*" qualifier*)* ("(" declarator ")" | ident?) type_suffix
+//
+// The declarator is named if an identifier appears in it and abstract otherwise.
+fn any_declarator(tokens: &mut Vec<Token>, env: &mut Env, base: Type) -> (Type, Option<String>) {
+    let mut ty = base;
+    while consume(tokens, OperatorKind::TkMul) {
+        ty = Type::pointer_to(ty);
+        loop {
+            if let Some((is_const, is_volatile)) = type_qualifier(tokens) {
+                ty = ty.qualified(is_const, is_volatile);
+            } else if let Some(body) = attribute(tokens) {
+                let mut attr = env.decl_attr;
+                layout_attribute(&body, env, &mut attr);
+                env.decl_attr = attr;
+            } else {
+                break;
+            }
+        }
+    }
+    if is_nested_declarator(tokens, env) {
+        // The suffixes after the parentheses apply before the nested
+        // declarator, so the nested tokens are set aside and parsed last.
+        let close = matching_paren(tokens);
+        let mut inner: Vec<Token> = tokens.drain(1..close).collect();
+        tokens.drain(0..2);
+        let ty = type_suffix(tokens, env, ty);
+        let (ty, name) = any_declarator(&mut inner, env, ty);
+        if let Some(t) = inner.first() {
+            error(&format!("unexpected {:?} in declarator", t));
+        }
+        return (ty, name);
+    }
+    let name = match tokens.first() {
+        Some(Token::Ident { .. }) => Some(expect_ident(tokens)),
+        _ => None,
+    };
+    let ty = type_suffix(tokens, env, ty);
+    (declarator_attributes(tokens, env, ty), name)
+}
+
+// Returns the index of the ")" matching the "(" at the start of the tokens.
+fn matching_paren(tokens: &[Token]) -> usize {
+    let mut depth = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        match t {
+            Token::Operator { kind: OperatorKind::TkPrSt } => depth += 1,
+            Token::Operator { kind: OperatorKind::TkPrEd } => {
+                depth -= 1;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+    }
+    error("expected ')' at end of input")
+}
+
+fn type_suffix(tokens: &mut Vec<Token>, env: &mut Env, ty: Type) -> Type {
+    if consume(tokens, OperatorKind::TkPrSt) {
+        return func_params(tokens, env, ty);
+    }
+    if consume(tokens, OperatorKind::TkSqSt) {
+        let len = if peek(tokens, OperatorKind::TkSqEd) {
+            0
+        } else {
+            let len = const_expr(tokens, env);
+            if len < 0 {
+                error("size of array is negative");
+            }
+            if len == 0 {
+                error("array size must be positive");
+            }
+            len as usize
+        };
+        expect(tokens, OperatorKind::TkSqEd);
+        let ty = type_suffix(tokens, env, ty);
+        return Type::array_of(ty, len);
+    }
+    ty
+}
+
+// func_params = ("void" | param ("," param)* ("," "...")?)? ")"
+// param       = declspec (declarator | abstract_declarator)
+fn func_params(tokens: &mut Vec<Token>, env: &mut Env, ret: Type) -> Type {
+    let mut params = Vec::new();
+    let mut names = Vec::new();
+    if matches!(tokens.first(), Some(Token::Keyword { kind: KeywordKind::TkVoid }))
+        && matches!(tokens.get(1), Some(Token::Operator { kind: OperatorKind::TkPrEd }))
+    {
+        tokens.drain(0..2);
+        env.param_names = names;
+        return Type::func(ret, params, false);
+    }
+    // An empty list leaves the parameters unspecified, so that calls are
+    // not checked against it, as for an undeclared function.
+    if consume(tokens, OperatorKind::TkPrEd) {
+        env.param_names = names;
+        return Type::func(ret, params, true);
+    }
+    let mut variadic = false;
+    while !consume(tokens, OperatorKind::TkPrEd) {
+        if !params.is_empty() {
+            expect(tokens, OperatorKind::TkComma);
+        }
+        if consume(tokens, OperatorKind::TkEllipsis) {
+            if params.is_empty() {
+                error("ISO C requires a named parameter before '...'");
+            }
+            variadic = true;
+            expect(tokens, OperatorKind::TkPrEd);
+            break;
+        }
+        let (base, attr) = declspec(tokens, env);
+        env.decl_attr = VarAttr::default();
+        let (ty, name) = any_declarator(tokens, env, base);
+        if attr.align != 0 || std::mem::take(&mut env.decl_attr).align != 0 {
+            error("alignment of a parameter is not supported");
+        }
+        let name = name.unwrap_or_default();
+        // Array and function parameters are adjusted to pointers.
+        let ty = match ty.kind {
+            TypeKind::Array(base, _) => Type::pointer_to(*base),
+            TypeKind::Func { .. } => Type::pointer_to(ty),
+            _ => ty,
+        };
+        params.push(ty);
+        names.push(name);
+    }
+    env.param_names = names;
+    Type::func(ret, params, variadic)
+}
+
+fn compound_stmt(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut stmts: Vec<Node> = Vec::new();
+    env.enter_scope();
+    while !consume(tokens, OperatorKind::TkBrEd) {
+        if tokens.is_empty() {
+            error("expected '}' at end of input");
+        }
+        if consume_keyword(tokens, KeywordKind::TkStaticAssert) {
+            static_assert(tokens, env);
+        } else if is_typename(tokens, env) {
+            declaration(tokens, env, &mut stmts);
+        } else {
+            stmts.push(stmt(tokens, env));
+        }
+    }
+    env.leave_scope();
+    Node::Block(stmts)
+}
+
+fn parse_typedef(tokens: &mut Vec<Token>, env: &mut Env, base: Type) {
+    let mut first = true;
+    while !consume(tokens, OperatorKind::TkExprEnd) {
+        if !first {
+            expect(tokens, OperatorKind::TkComma);
+        }
+        first = false;
+        let (ty, name) = declarator(tokens, env, base.clone());
+        // Types carry no alignment of their own, but an anonymous struct
+        // has no name but the typedef's, so `aligned` can raise its own.
+        let align = env.decl_attr.align;
+        if align > ty.align() {
+            match &ty.kind {
+                TypeKind::Struct(agg) if agg.borrow().tag.is_none() => {
+                    let mut agg = agg.borrow_mut();
+                    agg.align = align;
+                    agg.size = align_to(agg.size, align);
+                }
+                _ => error(&format!("'aligned' attribute on typedef '{}' is not supported", name)),
+            }
+        }
+        env.scopes.last_mut().unwrap().vars.push((name, Var::Typedef(ty)));
+    }
+}
+
+fn declaration(tokens: &mut Vec<Token>, env: &mut Env, stmts: &mut Vec<Node>) {
+    let (base, attr) = declspec(tokens, env);
+    if attr.is_typedef {
+        parse_typedef(tokens, env, base);
+        return;
+    }
+    let mut first = true;
+    while !consume(tokens, OperatorKind::TkExprEnd) {
+        if !first {
+            expect(tokens, OperatorKind::TkComma);
+        }
+        first = false;
+        let (ty, name) = declarator(tokens, env, base.clone());
+        if ty.kind == TypeKind::Void {
+            error(&format!("variable '{}' declared void", name));
+        }
+        let label = asm_label(tokens, env);
+        let align = attr.align.max(env.decl_attr.align);
+        // Block-scope extern declarations and functions refer to symbols
+        // defined elsewhere.
+        if attr.is_extern || matches!(ty.kind, TypeKind::Func { .. }) {
+            let label = label.unwrap_or_else(|| name.clone());
+            env.declare_gvar(label.clone(), ty.clone(), false, false);
+            env.scopes.last_mut().unwrap().vars.push((name, Var::Global { name: label, ty }));
+            continue;
+        }
+        if label.is_some() {
+            error(&format!("asm label on local variable '{}'", name));
+        }
+        // A static local lives in static storage under a unique label and is
+        // initialized once, at load time, like a global variable.
+        if attr.is_static {
+            let label = format!("{}.{}.{}", env.func_name, name, env.statics);
+            env.statics += 1;
+            global_variable(tokens, env, label.clone(), ty, &attr);
+            env.scopes[0].vars.pop();
+            let ty = env.globals.iter().find(|g| g.name == label).unwrap().ty.clone();
+            env.scopes.last_mut().unwrap().vars.push((name, Var::Global { name: label, ty }));
+            continue;
+        }
+        if !consume(tokens, OperatorKind::TkAssign) {
+            if let TypeKind::Array(_, 0) = ty.kind {
+                error(&format!("array size of '{}' is unknown", name));
+            }
+            if !ty.is_complete() {
+                error(&format!("storage size of '{}' isn't known", name));
+            }
+            env.new_lvar_aligned(name, ty, align);
+            continue;
+        }
+        // The variable is in scope in its own initializer, so it is
+        // declared before the initializer is parsed, except for an unsized
+        // array, whose type is only complete after its initializer.
+        let (offset, init) = if let TypeKind::Array(_, 0) = ty.kind {
+            let init = initializer(tokens, env, ty);
+            (env.new_lvar_aligned(name, init.ty.clone(), align), init)
+        } else {
+            if !ty.is_complete() {
+                error(&format!("variable '{}' has initializer but incomplete type", name));
+            }
+            let offset = env.new_lvar_aligned(name, ty.clone(), align);
+            (offset, initializer(tokens, env, ty))
+        };
+        let ty = init.ty.clone();
+        lvar_initializer(init, offset, &ty, stmts);
+    }
+}
+
+fn stmt(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    if consume_keyword(tokens, KeywordKind::TkReturn) {
+        if consume(tokens, OperatorKind::TkExprEnd) {
+            return Node::Return(None);
+        }
+        let node = expr(tokens, env);
+        check_qualifiers(&env.ret_ty, &node.ty(), "return");
+        let node = Node::Return(Some(convert(Box::new(node), &env.ret_ty)));
+        expect(tokens, OperatorKind::TkExprEnd);
+        return node;
+    }
+    if consume_keyword(tokens, KeywordKind::TkIf) {
+        expect(tokens, OperatorKind::TkPrSt);
+        let cond = Box::new(condition(expr(tokens, env)));
+        expect(tokens, OperatorKind::TkPrEd);
+        let i_st = Box::new(stmt(tokens, env));
+        let e_st = if consume_keyword(tokens, KeywordKind::TkElse) {
+            Some(Box::new(stmt(tokens, env)))
+        } else {
+            None
+        };
+        return Node::If { cond, i_st, e_st };
+    }
+    if consume_keyword(tokens, KeywordKind::TkWhile) {
+        expect(tokens, OperatorKind::TkPrSt);
+        let cond = Box::new(condition(expr(tokens, env)));
+        expect(tokens, OperatorKind::TkPrEd);
+        env.breakable += 1;
+        let st = Box::new(stmt(tokens, env));
+        env.breakable -= 1;
+        return Node::While { cond, st };
+    }
+    if consume_keyword(tokens, KeywordKind::TkFor) {
+        expect(tokens, OperatorKind::TkPrSt);
+        // A declaration in the first clause is in scope only in the loop,
+        // whose block runs its initializers first.
+        env.enter_scope();
+        let mut init = Vec::new();
+        let mut conds = Vec::new();
+        if is_typename(tokens, env) {
+            declaration(tokens, env, &mut init);
+            conds.push(None);
+        }
+        for end in [OperatorKind::TkExprEnd, OperatorKind::TkExprEnd, OperatorKind::TkPrEd][conds.len()..].iter() {
+            if consume(tokens, end.clone()) {
+                conds.push(None);
+            } else {
+                conds.push(Some(Box::new(expr(tokens, env))));
+                expect(tokens, end.clone());
+            }
+        }
+        env.breakable += 1;
+        let st = Box::new(stmt(tokens, env));
+        env.breakable -= 1;
+        env.leave_scope();
+        let cond_3 = conds.pop().unwrap();
+        let cond_2 = conds.pop().unwrap().map(|c| Box::new(condition(*c)));
+        let cond_1 = conds.pop().unwrap();
+        init.push(Node::For { cond_1, cond_2, cond_3, st });
+        return Node::Block(init);
+    }
+    if consume_keyword(tokens, KeywordKind::TkSwitch) {
+        expect(tokens, OperatorKind::TkPrSt);
+        let cond = Box::new(expr(tokens, env));
+        expect(tokens, OperatorKind::TkPrEd);
+        if !cond.ty().is_integer() {
+            error("switch quantity not an integer");
+        }
+        let ty = cond.promoted_ty();
+        let cond = new_cast(cond, ty.clone());
+        let id = env.switch_count;
+        env.switch_count += 1;
+        env.switches.push(SwitchCtx { id, ty, cases: Vec::new(), has_default: false });
+        env.breakable += 1;
+        let st = Box::new(stmt(tokens, env));
+        env.breakable -= 1;
+        let ctx = env.switches.pop().unwrap();
+        return Node::Switch { cond, st, id, cases: ctx.cases, has_default: ctx.has_default };
+    }
+    if consume_keyword(tokens, KeywordKind::TkCase) {
+        let val = const_expr(tokens, env);
+        expect(tokens, OperatorKind::TkColon);
+        let (id, idx) = match env.switches.last_mut() {
+            Some(ctx) => {
+                // Case values are converted to the promoted type of the condition.
+                let val = truncate(val, &ctx.ty);
+                if ctx.cases.contains(&val) {
+                    error(&format!("duplicate case value {}", val));
+                }
+                ctx.cases.push(val);
+                (ctx.id, ctx.cases.len() - 1)
+            }
+            None => error("case label not within a switch statement"),
+        };
+        let st = Box::new(stmt(tokens, env));
+        return Node::Case { id, idx: Some(idx), st };
+    }
+    if consume_keyword(tokens, KeywordKind::TkDefault) {
+        expect(tokens, OperatorKind::TkColon);
+        let id = match env.switches.last_mut() {
+            Some(ctx) => {
+                if ctx.has_default {
+                    error("multiple default labels in one switch");
+                }
+                ctx.has_default = true;
+                ctx.id
+            }
+            None => error("'default' label not within a switch statement"),
+        };
+        let st = Box::new(stmt(tokens, env));
+        return Node::Case { id, idx: None, st };
+    }
+    if consume_keyword(tokens, KeywordKind::TkBreak) {
+        if env.breakable == 0 {
+            error("break statement not within loop or switch");
+        }
+        expect(tokens, OperatorKind::TkExprEnd);
+        return Node::Break;
+    }
+    if consume(tokens, OperatorKind::TkBrSt) {
+        return compound_stmt(tokens, env);
+    }
+    if consume(tokens, OperatorKind::TkExprEnd) {
+        return Node::Block(Vec::new());
+    }
+    let node = expr(tokens, env);
+    expect(tokens, OperatorKind::TkExprEnd);
+    Node::ExprStmt(Box::new(node))
+}
+
+// Conditions are tested as integers, so a floating value is compared with
+// zero first.
+fn condition(node: Node) -> Node {
+    if node.ty().is_flonum() {
+        return *Node::new(NodeKind::NdNEq, Box::new(node), Node::new_node_num(0));
+    }
+    node
+}
+
+fn expr(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = assign(tokens, env);
+    while consume(tokens, OperatorKind::TkComma) {
+        node = Node::Comma { lhs: Box::new(node), rhs: Box::new(assign(tokens, env)) };
+    }
+    node
+}
+
+fn assign(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let node = conditional(tokens, env);
+    if consume(tokens, OperatorKind::TkAssign) {
+        check_assignable(&node);
+        let rhs = assign(tokens, env);
+        check_qualifiers(&node.ty(), &rhs.ty(), "assignment");
+        let rhs = convert(Box::new(rhs), &node.ty());
+        return *Node::new(NodeKind::NdAssign, Box::new(node), rhs);
+    }
+    let ops = [
+        (OperatorKind::TkAddAssign, NodeKind::NdAdd),
+        (OperatorKind::TkSubAssign, NodeKind::NdSub),
+        (OperatorKind::TkMulAssign, NodeKind::NdMul),
+        (OperatorKind::TkDivAssign, NodeKind::NdDiv),
+        (OperatorKind::TkModAssign, NodeKind::NdMod),
+        (OperatorKind::TkAndAssign, NodeKind::NdBitAnd),
+        (OperatorKind::TkOrAssign, NodeKind::NdBitOr),
+        (OperatorKind::TkXorAssign, NodeKind::NdBitXor),
+        (OperatorKind::TkShlAssign, NodeKind::NdShl),
+        (OperatorKind::TkShrAssign, NodeKind::NdShr),
+    ];
+    for (op, kind) in ops {
+        if consume(tokens, op) {
+            let rhs = assign(tokens, env);
+            return op_assign(node, kind, rhs, false);
+        }
+    }
+    node
+}
+
+// Builds `lhs op= rhs`, or with `post`, the postfix increment or
+// decrement that yields the old value. The operation applies the usual
+// conversions and pointer scaling of the binary operator, and its result
+// is converted back to the type of `lhs`.
+fn op_assign(lhs: Node, kind: NodeKind, rhs: Node, post: bool) -> Node {
+    check_assignable(&lhs);
+    let ty = lhs.ty().unqualified();
+    let bit_field = match lhs {
+        Node::Member { bit_field, .. } => bit_field,
+        _ => None,
+    };
+    let target = Node::AssignTarget { bit_field, ty: ty.clone() };
+    let value = match kind {
+        NodeKind::NdAdd => new_add(target, rhs),
+        NodeKind::NdSub => new_sub(target, rhs),
+        _ => *new_arith(kind, target, rhs),
+    };
+    if !value.ty().is_scalar() || value.ty().base().is_some() != ty.base().is_some() {
+        error("invalid operands to compound assignment");
+    }
+    let rhs = convert(Box::new(value), &ty);
+    Node::OpAssign { lhs: Box::new(lhs), rhs, ty, post }
+}
+
+fn check_assignable(node: &Node) {
+    if !matches!(node, Node::LVar { .. } | Node::GVar { .. } | Node::Deref { .. } | Node::Member { .. } | Node::CompoundLiteral { .. }) {
+        error("lvalue required as left operand of assignment");
+    }
+    let ty = node.ty();
+    if let TypeKind::Array(..) = ty.kind {
+        error("assignment to an expression with array type");
+    }
+    if !ty.is_modifiable() {
+        error("assignment of read-only location");
+    }
+}
+
+// Diagnoses a conversion to the pointer type `to` that drops a qualifier
+// of the type `from` points to.
+fn check_qualifiers(to: &Type, from: &Type, context: &str) {
+    if let (TypeKind::Ptr(to_base), Some(from_base)) = (&to.kind, from.base()) {
+        if from_base.is_const && !to_base.is_const {
+            warn(&format!("{} discards 'const' qualifier from pointer target type", context));
+        }
+        if from_base.is_volatile && !to_base.is_volatile {
+            warn(&format!("{} discards 'volatile' qualifier from pointer target type", context));
+        }
+    }
+}
+
+fn conditional(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let cond = logor(tokens, env);
+    if !consume(tokens, OperatorKind::TkQuestion) {
+        return cond;
+    }
+    let then = expr(tokens, env);
+    expect(tokens, OperatorKind::TkColon);
+    let els = conditional(tokens, env);
+    new_cond(cond, then, els)
+}
+
+// The operands of `?:` are converted to a common type: the usual arithmetic
+// conversions for numbers, the pointer type when the other operand is a
+// null pointer constant or another pointer, where `void *` wins.
+fn new_cond(cond: Node, then: Node, els: Node) -> Node {
+    if cond.ty().is_struct() {
+        error("used struct type value where scalar is required");
+    }
+    let cond = Box::new(condition(cond));
+    let (t, e) = (then.ty(), els.ty());
+    let decay = |ty: &Type| match &ty.kind {
+        TypeKind::Array(base, _) => Type::pointer_to((**base).clone()),
+        TypeKind::Func { .. } => Type::pointer_to(ty.clone()),
+        _ => ty.unqualified(),
+    };
+    let (t, e) = (decay(&t), decay(&e));
+    let ty = if t.is_arith() && e.is_arith() {
+        common_type(&then.promoted_ty(), &els.promoted_ty())
+    } else if t.kind == TypeKind::Void || e.kind == TypeKind::Void {
+        Type::void()
+    } else if t.base().is_some() && e.is_integer() {
+        t
+    } else if e.base().is_some() && t.is_integer() {
+        e
+    } else if t.base().is_some() && e.base().is_some() {
+        if e.base().unwrap().kind == TypeKind::Void { e } else { t }
+    } else if t == e {
+        t
+    } else {
+        error("type mismatch in conditional expression");
+    };
+    let (then, els) = if ty.is_scalar() {
+        (new_cast(Box::new(then), ty.clone()), new_cast(Box::new(els), ty.clone()))
+    } else {
+        (Box::new(then), Box::new(els))
+    };
+    Node::Cond { cond, then, els, ty }
+}
+
+fn logor(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = logand(tokens, env);
+    while consume(tokens, OperatorKind::TkLogOr) {
+        node = new_logical(NodeKind::NdLogOr, node, logand(tokens, env));
+    }
+    node
+}
+
+fn logand(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = bitor(tokens, env);
+    while consume(tokens, OperatorKind::TkLogAnd) {
+        node = new_logical(NodeKind::NdLogAnd, node, bitor(tokens, env));
+    }
+    node
+}
+
+fn new_logical(kind: NodeKind, lhs: Node, rhs: Node) -> Node {
+    if lhs.ty().is_struct() || rhs.ty().is_struct() {
+        error("invalid operands to binary expression");
+    }
+    let (lhs, rhs) = (Box::new(condition(lhs)), Box::new(condition(rhs)));
+    Node::Operator { kind, lhs, rhs, ty: Type::int() }
+}
+
+fn bitor(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = bitxor(tokens, env);
+    while consume(tokens, OperatorKind::TkOr) {
+        node = *new_arith(NodeKind::NdBitOr, node, bitxor(tokens, env));
+    }
+    node
+}
+
+fn bitxor(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = bitand(tokens, env);
+    while consume(tokens, OperatorKind::TkXor) {
+        node = *new_arith(NodeKind::NdBitXor, node, bitand(tokens, env));
+    }
+    node
+}
+
+fn bitand(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = equality(tokens, env);
+    while consume(tokens, OperatorKind::TkAnd) {
+        node = *new_arith(NodeKind::NdBitAnd, node, equality(tokens, env));
+    }
+    node
+}
+
+fn equality(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = relational(tokens, env);
+
+    loop {
+        if consume(tokens, OperatorKind::TkEq) {
+            node = *Node::new(NodeKind::NdEq, Box::new(node), Box::new(relational(tokens, env)));
+        } else if consume(tokens, OperatorKind::TkNEq) {
+            node = *Node::new(NodeKind::NdNEq, Box::new(node), Box::new(relational(tokens, env)));
+        } else {
+            break;
+        }
+    }
+    node
+}
+
+fn relational(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = shift(tokens, env);
+
+    loop {
+        if consume(tokens, OperatorKind::TkLt) {
+            node = *Node::new(NodeKind::NdLt, Box::new(node), Box::new(shift(tokens, env)));
+        } else if consume(tokens, OperatorKind::TkLe) {
+            node = *Node::new(NodeKind::NdLe, Box::new(node), Box::new(shift(tokens, env)));
+        } else if consume(tokens, OperatorKind::TkGt) {
+            node = *Node::new(NodeKind::NdGt, Box::new(shift(tokens, env)), Box::new(node));
+        } else if consume(tokens, OperatorKind::TkGe) {
+            node = *Node::new(NodeKind::NdGe, Box::new(shift(tokens, env)), Box::new(node));
+        } else {
+            break;
+        }
+    }
+    node
+}
+
+fn shift(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = add(tokens, env);
+
+    loop {
+        if consume(tokens, OperatorKind::TkShl) {
+            node = *new_arith(NodeKind::NdShl, node, add(tokens, env));
+        } else if consume(tokens, OperatorKind::TkShr) {
+            node = *new_arith(NodeKind::NdShr, node, add(tokens, env));
+        } else {
+            break;
+        }
+    }
+    node
+}
+
+// Converts `expr` to the type `ty`, unless it already has that type.
+fn new_cast(expr: Box<Node>, ty: Type) -> Box<Node> {
+    let from = expr.ty();
+    if from.kind == ty.kind && from.is_unsigned == ty.is_unsigned {
+        return expr;
+    }
+    Box::new(Node::Cast { expr, ty })
+}
+
+// Converts a value as if by assignment to an object of type `ty`. Only
+// scalars are converted; struct values are copied as they are.
+fn convert(expr: Box<Node>, ty: &Type) -> Box<Node> {
+    if ty.is_scalar() && expr.ty().is_scalar() {
+        new_cast(expr, ty.unqualified())
+    } else {
+        expr
+    }
+}
+
+// Truncates a constant to the width of an integer type, extending it back
+// to 64 bits according to the type's signedness.
+fn truncate(val: i64, ty: &Type) -> i64 {
+    if ty.kind == TypeKind::Bool {
+        return (val != 0) as i64;
+    }
+    match (ty.size(), ty.is_unsigned) {
+        (1, false) => val as i8 as i64,
+        (1, true) => val as u8 as i64,
+        (2, false) => val as i16 as i64,
+        (2, true) => val as u16 as i64,
+        (4, false) => val as i32 as i64,
+        (4, true) => val as u32 as i64,
+        _ => val,
+    }
+}
+
+fn new_arith(kind: NodeKind, lhs: Node, rhs: Node) -> Box<Node> {
+    let valid = match kind {
+        NodeKind::NdAdd | NodeKind::NdSub | NodeKind::NdMul | NodeKind::NdDiv => {
+            lhs.ty().is_arith() && rhs.ty().is_arith()
+        }
+        _ => lhs.ty().is_integer() && rhs.ty().is_integer(),
+    };
+    if !valid {
+        error("invalid operands to binary expression");
+    }
+    Node::new(kind, Box::new(lhs), Box::new(rhs))
+}
+
+// In C, `+` and `-` are overloaded for pointer arithmetic: `p + n` moves
+// the pointer by n elements and `p - q` counts the elements between them.
+fn new_add(lhs: Node, rhs: Node) -> Node {
+    let (lty, rty) = (lhs.ty(), rhs.ty());
+    match (lty.base(), rty.base()) {
+        (None, None) => *new_arith(NodeKind::NdAdd, lhs, rhs),
+        (Some(_), Some(_)) => error("invalid operands to binary +"),
+        (None, Some(_)) => new_add(rhs, lhs),
+        (Some(base), None) => {
+            let size = Node::new_node_long(base.size() as i64);
+            let ty = Type::pointer_to(base.clone());
+            let rhs = Node::new(NodeKind::NdMul, Box::new(rhs), size);
+            Node::Operator { kind: NodeKind::NdAdd, lhs: Box::new(lhs), rhs, ty }
+        }
+    }
+}
+
+fn new_sub(lhs: Node, rhs: Node) -> Node {
+    let (lty, rty) = (lhs.ty(), rhs.ty());
+    match (lty.base(), rty.base()) {
+        (None, None) => *new_arith(NodeKind::NdSub, lhs, rhs),
+        (Some(base), None) => {
+            let size = Node::new_node_long(base.size() as i64);
+            let ty = Type::pointer_to(base.clone());
+            let rhs = Node::new(NodeKind::NdMul, Box::new(rhs), size);
+            Node::Operator { kind: NodeKind::NdSub, lhs: Box::new(lhs), rhs, ty }
+        }
+        (Some(base), Some(_)) => {
+            let size = Node::new_node_long(base.size() as i64);
+            let diff = Node::Operator { kind: NodeKind::NdSub, lhs: Box::new(lhs), rhs: Box::new(rhs), ty: Type::long() };
+            *Node::new(NodeKind::NdDiv, Box::new(diff), size)
+        }
+        (None, Some(_)) => error("invalid operands to binary -"),
+    }
+}
+
+fn add(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = mul(tokens, env);
+
+    loop {
+        if consume(tokens, OperatorKind::TkAdd) {
+            node = new_add(node, mul(tokens, env));
+        } else if consume(tokens, OperatorKind::TkSub) {
+            node = new_sub(node, mul(tokens, env));
+        } else {
+            break;
+        }
+    }
+    node
+}
+
+fn mul(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let mut node = cast(tokens, env);
+
+    loop {
+        if consume(tokens, OperatorKind::TkMul) {
+            node = *new_arith(NodeKind::NdMul, node, cast(tokens, env));
+        } else if consume(tokens, OperatorKind::TkDiv) {
+            node = *new_arith(NodeKind::NdDiv, node, cast(tokens, env));
+        } else if consume(tokens, OperatorKind::TkMod) {
+            node = *new_arith(NodeKind::NdMod, node, cast(tokens, env));
+        } else {
+            break;
+        }
+    }
+    node
+}
+
+// A parenthesized type name starts a cast; any other "(" starts a
+// parenthesized expression.
+fn cast(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    if peek(tokens, OperatorKind::TkPrSt) && is_typename(&tokens[1..], env) {
+        tokens.remove(0);
+        let ty = type_name(tokens, env);
+        expect(tokens, OperatorKind::TkPrEd);
+        if peek(tokens, OperatorKind::TkBrSt) {
+            let node = compound_literal(tokens, env, ty);
+            return postfix_ops(tokens, env, node);
+        }
+        let expr = cast(tokens, env);
+        if ty.kind != TypeKind::Void && !ty.is_scalar() {
+            error("conversion to non-scalar type requested");
+        }
+        // Arrays and functions decay to pointers.
+        let from = expr.ty();
+        if ty.kind != TypeKind::Void && !from.is_scalar() && !matches!(from.kind, TypeKind::Array(..) | TypeKind::Func { .. }) {
+            error("operand of a cast must have scalar type");
+        }
+        return Node::Cast { expr: Box::new(expr), ty: ty.unqualified() };
+    }
+    unary(tokens, env)
+}
+
+fn unary(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    if consume_keyword(tokens, KeywordKind::TkExtension) {
+        return cast(tokens, env);
+    }
+    if consume(tokens, OperatorKind::TkAdd) {
+        let expr = Box::new(cast(tokens, env));
+        if !expr.ty().is_arith() {
+            error("wrong type argument to unary plus");
+        }
+        let ty = expr.promoted_ty();
+        return *new_cast(expr, ty);
+    }
+    if consume(tokens, OperatorKind::TkSub) {
+        let expr = Box::new(cast(tokens, env));
+        let ty = expr.ty();
+        if ty.is_flonum() {
+            return Node::Neg { expr, ty: ty.unqualified() };
+        }
+        return *Node::new(NodeKind::NdSub, Node::new_node_num(0), expr);
+    }
+    if consume(tokens, OperatorKind::TkInc) {
+        return op_assign(cast(tokens, env), NodeKind::NdAdd, *Node::new_node_num(1), false);
+    }
+    if consume(tokens, OperatorKind::TkDec) {
+        return op_assign(cast(tokens, env), NodeKind::NdSub, *Node::new_node_num(1), false);
+    }
+    if consume(tokens, OperatorKind::TkNot) {
+        let expr = cast(tokens, env);
+        if expr.ty().is_struct() {
+            error("wrong type argument to unary exclamation mark");
+        }
+        return *Node::new(NodeKind::NdEq, Box::new(expr), Node::new_node_num(0));
+    }
+    if consume(tokens, OperatorKind::TkTilde) {
+        return *new_arith(NodeKind::NdBitXor, cast(tokens, env), *Node::new_node_num(-1));
+    }
+    if consume(tokens, OperatorKind::TkAnd) {
+        let expr = Box::new(cast(tokens, env));
+        if let Node::Member { bit_field: Some(_), .. } = *expr {
+            error("cannot take address of bit-field");
+        }
+        let ty = Type::pointer_to(expr.ty());
+        return Node::Addr { expr, ty };
+    }
+    if consume(tokens, OperatorKind::TkMul) {
+        let expr = cast(tokens, env);
+        if let TypeKind::Func { .. } = expr.ty().kind {
+            return expr;
+        }
+        return *Node::new_node_deref(Box::new(expr));
+    }
+    if consume_keyword(tokens, KeywordKind::TkAlignof) {
+        if peek(tokens, OperatorKind::TkPrSt) && is_typename(&tokens[1..], env) {
+            tokens.remove(0);
+            let ty = type_name(tokens, env);
+            expect(tokens, OperatorKind::TkPrEd);
+            return *Node::new_node_ulong(ty.align() as i64);
+        }
+        let node = unary(tokens, env);
+        return *Node::new_node_ulong(node.ty().align() as i64);
+    }
+    if consume_keyword(tokens, KeywordKind::TkSizeof) {
+        if peek(tokens, OperatorKind::TkPrSt) && is_typename(&tokens[1..], env) {
+            tokens.remove(0);
+            let ty = type_name(tokens, env);
+            expect(tokens, OperatorKind::TkPrEd);
+            if !peek(tokens, OperatorKind::TkBrSt) {
+                return *Node::new_node_ulong(ty.size() as i64);
+            }
+            let node = compound_literal(tokens, env, ty);
+            let node = postfix_ops(tokens, env, node);
+            return *Node::new_node_ulong(node.ty().size() as i64);
+        }
+        let node = unary(tokens, env);
+        return *Node::new_node_ulong(node.ty().size() as i64);
+    }
+    postfix(tokens, env)
+}
+
+fn struct_ref(node: Node, name: String) -> Node {
+    let struct_ty = node.ty();
+    let found = match &struct_ty.kind {
+        TypeKind::Struct(agg) => {
+            if !agg.borrow().complete {
+                error(&format!("invalid use of incomplete type '{:?}'", agg.borrow()));
+            }
+            agg.borrow().find_member(&name)
+        }
+        _ => error(&format!("request for member '{}' in something not a structure or union", name)),
+    };
+    match found {
+        Some(mem) => {
+            // Members of a qualified struct are qualified the same way.
+            let ty = mem.ty.qualified(struct_ty.is_const, struct_ty.is_volatile);
+            Node::Member { expr: Box::new(node), offset: mem.offset, bit_field: mem.bit_field, ty }
+        }
+        None => error(&format!("no member named '{}'", name)),
+    }
+}
+
+fn postfix(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    let node = primary(tokens, env);
+    postfix_ops(tokens, env, node)
+}
+
+fn postfix_ops(tokens: &mut Vec<Token>, env: &mut Env, mut node: Node) -> Node {
+    loop {
+        if consume(tokens, OperatorKind::TkSqSt) {
+            let idx = expr(tokens, env);
+            expect(tokens, OperatorKind::TkSqEd);
+            node = *Node::new_node_deref(Box::new(new_add(node, idx)));
+        } else if consume(tokens, OperatorKind::TkPrSt) {
+            node = funcall(tokens, env, node);
+        } else if consume(tokens, OperatorKind::TkDot) {
+            node = struct_ref(node, expect_ident(tokens));
+        } else if consume(tokens, OperatorKind::TkArrow) {
+            node = struct_ref(*Node::new_node_deref(Box::new(node)), expect_ident(tokens));
+        } else if consume(tokens, OperatorKind::TkInc) {
+            node = op_assign(node, NodeKind::NdAdd, *Node::new_node_num(1), true);
+        } else if consume(tokens, OperatorKind::TkDec) {
+            node = op_assign(node, NodeKind::NdSub, *Node::new_node_num(1), true);
+        } else {
+            break;
+        }
+    }
+    node
+}
+
+// A compound literal at file scope is a static object; in a function it is
+// an unnamed local variable, initialized where the literal appears.
+fn compound_literal(tokens: &mut Vec<Token>, env: &mut Env, ty: Type) -> Node {
+    if env.scopes.len() == 1 {
+        let name = format!(".L..{}", env.strings);
+        env.strings += 1;
+        let init = initializer(tokens, env, ty);
+        let (data, relocs) = gvar_data(&init);
+        let gvar = env.declare_gvar(name.clone(), init.ty, true, true);
+        gvar.init = Some(data);
+        gvar.relocs = relocs;
+        return Node::GVar { name, ty: gvar.ty.clone() };
+    }
+    if !ty.is_complete() && !matches!(ty.kind, TypeKind::Array(_, 0)) {
+        error("compound literal has incomplete type");
+    }
+    let init = initializer(tokens, env, ty);
+    let ty = init.ty.clone();
+    let offset = env.new_lvar(String::new(), ty.clone());
+    let mut stmts = Vec::new();
+    lvar_initializer(init, offset, &ty, &mut stmts);
+    Node::CompoundLiteral { init: Box::new(Node::Block(stmts)), var: Box::new(Node::LVar { offset, ty }) }
+}
+
+fn funcall(tokens: &mut Vec<Token>, env: &mut Env, func: Node) -> Node {
+    let (ty, params, variadic) = match &func.ty().kind {
+        TypeKind::Func { ret, params, variadic } => ((**ret).clone(), params.clone(), *variadic),
+        TypeKind::Ptr(base) => match &base.kind {
+            TypeKind::Func { ret, params, variadic } => ((**ret).clone(), params.clone(), *variadic),
+            _ => error("called object is not a function or function pointer"),
+        },
+        _ => error("called object is not a function or function pointer"),
+    };
+    let mut args = Vec::new();
+    while !consume(tokens, OperatorKind::TkPrEd) {
+        if !args.is_empty() {
+            expect(tokens, OperatorKind::TkComma);
+        }
+        let arg = assign(tokens, env);
+        if arg.ty().is_struct() {
+            error("passing a struct by value is not supported");
+        }
+        let arg = match params.get(args.len()) {
+            Some(pty) => {
+                check_qualifiers(pty, &arg.ty(), &format!("passing argument {}", args.len() + 1));
+                *convert(Box::new(arg), pty)
+            }
+            // Arguments without a parameter type undergo the default
+            // argument promotions.
+            None if arg.ty().kind == TypeKind::Float => *new_cast(Box::new(arg), Type::double()),
+            None => arg,
+        };
+        args.push(arg);
+    }
+    if args.len() < params.len() || (!variadic && args.len() > params.len()) {
+        let few = if args.len() < params.len() { "few" } else { "many" };
+        error(&format!("too {} arguments to function call, expected {}, have {}", few, params.len(), args.len()));
+    }
+    if ty.is_struct() {
+        error("returning a struct by value is not supported");
+    }
+    Node::Call { func: Box::new(func), args, ty }
+}
+
+// va_builtin = "__builtin_va_start" "(" assign "," ident ")"
+//            | "__builtin_va_arg" "(" assign "," type_name ")"
+//            | "__builtin_va_copy" "(" assign "," assign ")"
+//            | "__builtin_va_end" "(" assign ")"
+fn va_builtin(tokens: &mut Vec<Token>, env: &mut Env, name: &str) -> Node {
+    expect(tokens, OperatorKind::TkPrSt);
+    let ap = Box::new(assign(tokens, env));
+    if ap.ty().base().map(|b| b.is_struct()) != Some(true) {
+        error(&format!("first argument to '{}' is not of type va_list", name));
+    }
+    let node = match name {
+        "__builtin_va_start" => {
+            expect(tokens, OperatorKind::TkComma);
+            expect_ident(tokens);
+            let (save_area, named) = match &env.va_area {
+                Some((offset, named)) => (*offset, named),
+                None => error("'va_start' used in function with fixed arguments"),
+            };
+            // The variadic arguments continue where the named ones end.
+            let (locs, stack_size) = classify_args(named);
+            let gp = locs.iter().filter(|l| matches!(l, ArgLoc::Gp(_))).count();
+            let fp = locs.iter().filter(|l| matches!(l, ArgLoc::Fp(_))).count();
+            let gp_offset = gp * 8;
+            let fp_offset = 48 + fp * 16;
+            let overflow_offset = 16 + stack_size;
+            Node::VaStart { ap, gp_offset, fp_offset, overflow_offset, save_area }
+        }
+        "__builtin_va_arg" => {
+            expect(tokens, OperatorKind::TkComma);
+            let ty = type_name(tokens, env);
+            if !ty.is_scalar() {
+                error("va_arg supports only arithmetic and pointer types");
+            }
+            Node::VaArg { ap, ty }
+        }
+        "__builtin_va_copy" => {
+            expect(tokens, OperatorKind::TkComma);
+            let src = assign(tokens, env);
+            let dst = Node::new_node_deref(ap);
+            *Node::new(NodeKind::NdAssign, dst, Node::new_node_deref(Box::new(src)))
+        }
+        // va_end has nothing to release on x86-64.
+        "__builtin_va_end" => *Node::new_node_num(0),
+        _ => error(&format!("unknown builtin '{}'", name)),
+    };
+    expect(tokens, OperatorKind::TkPrEd);
+    node
+}
+
+fn bswap_builtin(tokens: &mut Vec<Token>, env: &mut Env, name: &str) -> Node {
+    let mut ty = match name {
+        "__builtin_bswap16" => Type::short(),
+        "__builtin_bswap32" => Type::int(),
+        "__builtin_bswap64" => Type::long(),
+        _ => error(&format!("unknown builtin '{}'", name)),
+    };
+    ty.is_unsigned = true;
+    expect(tokens, OperatorKind::TkPrSt);
+    let arg = Box::new(assign(tokens, env));
+    expect(tokens, OperatorKind::TkPrEd);
+    if !arg.ty().is_integer() {
+        error(&format!("argument to '{}' is not an integer", name));
+    }
+    Node::Bswap { expr: convert(arg, &ty), ty }
+}
+
+fn primary(tokens: &mut Vec<Token>, env: &mut Env) -> Node {
+    if consume(tokens, OperatorKind::TkPrSt) {
+        let node = expr(tokens, env);
+        expect(tokens, OperatorKind::TkPrEd);
+        return node;
+    }
+    if tokens.is_empty() {
+        error("expected an expression at end of input");
+    }
+    match tokens.remove(0) {
+        Token::Ident { name } => {
+            if name.starts_with("__builtin_va_") {
+                return va_builtin(tokens, env, &name);
+            }
+            if name.starts_with("__builtin_bswap") {
+                return bswap_builtin(tokens, env, &name);
+            }
+            // A call to an undeclared function implicitly declares it as returning int.
+            if env.find_var(&name).is_none() && peek(tokens, OperatorKind::TkPrSt) {
+                return Node::GVar { name, ty: Type::func(Type::int(), Vec::new(), true) };
+            }
+            match env.find_var(&name) {
+                Some(Var::Local { offset, ty }) => Node::LVar { offset: *offset, ty: ty.clone() },
+                Some(Var::Global { name, ty }) => Node::GVar { name: name.clone(), ty: ty.clone() },
+                Some(Var::EnumConst(val)) => Node::Number { val: *val, ty: Type::int() },
+                Some(Var::Typedef(_)) => error(&format!("unexpected type name '{}'", name)),
+                None => error(&format!("undefined variable '{}'", name)),
+            }
         }
+        Token::Number { val, ty } => Node::Number { val, ty },
+        Token::Float { val, x87, ty } => Node::FNumber { val, x87, ty },
+        Token::Str { val, ty } => env.new_string(val, ty),
+        t => error(&format!("expected an expression, but got {:?}", t)),
     }
 }
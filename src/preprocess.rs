@@ -0,0 +1,1262 @@
+//! The preprocessor. It runs on the tokens of the main source, splicing in
+//! the tokens of included files and expanding macros, and leaves the parser
+//! a token stream without directives.
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::headers;
+use crate::token::{convert_token, error, replace_trigraphs, tokenize_source, warn, OperatorKind, PpToken, Token};
+
+struct Macro {
+    // The parameters of a function-like macro; `None` for an object-like one.
+    params: Option<Vec<String>>,
+    variadic: bool,
+    body: Vec<PpToken>,
+    // Computes the spelling of a macro like `__LINE__` from its invocation.
+    builtin: Option<fn(&PpToken, &PpEnv) -> String>,
+}
+
+#[derive(PartialEq)]
+enum CondCtx {
+    Then,
+    Elif,
+    Else,
+}
+
+// An open `#if` and whether one of its groups has been included.
+struct Cond {
+    ctx: CondCtx,
+    included: bool,
+    tok: PpToken,
+}
+
+pub struct PpEnv {
+    // Directories given with `-I`, searched after the directory of the
+    // including file for `#include "..."`.
+    pub include_paths: Vec<PathBuf>,
+    // Searched last, after the bundled headers.
+    pub system_paths: Vec<PathBuf>,
+    // Whether headers have their trigraphs replaced, as with `-trigraphs`.
+    pub trigraphs: bool,
+    macros: HashMap<String, Macro>,
+    conds: Vec<Cond>,
+    // Headers marked with `#pragma once`.
+    once: HashSet<Rc<str>>,
+    // The guard macro of each header wrapped in `#ifndef X ... #endif`.
+    guards: HashMap<Rc<str>, String>,
+    counter: Cell<usize>,
+}
+
+impl PpEnv {
+    pub fn new() -> PpEnv {
+        let mut env = PpEnv {
+            include_paths: Vec::new(),
+            system_paths: ["/usr/local/include", "/usr/include/x86_64-linux-gnu", "/usr/include"]
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+            trigraphs: false,
+            macros: HashMap::new(),
+            conds: Vec::new(),
+            once: HashSet::new(),
+            guards: HashMap::new(),
+            counter: Cell::new(0),
+        };
+        for def in PREDEFINED.iter() {
+            env.define_macro(def);
+        }
+        let (date, time) = date_time();
+        env.define_macro(&format!("__DATE__={}", date));
+        env.define_macro(&format!("__TIME__={}", time));
+        env.define_builtin("__FILE__", |tok, _| quote(&tok.file));
+        env.define_builtin("__LINE__", |tok, _| tok.line.to_string());
+        env.define_builtin("__COUNTER__", |_, env| {
+            let n = env.counter.get();
+            env.counter.set(n + 1);
+            n.to_string()
+        });
+        env
+    }
+
+    /// Defines a macro as `-D` does: `NAME` defines it as 1, `NAME=VALUE`
+    /// and `NAME(PARAMS)=VALUE` as the value.
+    pub fn define_macro(&mut self, def: &str) {
+        let def = match def.split_once('=') {
+            Some((name, value)) => format!("{} {}", name, value),
+            None => format!("{} 1", def),
+        };
+        let line = tokenize_source(&def, &Rc::from("<command-line>"));
+        match line.first() {
+            Some(name) => define(&line, &name.clone(), self),
+            None => error("macro names must be identifiers"),
+        }
+    }
+
+    pub fn undef_macro(&mut self, name: &str) {
+        self.macros.remove(name);
+    }
+
+    fn define_builtin(&mut self, name: &str, builtin: fn(&PpToken, &PpEnv) -> String) {
+        self.macros.insert(name.to_string(), Macro { params: None, variadic: false, body: Vec::new(), builtin: Some(builtin) });
+    }
+}
+
+// The macros every translation unit starts with, describing the language
+// version and the target: x86-64 Linux with the LP64 data model.
+const PREDEFINED: [&str; 63] = [
+    "__STDC__",
+    "__STDC_VERSION__=201112L",
+    "__STDC_HOSTED__",
+    "__STDC_NO_ATOMICS__",
+    "__STDC_NO_COMPLEX__",
+    "__STDC_NO_THREADS__",
+    "__STDC_NO_VLA__",
+    "__x86_64__",
+    "__x86_64",
+    "__amd64__",
+    "__amd64",
+    "__linux__",
+    "__linux",
+    "__gnu_linux__",
+    "__unix__",
+    "__unix",
+    "__ELF__",
+    "__LP64__",
+    "_LP64",
+    "__CHAR_BIT__=8",
+    "__SIZEOF_SHORT__=2",
+    "__SIZEOF_INT__=4",
+    "__SIZEOF_LONG__=8",
+    "__SIZEOF_LONG_LONG__=8",
+    "__SIZEOF_FLOAT__=4",
+    "__SIZEOF_DOUBLE__=8",
+    "__SIZEOF_LONG_DOUBLE__=16",
+    "__SIZEOF_POINTER__=8",
+    "__SIZEOF_SIZE_T__=8",
+    "__SIZEOF_PTRDIFF_T__=8",
+    "__SIZEOF_WCHAR_T__=4",
+    "__SIZEOF_WINT_T__=4",
+    "__SCHAR_MAX__=0x7f",
+    "__SHRT_MAX__=0x7fff",
+    "__INT_MAX__=0x7fffffff",
+    "__LONG_MAX__=0x7fffffffffffffffL",
+    "__LONG_LONG_MAX__=0x7fffffffffffffffLL",
+    "__WCHAR_MAX__=0x7fffffff",
+    "__WCHAR_MIN__=(-__WCHAR_MAX__ - 1)",
+    "__WINT_MAX__=0xffffffffU",
+    "__WINT_MIN__=0U",
+    "__PTRDIFF_MAX__=0x7fffffffffffffffL",
+    "__SIZE_MAX__=0xffffffffffffffffUL",
+    "__INTMAX_MAX__=0x7fffffffffffffffL",
+    "__UINTMAX_MAX__=0xffffffffffffffffUL",
+    "__INTPTR_MAX__=0x7fffffffffffffffL",
+    "__UINTPTR_MAX__=0xffffffffffffffffUL",
+    "__SIG_ATOMIC_MAX__=0x7fffffff",
+    "__SIG_ATOMIC_MIN__=(-__SIG_ATOMIC_MAX__ - 1)",
+    "__SIZE_TYPE__=unsigned long",
+    "__PTRDIFF_TYPE__=long",
+    "__WCHAR_TYPE__=int",
+    "__WINT_TYPE__=unsigned int",
+    "__CHAR16_TYPE__=unsigned short",
+    "__CHAR32_TYPE__=unsigned int",
+    "__INTMAX_TYPE__=long",
+    "__UINTMAX_TYPE__=unsigned long",
+    "__INTPTR_TYPE__=long",
+    "__UINTPTR_TYPE__=unsigned long",
+    "__SIG_ATOMIC_TYPE__=int",
+    "__ORDER_LITTLE_ENDIAN__=1234",
+    "__ORDER_BIG_ENDIAN__=4321",
+    "__BYTE_ORDER__=__ORDER_LITTLE_ENDIAN__",
+];
+
+// A string literal spelling `s`.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// `__DATE__` and `__TIME__` of the time of translation in UTC, which
+// SOURCE_DATE_EPOCH fixes for reproducible builds.
+fn date_time() -> (String, String) {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    // The civil date of a day count, counting in 400-year eras that start on March 1st.
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let secs = secs % 86400;
+    (
+        format!("\"{} {:2} {}\"", MONTHS[month as usize - 1], day, year),
+        format!("\"{:02}:{:02}:{:02}\"", secs / 3600, secs / 60 % 60, secs % 60),
+    )
+}
+
+impl Default for PpEnv {
+    fn default() -> PpEnv {
+        PpEnv::new()
+    }
+}
+
+fn error_at(tok: &PpToken, msg: &str) -> ! {
+    error(&format!("{}:{}: {}", tok.file, tok.line, msg))
+}
+
+fn warn_at(tok: &PpToken, msg: &str) {
+    warn(&format!("{}:{}: {}", tok.file, tok.line, msg))
+}
+
+// Keywords are ordinary identifiers to the preprocessor.
+fn ident(tok: &PpToken) -> Option<&str> {
+    match tok.token {
+        Token::Ident { .. } | Token::Keyword { .. } => Some(&tok.text),
+        _ => None,
+    }
+}
+
+fn is_op(tok: &PpToken, op: OperatorKind) -> bool {
+    matches!(&tok.token, Token::Operator { kind } if *kind == op)
+}
+
+fn is_hash(tok: &PpToken) -> bool {
+    tok.bol && is_op(tok, OperatorKind::TkHash)
+}
+
+pub fn preprocess(tokens: Vec<PpToken>, env: &mut PpEnv) -> Vec<PpToken> {
+    let mut input: VecDeque<PpToken> = tokens.into();
+    let mut output = Vec::new();
+    while let Some(tok) = input.pop_front() {
+        if expand_macro(&tok, &mut input, env) {
+            continue;
+        }
+        if is_hash(&tok) {
+            directive(&mut input, env);
+            continue;
+        }
+        if ident(&tok) == Some("_Pragma") {
+            pragma_operator(&tok, &mut input, env);
+            continue;
+        }
+        output.push(tok);
+    }
+    if let Some(cond) = env.conds.last() {
+        error_at(&cond.tok, "unterminated conditional directive");
+    }
+    output
+}
+
+// Adjacent string literals are concatenated once preprocessing is done. If
+// one of them is wide, the narrow ones are read again with its prefix.
+pub fn join_strings(tokens: Vec<PpToken>) -> Vec<PpToken> {
+    let is_str = |t: &PpToken| matches!(t.token, Token::Str { .. });
+    let mut output: Vec<PpToken> = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(mut first) = tokens.next() {
+        if !is_str(&first) {
+            output.push(first);
+            continue;
+        }
+        let mut group = Vec::new();
+        while let Some(tok) = tokens.next_if(is_str) {
+            group.push(tok);
+        }
+        let prefix_of = |t: &PpToken| t.text[..t.text.find('"').unwrap()].to_string();
+        let narrow = |p: &str| p.is_empty() || p == "u8";
+        let prefix = std::iter::once(&first).chain(&group).map(prefix_of).find(|p| !narrow(p)).unwrap_or_default();
+        let mut val = Vec::new();
+        let mut elem = None;
+        for tok in std::iter::once(&first).chain(&group) {
+            let p = prefix_of(tok);
+            let tok = if p == prefix || (narrow(&p) && narrow(&prefix)) {
+                tok.clone()
+            } else if narrow(&p) {
+                retokenize(&format!("{}{}", prefix, &tok.text[p.len()..]), tok).unwrap()
+            } else {
+                error_at(tok, "unsupported concatenation of string literals with different prefixes")
+            };
+            if let Token::Str { val: v, ty } = tok.token {
+                val.extend(v);
+                elem = Some(ty);
+            }
+        }
+        for tok in group {
+            first.text.push(' ');
+            first.text.push_str(&tok.text);
+        }
+        first.token = Token::Str { val, ty: elem.unwrap() };
+        output.push(first);
+    }
+    output
+}
+
+/// The tokens as source text, for `-E`. A line marker `# line "file"` is
+/// written where the file changes or many lines are skipped, so that later
+/// diagnostics can point into the original sources.
+pub fn to_text(tokens: &[PpToken]) -> String {
+    let mut out = String::new();
+    let mut prev: Option<&PpToken> = None;
+    for tok in tokens {
+        match prev {
+            Some(p) if p.file == tok.file && p.line <= tok.line && tok.line - p.line <= 8 => {
+                if tok.line > p.line {
+                    out.extend(std::iter::repeat_n('\n', tok.line - p.line));
+                } else if tok.has_space || needs_space(p, tok) {
+                    out.push(' ');
+                }
+            }
+            _ => {
+                if prev.is_some() {
+                    out.push('\n');
+                }
+                out.push_str(&format!("# {} {}\n", tok.line, quote(&tok.file)));
+            }
+        }
+        out.push_str(&tok.text);
+        prev = Some(tok);
+    }
+    if prev.is_some() {
+        out.push('\n');
+    }
+    out
+}
+
+// Whether two tokens written together would lex differently, as `+` and `+`
+// from separate macros would become `++`.
+fn needs_space(prev: &PpToken, tok: &PpToken) -> bool {
+    let (a, b) = match (prev.text.chars().last(), tok.text.chars().next()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return false,
+    };
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if word(a) && (word(b) || b == '\'' || b == '"') {
+        return true;
+    }
+    if a == '.' && b.is_ascii_digit() {
+        return true;
+    }
+    // A number absorbs a following "." and, after an exponent, a sign.
+    if prev.token == Token::PpNumber && (b == '.' || ((b == '+' || b == '-') && "eEpP".contains(a))) {
+        return true;
+    }
+    let pair: String = [a, b].iter().collect();
+    ["++", "--", "<<", ">>", "&&", "||", "==", "!=", "<=", ">=", "->", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "##", "..", "//", "/*", "<:", ":>", "<%", "%>", "%:"]
+        .contains(&pair.as_str())
+}
+
+// The tokens up to the end of the current line.
+fn read_line(input: &mut VecDeque<PpToken>) -> Vec<PpToken> {
+    let mut line = Vec::new();
+    while input.front().is_some_and(|t| !t.bol) {
+        line.push(input.pop_front().unwrap());
+    }
+    line
+}
+
+fn directive(input: &mut VecDeque<PpToken>, env: &mut PpEnv) {
+    let line = read_line(input);
+    // A "#" alone on a line is the null directive.
+    let name = match line.first() {
+        Some(name) => name,
+        None => return,
+    };
+    match ident(name) {
+        Some("include") | Some("include_next") => include(&line[1..], name, input, env),
+        Some("define") => define(&line[1..], name, env),
+        Some("undef") => {
+            match line.get(1).and_then(ident) {
+                Some(m) => env.macros.remove(m),
+                None => error_at(name, "macro names must be identifiers"),
+            };
+            if let Some(t) = line.get(2) {
+                warn_at(t, "extra tokens at end of #undef directive");
+            }
+        }
+        Some("if") => {
+            let val = eval_cond(&line[1..], name, env);
+            push_cond(name, val, input, env);
+        }
+        Some(kind @ ("ifdef" | "ifndef")) => {
+            let val = is_defined(&line, kind, env) == (kind == "ifdef");
+            push_cond(name, val, input, env);
+        }
+        Some(kind @ ("elif" | "elifdef" | "elifndef")) => {
+            let included = match env.conds.last_mut() {
+                Some(cond) if cond.ctx != CondCtx::Else => {
+                    cond.ctx = CondCtx::Elif;
+                    cond.included
+                }
+                Some(_) => error_at(name, &format!("#{} after #else", kind)),
+                None => error_at(name, &format!("#{} without #if", kind)),
+            };
+            // The condition is not evaluated once a group has been included.
+            let val = !included
+                && match kind {
+                    "elif" => eval_cond(&line[1..], name, env),
+                    _ => is_defined(&line, kind, env) == (kind == "elifdef"),
+                };
+            if val {
+                env.conds.last_mut().unwrap().included = true;
+            } else {
+                skip_cond(input);
+            }
+        }
+        Some("else") => {
+            let included = match env.conds.last_mut() {
+                Some(cond) if cond.ctx != CondCtx::Else => {
+                    cond.ctx = CondCtx::Else;
+                    cond.included
+                }
+                Some(_) => error_at(name, "#else after #else"),
+                None => error_at(name, "#else without #if"),
+            };
+            if let Some(t) = line.get(1) {
+                warn_at(t, "extra tokens at end of #else directive");
+            }
+            if included {
+                skip_cond(input);
+            }
+        }
+        Some("endif") => {
+            if env.conds.pop().is_none() {
+                error_at(name, "#endif without #if");
+            }
+            if let Some(t) = line.get(1) {
+                warn_at(t, "extra tokens at end of #endif directive");
+            }
+        }
+        Some("error") => error_at(name, &format!("#error {}", spell(&line[1..]))),
+        Some("warning") => warn_at(name, &format!("#warning {}", spell(&line[1..]))),
+        Some("pragma") => pragma(&line[1..], name, env),
+        Some("line") => {
+            let expanded = expand_line(line[1..].to_vec(), env);
+            line_directive(&expanded, name, input);
+        }
+        // The `# 12 "file"` markers of `-E` output.
+        None if name.token == Token::PpNumber => line_directive(&line, name, input),
+        _ => error_at(name, &format!("invalid preprocessing directive #{}", name.text)),
+    }
+}
+
+// The tokens as written, with one space wherever there was whitespace.
+fn spell(tokens: &[PpToken]) -> String {
+    let mut s = String::new();
+    for (i, t) in tokens.iter().enumerate() {
+        if i > 0 && t.has_space {
+            s.push(' ');
+        }
+        s.push_str(&t.text);
+    }
+    s
+}
+
+// `#line 12 "file"` renumbers the following lines of the file, starting
+// with 12, and renames it.
+fn line_directive(line: &[PpToken], dir: &PpToken, input: &mut VecDeque<PpToken>) {
+    let num = match line.first() {
+        Some(t) if t.token == Token::PpNumber && t.text.bytes().all(|c| c.is_ascii_digit()) => t.text.parse().unwrap_or(0),
+        _ => 0,
+    };
+    if num == 0 {
+        error_at(dir, "#line directive requires a positive integer argument");
+    }
+    let file: Option<Rc<str>> = match line.get(1) {
+        Some(PpToken { token: Token::Str { val, .. }, .. }) => Some(Rc::from(String::from_utf8_lossy(val).as_ref())),
+        Some(t) if t.token != Token::PpNumber => error_at(t, "invalid filename in #line directive"),
+        _ => None,
+    };
+    let next_line = dir.line + 1;
+    for t in input.iter_mut().take_while(|t| t.file == dir.file) {
+        t.line = t.line - next_line + num;
+        if let Some(file) = &file {
+            t.file = file.clone();
+        }
+    }
+}
+
+fn push_cond(tok: &PpToken, val: bool, input: &mut VecDeque<PpToken>, env: &mut PpEnv) {
+    env.conds.push(Cond { ctx: CondCtx::Then, included: val, tok: tok.clone() });
+    if !val {
+        skip_cond(input);
+    }
+}
+
+// Skips a group whose condition is false, up to the `#elif`, `#else` or
+// `#endif` that ends it, which is left in the input. Nested conditionals are
+// skipped whole.
+fn skip_cond(input: &mut VecDeque<PpToken>) {
+    let mut depth = 0;
+    while let Some(tok) = input.front() {
+        if is_hash(tok) {
+            match input.get(1).filter(|t| !t.bol).and_then(ident) {
+                Some("if" | "ifdef" | "ifndef") => depth += 1,
+                Some("elif" | "elifdef" | "elifndef" | "else") if depth == 0 => return,
+                Some("endif") => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        input.pop_front();
+    }
+}
+
+// Evaluates the expression of an `#if` or `#elif`. `defined` and
+// `__has_include` are replaced before macro expansion; identifiers left after
+// it are 0.
+fn eval_cond(line: &[PpToken], dir: &PpToken, env: &PpEnv) -> bool {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < line.len() {
+        let tok = &line[i];
+        let val = match ident(tok) {
+            Some("defined") => {
+                let paren = line.get(i + 1).is_some_and(|t| is_op(t, OperatorKind::TkPrSt));
+                let name = match line.get(i + 1 + paren as usize).and_then(ident) {
+                    Some(name) => name,
+                    None => error_at(tok, "operator 'defined' requires an identifier"),
+                };
+                i += 2;
+                if paren {
+                    if !line.get(i + 1).is_some_and(|t| is_op(t, OperatorKind::TkPrEd)) {
+                        error_at(tok, "missing ')' after 'defined'");
+                    }
+                    i += 2;
+                }
+                env.macros.contains_key(name) || name == "__has_include"
+            }
+            Some("__has_include") => {
+                if !line.get(i + 1).is_some_and(|t| is_op(t, OperatorKind::TkPrSt)) {
+                    error_at(tok, "missing '(' after '__has_include'");
+                }
+                let (name, quoted, rest) = header_name(&line[i + 2..], tok);
+                if !rest.first().is_some_and(|t| is_op(t, OperatorKind::TkPrEd)) {
+                    error_at(tok, "missing ')' after '__has_include'");
+                }
+                i = line.len() - rest.len() + 1;
+                find_header(&name, quoted, false, &tok.file, env).is_some()
+            }
+            _ => {
+                tokens.push(tok.clone());
+                i += 1;
+                continue;
+            }
+        };
+        tokens.push(retokenize(if val { "1" } else { "0" }, tok).unwrap());
+    }
+    let mut tokens: VecDeque<PpToken> = expand_line(tokens, env).into();
+    if tokens.is_empty() {
+        error_at(dir, &format!("#{} with no expression", dir.text));
+    }
+    let (val, _) = cond_expr(&mut tokens, true);
+    if let Some(t) = tokens.front() {
+        error_at(t, &format!("missing binary operator before token '{}'", t.text));
+    }
+    val != 0
+}
+
+// A value of a preprocessor expression, which is an intmax_t or, if the
+// flag is set, a uintmax_t.
+type PpValue = (i64, bool);
+
+fn consume_op(tokens: &mut VecDeque<PpToken>, op: OperatorKind) -> bool {
+    if tokens.front().is_some_and(|t| is_op(t, op)) {
+        tokens.pop_front();
+        return true;
+    }
+    false
+}
+
+// cond_expr = binary ("?" cond_expr ":" cond_expr)?
+//
+// `live` is false in an operand that is not evaluated, where dividing by
+// zero is no error.
+fn cond_expr(tokens: &mut VecDeque<PpToken>, live: bool) -> PpValue {
+    let cond = binary(tokens, 1, live);
+    if !consume_op(tokens, OperatorKind::TkQuestion) {
+        return cond;
+    }
+    let then = cond_expr(tokens, live && cond.0 != 0);
+    if !consume_op(tokens, OperatorKind::TkColon) {
+        match tokens.front() {
+            Some(t) => error_at(t, "expected ':' in preprocessor expression"),
+            None => error("expected ':' at end of preprocessor expression"),
+        }
+    }
+    let els = cond_expr(tokens, live && cond.0 == 0);
+    let val = if cond.0 != 0 { then.0 } else { els.0 };
+    (val, then.1 || els.1)
+}
+
+fn precedence(op: &OperatorKind) -> Option<usize> {
+    use OperatorKind::*;
+    Some(match op {
+        TkLogOr => 1,
+        TkLogAnd => 2,
+        TkOr => 3,
+        TkXor => 4,
+        TkAnd => 5,
+        TkEq | TkNEq => 6,
+        TkLt | TkGt | TkLe | TkGe => 7,
+        TkShl | TkShr => 8,
+        TkAdd | TkSub => 9,
+        TkMul | TkDiv | TkMod => 10,
+        _ => return None,
+    })
+}
+
+// The binary operator at `tok` if it binds at least as tightly as `min`.
+fn binary_op(tok: Option<&PpToken>, min: usize) -> Option<(OperatorKind, usize)> {
+    match tok.map(|t| &t.token) {
+        Some(Token::Operator { kind }) => precedence(kind).filter(|&prec| prec >= min).map(|prec| (kind.clone(), prec)),
+        _ => None,
+    }
+}
+
+// The binary operators, by precedence climbing from `min`.
+fn binary(tokens: &mut VecDeque<PpToken>, min: usize, live: bool) -> PpValue {
+    let mut lhs = unary_expr(tokens, live);
+    while let Some((op, prec)) = binary_op(tokens.front(), min) {
+        let tok = tokens.pop_front().unwrap();
+        let rhs_live = live
+            && match op {
+                OperatorKind::TkLogAnd => lhs.0 != 0,
+                OperatorKind::TkLogOr => lhs.0 == 0,
+                _ => true,
+            };
+        let rhs = binary(tokens, prec + 1, rhs_live);
+        lhs = apply_binary(&op, lhs, rhs, live, &tok);
+    }
+    lhs
+}
+
+// Arithmetic is done in intmax_t, or in uintmax_t if either operand is
+// unsigned, and wraps.
+fn apply_binary(op: &OperatorKind, (l, lu): PpValue, (r, ru): PpValue, live: bool, tok: &PpToken) -> PpValue {
+    use OperatorKind::*;
+    let u = lu || ru;
+    let (ul, ur) = (l as u64, r as u64);
+    let val = match op {
+        TkLogOr => return ((l != 0 || r != 0) as i64, false),
+        TkLogAnd => return ((l != 0 && r != 0) as i64, false),
+        TkEq => return ((l == r) as i64, false),
+        TkNEq => return ((l != r) as i64, false),
+        TkLt => return ((if u { ul < ur } else { l < r }) as i64, false),
+        TkGt => return ((if u { ul > ur } else { l > r }) as i64, false),
+        TkLe => return ((if u { ul <= ur } else { l <= r }) as i64, false),
+        TkGe => return ((if u { ul >= ur } else { l >= r }) as i64, false),
+        // A shift has the type of its left operand.
+        TkShl => return (if (0..64).contains(&r) { l << r } else { 0 }, lu),
+        TkShr => {
+            let val = match (lu, (0..64).contains(&r)) {
+                (true, true) => (ul >> r) as i64,
+                (false, true) => l >> r,
+                (true, false) => 0,
+                (false, false) => if l < 0 { -1 } else { 0 },
+            };
+            return (val, lu);
+        }
+        TkOr => l | r,
+        TkXor => l ^ r,
+        TkAnd => l & r,
+        TkAdd => l.wrapping_add(r),
+        TkSub => l.wrapping_sub(r),
+        TkMul => l.wrapping_mul(r),
+        TkDiv | TkMod => {
+            if r == 0 {
+                if live {
+                    error_at(tok, "division by zero in #if");
+                }
+                return (0, u);
+            }
+            match (op, u) {
+                (TkDiv, true) => (ul / ur) as i64,
+                (TkDiv, false) => l.wrapping_div(r),
+                (_, true) => (ul % ur) as i64,
+                (_, false) => l.wrapping_rem(r),
+            }
+        }
+        _ => unreachable!(),
+    };
+    (val, u)
+}
+
+// unary_expr = ("+" | "-" | "~" | "!") unary_expr | "(" cond_expr ")" | number | ident
+fn unary_expr(tokens: &mut VecDeque<PpToken>, live: bool) -> PpValue {
+    let tok = match tokens.pop_front() {
+        Some(tok) => tok,
+        None => error("expected a value at end of preprocessor expression"),
+    };
+    match &tok.token {
+        Token::Operator { kind: OperatorKind::TkAdd } => unary_expr(tokens, live),
+        Token::Operator { kind: OperatorKind::TkSub } => {
+            let (val, u) = unary_expr(tokens, live);
+            (val.wrapping_neg(), u)
+        }
+        Token::Operator { kind: OperatorKind::TkTilde } => {
+            let (val, u) = unary_expr(tokens, live);
+            (!val, u)
+        }
+        Token::Operator { kind: OperatorKind::TkNot } => ((unary_expr(tokens, live).0 == 0) as i64, false),
+        Token::Operator { kind: OperatorKind::TkPrSt } => {
+            let val = cond_expr(tokens, live);
+            if !consume_op(tokens, OperatorKind::TkPrEd) {
+                error_at(&tok, "missing ')' in preprocessor expression");
+            }
+            val
+        }
+        // A constant is unsigned if it has a `u` suffix or does not fit in
+        // intmax_t, and a character constant if it is a char32_t.
+        Token::PpNumber | Token::PpChar => match convert_token(&tok) {
+            Token::Number { val, ty } if tok.token == Token::PpChar => (val, ty.is_unsigned && ty.size() == 4),
+            Token::Number { val, .. } => (val, val < 0 || tok.text.contains(['u', 'U'])),
+            _ => error_at(&tok, "floating constant in preprocessor expression"),
+        },
+        Token::Ident { .. } | Token::Keyword { .. } => (0, false),
+        _ => error_at(&tok, &format!("token '{}' is not valid in preprocessor expressions", tok.text)),
+    }
+}
+
+// Whether the macro named by an `#ifdef`-like directive is defined.
+fn is_defined(line: &[PpToken], kind: &str, env: &PpEnv) -> bool {
+    let name = match line.get(1).and_then(ident) {
+        Some(name) => name,
+        None => error_at(&line[0], &format!("no macro name given in #{} directive", kind)),
+    };
+    if let Some(t) = line.get(2) {
+        warn_at(t, &format!("extra tokens at end of #{} directive", kind));
+    }
+    env.macros.contains_key(name)
+}
+
+// `#pragma once` keeps the header from being included again; other pragmas
+// are ignored.
+fn pragma(line: &[PpToken], dir: &PpToken, env: &mut PpEnv) {
+    if line.first().and_then(ident) == Some("once") {
+        env.once.insert(dir.file.clone());
+    }
+}
+
+// `_Pragma("...")` is the directive `#pragma ...`.
+fn pragma_operator(tok: &PpToken, input: &mut VecDeque<PpToken>, env: &mut PpEnv) {
+    let mut args = Vec::new();
+    for _ in 0..3 {
+        match input.pop_front() {
+            Some(t) => args.push(t),
+            None => error_at(tok, "_Pragma takes a parenthesized string literal"),
+        }
+    }
+    let text = match &args[..] {
+        [l, PpToken { token: Token::Str { .. }, text, .. }, r] if is_op(l, OperatorKind::TkPrSt) && is_op(r, OperatorKind::TkPrEd) => text,
+        _ => error_at(tok, "_Pragma takes a parenthesized string literal"),
+    };
+    let text = text[1..text.len() - 1].replace("\\\"", "\"").replace("\\\\", "\\");
+    pragma(&tokenize_source(&text, &tok.file), tok, env);
+}
+
+fn define(line: &[PpToken], dir: &PpToken, env: &mut PpEnv) {
+    let name = match line.first().and_then(ident) {
+        Some("defined") => error_at(dir, "'defined' cannot be used as a macro name"),
+        Some(name) => name.to_string(),
+        None => error_at(dir, "macro names must be identifiers"),
+    };
+    // A function-like macro has a "(" directly after its name.
+    let rest = &line[1..];
+    let mac = if rest.first().is_some_and(|t| is_op(t, OperatorKind::TkPrSt) && !t.has_space) {
+        let (params, variadic, len) = macro_params(&rest[1..], &line[0]);
+        Macro { params: Some(params), variadic, body: rest[len + 1..].to_vec(), builtin: None }
+    } else {
+        Macro { params: None, variadic: false, body: rest.to_vec(), builtin: None }
+    };
+    check_body(&mac);
+    // glibc's <sys/cdefs.h> defines `__attribute__` away for compilers other
+    // than GCC and clang, which would drop the `packed` and `aligned` that
+    // change the layout of structs.
+    if name == "__attribute__" && env.system_paths.iter().any(|d| Path::new(&*dir.file).starts_with(d)) {
+        return;
+    }
+    if let Some(old) = env.macros.get(&name) {
+        if !same_macro(old, &mac) {
+            warn_at(&line[0], &format!("'{}' redefined", name));
+        }
+    }
+    env.macros.insert(name, mac);
+}
+
+// Parses the parameter list after the "(", returning the names, whether the
+// macro is variadic and the number of tokens up to the ")".
+fn macro_params(tokens: &[PpToken], name: &PpToken) -> (Vec<String>, bool, usize) {
+    let mut params: Vec<String> = Vec::new();
+    let mut i = 0;
+    if tokens.first().is_some_and(|t| is_op(t, OperatorKind::TkPrEd)) {
+        return (params, false, 1);
+    }
+    loop {
+        let tok = match tokens.get(i) {
+            Some(tok) => tok,
+            None => error_at(name, "missing ')' in macro parameter list"),
+        };
+        if is_op(tok, OperatorKind::TkEllipsis) {
+            if !tokens.get(i + 1).is_some_and(|t| is_op(t, OperatorKind::TkPrEd)) {
+                error_at(tok, "expected ')' after '...'");
+            }
+            return (params, true, i + 2);
+        }
+        match ident(tok) {
+            Some(p) if params.iter().any(|q| q == p) => error_at(tok, &format!("duplicate macro parameter '{}'", p)),
+            Some(p) => params.push(p.to_string()),
+            None => error_at(tok, "expected a parameter name"),
+        }
+        match tokens.get(i + 1) {
+            Some(t) if is_op(t, OperatorKind::TkComma) => i += 2,
+            Some(t) if is_op(t, OperatorKind::TkPrEd) => return (params, false, i + 2),
+            _ => error_at(tok, "expected ',' or ')' in macro parameter list"),
+        }
+    }
+}
+
+fn check_body(mac: &Macro) {
+    for (i, t) in mac.body.iter().enumerate() {
+        if is_op(t, OperatorKind::TkHashHash) && (i == 0 || i == mac.body.len() - 1) {
+            error_at(t, "'##' cannot appear at either end of a macro expansion");
+        }
+        if mac.params.is_some() && is_op(t, OperatorKind::TkHash) {
+            let next = mac.body.get(i + 1);
+            if !next.is_some_and(|n| param_index(n, mac).is_some() || is_va_opt(n, mac)) {
+                error_at(t, "'#' is not followed by a macro parameter");
+            }
+        }
+    }
+}
+
+// A macro may be redefined only with the same parameters and an identical
+// replacement list, where all whitespace separations count as the same.
+fn same_macro(a: &Macro, b: &Macro) -> bool {
+    a.params == b.params
+        && a.variadic == b.variadic
+        && a.body.len() == b.body.len()
+        && a.body.iter().zip(&b.body).enumerate().all(|(i, (x, y))| x.text == y.text && (i == 0 || x.has_space == y.has_space))
+}
+
+// If `tok` names a macro, pushes its expansion back onto the input. This is
+// Prosser's algorithm: each token carries the set of macros it came from,
+// and a macro name in its own set is not expanded. The expansion of a
+// function-like macro inherits only the macros hiding both its name and the
+// ")" of its arguments.
+fn expand_macro(tok: &PpToken, input: &mut VecDeque<PpToken>, env: &PpEnv) -> bool {
+    let name = match ident(tok) {
+        Some(name) => name,
+        None => return false,
+    };
+    if tok.hideset.iter().any(|h| &**h == name) {
+        return false;
+    }
+    let mac = match env.macros.get(name) {
+        Some(mac) => mac,
+        None => return false,
+    };
+    if let Some(builtin) = mac.builtin {
+        let expansion = retokenize(&builtin(tok, env), tok).unwrap();
+        push_expansion(tok, vec![expansion], input);
+        return true;
+    }
+    let (body, mut hideset) = match mac.params {
+        None => (subst(&mac.body, mac, &[], env), tok.hideset.clone()),
+        Some(_) => {
+            // Without arguments, the name of a function-like macro is an ordinary identifier.
+            if !input.front().is_some_and(|t| is_op(t, OperatorKind::TkPrSt)) {
+                return false;
+            }
+            let (args, rparen) = read_args(tok, mac, input);
+            let hideset = tok.hideset.iter().filter(|h| rparen.hideset.contains(h)).cloned().collect();
+            (subst(&mac.body, mac, &args, env), hideset)
+        }
+    };
+    hideset.push(Rc::from(name));
+    let body = body
+        .into_iter()
+        .map(|mut t| {
+            for h in &hideset {
+                if !t.hideset.contains(h) {
+                    t.hideset.push(h.clone());
+                }
+            }
+            t
+        })
+        .collect();
+    push_expansion(tok, body, input);
+    true
+}
+
+// Reads the arguments of a function-like macro invocation, with the input at
+// the "(". Returns them with the closing ")".
+fn read_args(tok: &PpToken, mac: &Macro, input: &mut VecDeque<PpToken>) -> (Vec<Vec<PpToken>>, PpToken) {
+    let nparams = mac.params.as_ref().map_or(0, |p| p.len());
+    input.pop_front();
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    let rparen = loop {
+        let t = match input.pop_front() {
+            Some(t) => t,
+            None => error_at(tok, &format!("unterminated argument list invoking macro '{}'", tok.text)),
+        };
+        if is_op(&t, OperatorKind::TkPrEd) {
+            if depth == 0 {
+                break t;
+            }
+            depth -= 1;
+        } else if is_op(&t, OperatorKind::TkPrSt) {
+            depth += 1;
+        } else if depth == 0 && is_op(&t, OperatorKind::TkComma) && (!mac.variadic || args.len() <= nparams) {
+            // The variable arguments take the remaining commas.
+            args.push(Vec::new());
+            continue;
+        }
+        args.last_mut().unwrap().push(t);
+    };
+    // `F()` passes one empty argument, which a macro without parameters does
+    // not take, and the variable arguments may be left out entirely.
+    if nparams == 0 && !mac.variadic && args.len() == 1 && args[0].is_empty() {
+        args.clear();
+    }
+    if mac.variadic && args.len() == nparams {
+        args.push(Vec::new());
+    }
+    let expected = nparams + mac.variadic as usize;
+    if args.len() > expected {
+        error_at(tok, &format!("macro '{}' passed {} arguments, but takes just {}", tok.text, args.len(), expected));
+    }
+    if args.len() < expected {
+        error_at(tok, &format!("macro '{}' requires {} arguments, but only {} given", tok.text, expected, args.len()));
+    }
+    (args, rparen)
+}
+
+// The index of the argument a body token names, if it is a parameter.
+fn param_index(tok: &PpToken, mac: &Macro) -> Option<usize> {
+    let params = mac.params.as_ref()?;
+    let name = ident(tok)?;
+    if mac.variadic && name == "__VA_ARGS__" {
+        return Some(params.len());
+    }
+    params.iter().position(|p| p == name)
+}
+
+fn is_va_opt(tok: &PpToken, mac: &Macro) -> bool {
+    mac.variadic && ident(tok) == Some("__VA_OPT__")
+}
+
+// Replaces the parameters in a macro body with the arguments and applies the
+// `#` and `##` operators. An empty operand of `##` acts as the placemarker of
+// the standard: pasting it to another operand leaves that operand.
+fn subst(body: &[PpToken], mac: &Macro, args: &[Vec<PpToken>], env: &PpEnv) -> Vec<PpToken> {
+    let mut output: Vec<PpToken> = Vec::new();
+    let mut placemarker = false;
+    let mut i = 0;
+    while i < body.len() {
+        if is_op(&body[i], OperatorKind::TkHashHash) {
+            let (rhs, len) = operand(&body[i + 1..], mac, args, env, true);
+            i += len + 1;
+            if placemarker {
+                placemarker = rhs.is_empty();
+                output.extend(rhs);
+            } else if let Some((first, rest)) = rhs.split_first() {
+                let lhs = output.pop().unwrap();
+                output.push(paste(&lhs, first));
+                output.extend_from_slice(rest);
+            }
+            continue;
+        }
+        let (tokens, len) = operand(&body[i..], mac, args, env, false);
+        i += len;
+        placemarker = tokens.is_empty();
+        output.extend(tokens);
+    }
+    output
+}
+
+// Substitutes the operand at the start of `body`, returning its tokens and
+// the number of body tokens it took. An argument next to `##` is not
+// macro-expanded.
+fn operand(body: &[PpToken], mac: &Macro, args: &[Vec<PpToken>], env: &PpEnv, after_paste: bool) -> (Vec<PpToken>, usize) {
+    let tok = &body[0];
+    if mac.params.is_some() && is_op(tok, OperatorKind::TkHash) {
+        if let Some(i) = param_index(&body[1], mac) {
+            return (vec![stringize(tok, &args[i])], 2);
+        }
+        let (tokens, len) = va_opt(&body[1..], mac, args, env);
+        return (vec![stringize(tok, &tokens)], len + 1);
+    }
+    if is_va_opt(tok, mac) {
+        return va_opt(body, mac, args, env);
+    }
+    if let Some(i) = param_index(tok, mac) {
+        let before_paste = body.get(1).is_some_and(|t| is_op(t, OperatorKind::TkHashHash));
+        if after_paste || before_paste {
+            return (args[i].clone(), 1);
+        }
+        return (expand_line(args[i].clone(), env), 1);
+    }
+    (vec![tok.clone()], 1)
+}
+
+// `__VA_OPT__(tokens)` stands for the tokens if the variable arguments
+// expand to anything and for nothing otherwise.
+fn va_opt(body: &[PpToken], mac: &Macro, args: &[Vec<PpToken>], env: &PpEnv) -> (Vec<PpToken>, usize) {
+    let close = va_opt_end(body);
+    let va_args = args.last().unwrap();
+    if expand_line(va_args.clone(), env).is_empty() {
+        return (Vec::new(), close + 1);
+    }
+    (subst(&body[2..close], mac, args, env), close + 1)
+}
+
+// The index of the ")" closing the `__VA_OPT__(` at the start of `body`.
+fn va_opt_end(body: &[PpToken]) -> usize {
+    if !body.get(1).is_some_and(|t| is_op(t, OperatorKind::TkPrSt)) {
+        error_at(&body[0], "'__VA_OPT__' must be followed by '('");
+    }
+    let mut depth = 0;
+    for (i, t) in body.iter().enumerate().skip(1) {
+        if is_op(t, OperatorKind::TkPrSt) {
+            depth += 1;
+        } else if is_op(t, OperatorKind::TkPrEd) {
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+    error_at(&body[0], "unterminated '__VA_OPT__'")
+}
+
+// `#` spells its operand as a string literal, with one space wherever there
+// was whitespace and `"` and `\` escaped inside literals.
+fn stringize(hash: &PpToken, tokens: &[PpToken]) -> PpToken {
+    let mut s = String::from("\"");
+    for (i, t) in tokens.iter().enumerate() {
+        if i > 0 && t.has_space {
+            s.push(' ');
+        }
+        if matches!(t.token, Token::Str { .. } | Token::PpChar) {
+            for c in t.text.chars() {
+                if c == '"' || c == '\\' {
+                    s.push('\\');
+                }
+                s.push(c);
+            }
+        } else {
+            s.push_str(&t.text);
+        }
+    }
+    s.push('"');
+    match retokenize(&s, hash) {
+        Some(t) => t,
+        None => error_at(hash, &format!("invalid string literal {}", s)),
+    }
+}
+
+// `##` joins the spellings of its operands, which must form one token.
+fn paste(lhs: &PpToken, rhs: &PpToken) -> PpToken {
+    match retokenize(&format!("{}{}", lhs.text, rhs.text), lhs) {
+        Some(t) => t,
+        None => error_at(lhs, &format!("pasting \"{}\" and \"{}\" does not give a valid preprocessing token", lhs.text, rhs.text)),
+    }
+}
+
+// Lexes the spelling of a token made during expansion, placing it at `at`.
+fn retokenize(text: &str, at: &PpToken) -> Option<PpToken> {
+    let mut tokens = tokenize_source(text, &at.file);
+    if tokens.len() != 1 {
+        return None;
+    }
+    let mut tok = tokens.pop().unwrap();
+    tok.line = at.line;
+    tok.bol = false;
+    tok.has_space = at.has_space;
+    tok.hideset = at.hideset.clone();
+    Some(tok)
+}
+
+// The expansion takes the place of the macro invocation `tok`. Expanded
+// tokens never start a directive.
+fn push_expansion(tok: &PpToken, mut body: Vec<PpToken>, input: &mut VecDeque<PpToken>) {
+    for t in body.iter_mut() {
+        t.file = tok.file.clone();
+        t.line = tok.line;
+        t.bol = false;
+    }
+    match body.first_mut() {
+        Some(first) => first.has_space = tok.has_space,
+        None => {
+            if let Some(next) = input.front_mut() {
+                next.has_space |= tok.has_space;
+            }
+        }
+    }
+    for t in body.into_iter().rev() {
+        input.push_front(t);
+    }
+}
+
+// Expands the macros in the tokens of a directive.
+fn expand_line(tokens: Vec<PpToken>, env: &PpEnv) -> Vec<PpToken> {
+    let mut input: VecDeque<PpToken> = tokens.into();
+    let mut output = Vec::new();
+    while let Some(tok) = input.pop_front() {
+        if !expand_macro(&tok, &mut input, env) {
+            output.push(tok);
+        }
+    }
+    output
+}
+
+fn include(line: &[PpToken], dir: &PpToken, input: &mut VecDeque<PpToken>, env: &mut PpEnv) {
+    // A header name may also come from a macro.
+    let expanded;
+    let line = match line.first() {
+        Some(PpToken { token: Token::Str { .. }, .. }) => line,
+        Some(t) if is_op(t, OperatorKind::TkLt) => line,
+        _ => {
+            expanded = expand_line(line.to_vec(), env);
+            &expanded[..]
+        }
+    };
+    let (name, quoted, rest) = header_name(line, dir);
+    if let Some(t) = rest.first() {
+        warn_at(t, "extra tokens at end of #include directive");
+    }
+    let next = dir.text == "include_next";
+    let path = match find_header(&name, quoted, next, &dir.file, env) {
+        Some(path) => path,
+        None => error_at(dir, &format!("'{}' file not found", name)),
+    };
+    if env.once.contains(&path) {
+        return;
+    }
+    // A guarded header whose guard is defined need not be read again.
+    if env.guards.get(&path).is_some_and(|guard| env.macros.contains_key(guard)) {
+        return;
+    }
+    let src = match read_header(&path) {
+        Some(src) => src,
+        None => error_at(dir, &format!("cannot read '{}'", path)),
+    };
+    let src = if env.trigraphs { replace_trigraphs(&src) } else { src };
+    let tokens = tokenize_source(&src, &path);
+    if let Some(guard) = include_guard(&tokens) {
+        env.guards.insert(path, guard);
+    }
+    for t in tokens.into_iter().rev() {
+        input.push_front(t);
+    }
+}
+
+// Reads `"name"` or `<name>`, returning the name, whether it was quoted and
+// the tokens after it.
+fn header_name<'a>(line: &'a [PpToken], dir: &PpToken) -> (String, bool, &'a [PpToken]) {
+    match line.first() {
+        // Escape sequences have no meaning in a header name.
+        Some(PpToken { token: Token::Str { .. }, text, .. }) => (text[1..text.len() - 1].to_string(), true, &line[1..]),
+        Some(t) if is_op(t, OperatorKind::TkLt) => {
+            let end = match line.iter().position(|t| is_op(t, OperatorKind::TkGt)) {
+                Some(end) => end,
+                None => error_at(dir, "missing terminating '>' character"),
+            };
+            let mut name = String::new();
+            for (i, t) in line[1..end].iter().enumerate() {
+                if i > 0 && t.has_space {
+                    name.push(' ');
+                }
+                name.push_str(&t.text);
+            }
+            (name, false, &line[end + 1..])
+        }
+        _ => error_at(dir, "#include expects \"FILENAME\" or <FILENAME>"),
+    }
+}
+
+// Searches for a header: for `#include "..."` first next to the including
+// file, then in the `-I` directories, the bundled headers and the system
+// directories. Bundled headers are named `<built-in>/name`. `#include_next`
+// skips the directories up to the one the including file was found in under
+// the same name, so that a header can add to the one it replaces.
+fn find_header(name: &str, quoted: bool, next: bool, from: &str, env: &PpEnv) -> Option<Rc<str>> {
+    let found = |path: PathBuf| if path.is_file() { Some(Rc::from(path.to_string_lossy().as_ref())) } else { None };
+    if name.starts_with('/') {
+        return found(PathBuf::from(name));
+    }
+    if quoted && !next {
+        let dir = Path::new(from).parent().unwrap_or_else(|| Path::new(""));
+        if let Some(path) = found(dir.join(name)) {
+            return Some(path);
+        }
+    }
+    // The search path, with `None` standing for the bundled headers.
+    let dirs: Vec<Option<&Path>> = env.include_paths.iter().map(|dir| Some(dir.as_path()))
+        .chain(Some(None))
+        .chain(env.system_paths.iter().map(|dir| Some(dir.as_path())))
+        .collect();
+    let path = |dir: &Option<&Path>| match dir {
+        Some(dir) => dir.join(name).to_string_lossy().into_owned(),
+        None => format!("<built-in>/{}", name),
+    };
+    let start = if next { dirs.iter().position(|dir| path(dir) == from).map_or(0, |i| i + 1) } else { 0 };
+    dirs[start..].iter().find_map(|dir| match dir {
+        Some(dir) => found(dir.join(name)),
+        None => headers::find(name).map(|_| Rc::from(path(&None))),
+    })
+}
+
+fn read_header(path: &str) -> Option<String> {
+    if let Some(name) = path.strip_prefix("<built-in>/") {
+        return headers::find(name).map(str::to_string);
+    }
+    fs::read(path).ok().map(|src| String::from_utf8_lossy(&src).into_owned())
+}
+
+fn is_directive(tokens: &[PpToken], i: usize, name: &str) -> bool {
+    tokens.get(i).is_some_and(is_hash) && tokens.get(i + 1).filter(|t| !t.bol).and_then(ident) == Some(name)
+}
+
+// Recognizes a header wrapped in `#ifndef X`, `#define X` ... `#endif`,
+// returning the guard `X`.
+fn include_guard(tokens: &[PpToken]) -> Option<String> {
+    if !is_directive(tokens, 0, "ifndef") || !is_directive(tokens, 3, "define") {
+        return None;
+    }
+    let guard = tokens.get(2).and_then(ident)?;
+    if tokens.get(5).and_then(ident) != Some(guard) {
+        return None;
+    }
+    let mut depth = 0;
+    for i in 0..tokens.len() {
+        if is_directive(tokens, i, "if") || is_directive(tokens, i, "ifdef") || is_directive(tokens, i, "ifndef") {
+            depth += 1;
+        } else if is_directive(tokens, i, "endif") {
+            depth -= 1;
+            if depth == 0 {
+                // The `#endif` must end the file.
+                if tokens[i + 2..].iter().any(|t| t.bol) {
+                    return None;
+                }
+                return Some(guard.to_string());
+            }
+        }
+    }
+    None
+}
@@ -1,12 +1,31 @@
+use std::process;
+use std::rc::Rc;
+
+use crate::types::Type;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum OperatorKind {
     TkAdd,
     TkSub,
     TkMul,
     TkDiv,
+    TkMod,
+    TkAnd,
+    TkOr,
+    TkXor,
+    TkTilde,
+    TkNot,
+    TkLogAnd,
+    TkLogOr,
+    TkQuestion,
+    TkShl,
+    TkShr,
     TkPrSt,
     TkPrEd,
     TkBrSt,
     TkBrEd,
+    TkSqSt,
+    TkSqEd,
     TkEq,
     TkNEq,
     TkLt,
@@ -14,214 +33,929 @@ pub enum OperatorKind {
     TkLe,
     TkGe,
     TkAssign,
+    TkAddAssign,
+    TkSubAssign,
+    TkMulAssign,
+    TkDivAssign,
+    TkModAssign,
+    TkAndAssign,
+    TkOrAssign,
+    TkXorAssign,
+    TkShlAssign,
+    TkShrAssign,
+    TkInc,
+    TkDec,
+    TkComma,
+    TkDot,
+    TkArrow,
+    TkColon,
+    TkEllipsis,
     TkExprEnd,
+    TkHash,
+    TkHashHash,
+    // A character that is no other token, which only the preprocessor accepts.
+    TkOther,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum KeywordKind {
     TkReturn,
     TkIf,
     TkElse,
     TkWhile,
     TkFor,
+    TkVoid,
+    TkChar,
+    TkShort,
+    TkInt,
+    TkLong,
+    TkSizeof,
+    TkStatic,
+    TkStruct,
+    TkUnion,
+    TkEnum,
+    TkSwitch,
+    TkCase,
+    TkDefault,
+    TkBreak,
+    TkTypedef,
+    TkExtern,
+    TkConst,
+    TkVolatile,
+    TkRestrict,
+    TkUnsigned,
+    TkSigned,
+    TkFloat,
+    TkDouble,
+    TkBool,
+    TkStaticAssert,
+    TkAlignas,
+    TkAlignof,
+    TkNoreturn,
+    TkInline,
+    TkAttribute,
+    TkExtension,
+    TkAsm,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Operator {
         kind: OperatorKind,
     },
     Number {
-        val: i32,
+        val: i64,
+        ty: Type,
+    },
+    Float {
+        val: f64,
+        // The value in the x87 extended format, which long double
+        // constants keep to their full precision.
+        x87: [u8; 10],
+        ty: Type,
     },
+    // A string literal: the bytes of its elements, which have the type
+    // `ty`, without the terminating null.
+    Str {
+        val: Vec<u8>,
+        ty: Type,
+    },
+    // A preprocessing number like `1.2.3` or `0x1e+1` and a character
+    // constant, whose spellings are read as constants only after
+    // preprocessing, by `convert_tokens`.
+    PpNumber,
+    PpChar,
     Ident {
         name: String
     },
-    Return,
-    If,
-    Else,
-    While,
-    For,
+    Keyword {
+        kind: KeywordKind,
+    },
 }
 
+pub fn error(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    process::exit(1);
+}
+
+pub fn warn(msg: &str) {
+    eprintln!("warning: {}", msg);
+}
+
+// A preprocessing token: a token with where it came from and how it was
+// written, which the preprocessor needs for directives, stringification and
+// diagnostics.
+#[derive(Clone, Debug)]
+pub struct PpToken {
+    pub token: Token,
+    // The spelling in the source.
+    pub text: String,
+    pub file: Rc<str>,
+    pub line: usize,
+    // Whether the token is the first on its line.
+    pub bol: bool,
+    // Whether whitespace or a comment precedes the token.
+    pub has_space: bool,
+    // The macros whose expansion produced the token, which must not expand it again.
+    pub hideset: Vec<Rc<str>>,
+}
 
 pub fn tokenize(input: String) -> Vec<Token> {
-    let mut tokens: Vec<Token> = Vec::new();
-    let mut input = input;
-    loop {
-        if input.is_empty() {
-            break;
-        }
-        tokenize_whitespace(&mut input);
-        if let Some(kw) = tokenize_keyword(&mut input) {
-            match kw {
-                KeywordKind::TkReturn => tokens.push(Token::Return),
-                KeywordKind::TkIf => tokens.push(Token::If),
-                KeywordKind::TkElse => tokens.push(Token::Else),
-                _ => {}
+    convert_tokens(tokenize_source(&input, &Rc::from("<input>")))
+}
+
+/// Turns preprocessing tokens into the tokens of the parser, reading the
+/// values of numbers and character constants. Until then any spelling that
+/// looks like a number is a token, so that `1.2.3` or an unmatched quote in
+/// a skipped group or an unused macro is no error.
+pub fn convert_tokens(tokens: Vec<PpToken>) -> Vec<Token> {
+    tokens.iter().map(convert_token).collect()
+}
+
+pub fn convert_token(tok: &PpToken) -> Token {
+    let error_at = |msg: &str| -> ! { error(&format!("{}:{}: {}", tok.file, tok.line, msg)) };
+    let x = tok.text.as_bytes();
+    let mut pos = 0;
+    let token = match tok.token {
+        Token::PpNumber => {
+            if let Some((val, x87, ty)) = tokenize_float(x, &mut pos) {
+                Token::Float { val, x87, ty }
+            } else if let Some((val, ty)) = tokenize_number(x, &mut pos) {
+                Token::Number { val, ty }
+            } else {
+                error_at(&format!("invalid number: {}", tok.text))
             }
-            continue;
         }
-        if let Some(i) = tokenize_number(&mut input) {
-            tokens.push(Token::Number { val: i });
-            continue;
+        Token::PpChar => match char_value(x) {
+            Ok((val, ty)) => return Token::Number { val, ty },
+            Err(msg) => error_at(msg),
+        },
+        Token::Operator { kind: OperatorKind::TkOther } if tok.text == "'" || tok.text == "\"" => {
+            error_at(&format!("missing terminating {} character", tok.text))
         }
-        if let Some(op) = tokenize_operator(&mut input) {
-            tokens.push(Token::Operator { kind: op });
-            continue;
+        Token::Operator { kind: OperatorKind::TkOther } => error_at(&format!("stray '{}' in program", tok.text)),
+        _ => return tok.token.clone(),
+    };
+    if pos != x.len() {
+        error_at(&format!("invalid suffix \"{}\" on constant {}", &tok.text[pos..], tok.text));
+    }
+    token
+}
+
+// Tokens are numbered by physical line, counting the lines joined by
+// splices as well as the newlines left.
+pub fn tokenize_source(input: &str, file: &Rc<str>) -> Vec<PpToken> {
+    let mut tokens = Vec::new();
+    let (x, splices) = splice_lines(input, file);
+    let x = &x[..];
+    let mut pos = 0;
+    let mut line = 1;
+    let mut spliced = 0;
+    let mut bol = true;
+    loop {
+        let start = pos;
+        bol |= tokenize_whitespace(x, &mut pos);
+        line += x[start..pos].iter().filter(|&&c| c == b'\n').count();
+        if pos >= x.len() {
+            break;
         }
-        if let Some(name) = tokenize_variant(&mut input) {
-            tokens.push(Token::Ident { name });
-            continue;
+        while spliced < splices.len() && splices[spliced] <= pos {
+            spliced += 1;
+            line += 1;
         }
+        let has_space = pos != start;
+        let start = pos;
+        let token = tokenize_token(x, &mut pos);
+        tokens.push(PpToken {
+            token,
+            text: String::from_utf8_lossy(&x[start..pos]).into_owned(),
+            file: file.clone(),
+            line,
+            bol,
+            has_space,
+            hideset: Vec::new(),
+        });
+        bol = false;
     }
     tokens
 }
 
-fn tokenize_whitespace(x: &mut String) {
-    loop {
-        match x.chars().next() {
-            Some(c) if c.is_whitespace() => {
-                x.remove(0);
+// Translation phases 1 and 2: CRLF line ends become LF, and a backslash at
+// the end of a line joins it to the next. Returns the text with the offsets
+// where lines were joined.
+fn splice_lines(input: &str, file: &str) -> (Vec<u8>, Vec<usize>) {
+    let x = input.as_bytes();
+    let mut out = Vec::with_capacity(x.len());
+    let mut splices = Vec::new();
+    let mut i = 0;
+    while i < x.len() {
+        if x[i] == b'\r' && x.get(i + 1) == Some(&b'\n') {
+            i += 1;
+            continue;
+        }
+        if x[i] == b'\\' {
+            // Like GCC, allow blanks between the backslash and the newline.
+            let mut end = i + 1;
+            while end < x.len() && matches!(x[end], b' ' | b'\t') {
+                end += 1;
             }
-            _ => {
-                break;
+            if x.get(end) == Some(&b'\r') && x.get(end + 1) == Some(&b'\n') {
+                end += 1;
+            }
+            if x.get(end) == Some(&b'\n') {
+                if x[i + 1] != b'\n' && x[i + 1] != b'\r' {
+                    let line = 1 + out.iter().filter(|&&c| c == b'\n').count() + splices.len();
+                    warn(&format!("{}:{}: backslash and newline separated by space", file, line));
+                }
+                splices.push(out.len());
+                i = end + 1;
+                continue;
             }
         }
+        out.push(x[i]);
+        i += 1;
     }
+    (out, splices)
 }
 
-fn tokenize_keyword(x: &mut String) -> Option<KeywordKind> {
-    if x.starts_with("return ") {
-        x.drain(0..7);
-        return Some(KeywordKind::TkReturn);
+/// Replaces the trigraphs like `??=` with the characters they stand for.
+/// They belong to translation phase 1, but as in GCC they are only replaced
+/// when asked for with `-trigraphs`.
+pub fn replace_trigraphs(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(i) = rest.find("??") {
+        let c = match rest[i + 2..].chars().next() {
+            Some('=') => '#',
+            Some('(') => '[',
+            Some('/') => '\\',
+            Some(')') => ']',
+            Some('\'') => '^',
+            Some('<') => '{',
+            Some('!') => '|',
+            Some('>') => '}',
+            Some('-') => '~',
+            _ => {
+                // In `???=` the trigraph starts at the second "?".
+                out.push_str(&rest[..=i]);
+                rest = &rest[i + 1..];
+                continue;
+            }
+        };
+        out.push_str(&rest[..i]);
+        out.push(c);
+        rest = &rest[i + 3..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn tokenize_token(x: &[u8], pos: &mut usize) -> Token {
+    if tokenize_pp_number(x, pos) {
+        return Token::PpNumber;
     }
-    if x.starts_with("if(") {
-        x.drain(0..2);
-        return Some(KeywordKind::TkIf);
+    if let Some(token) = tokenize_char(x, pos) {
+        return token;
     }
-    if x.starts_with("else ") {
-        x.drain(0..4);
-        return Some(KeywordKind::TkElse);
+    if let Some(token) = tokenize_string(x, pos) {
+        return token;
     }
-    None
+    if let Some(name) = tokenize_variant(x, pos) {
+        return match keyword(&name) {
+            Some(kind) => Token::Keyword { kind },
+            None => Token::Ident { name },
+        };
+    }
+    if let Some(op) = tokenize_operator(x, pos) {
+        return Token::Operator { kind: op };
+    }
+    // Any other character is a token of its own, an error only if it
+    // survives preprocessing.
+    *pos += 1;
+    while *pos < x.len() && x[*pos] & 0xc0 == 0x80 {
+        *pos += 1;
+    }
+    Token::Operator { kind: OperatorKind::TkOther }
 }
 
-fn tokenize_number(x: &mut String) -> Option<i32> {
-    let mut digits = "".to_string();
+// Skips whitespace and comments, returning whether a line ended. A comment
+// counts as a space even if it spans lines.
+fn tokenize_whitespace(x: &[u8], pos: &mut usize) -> bool {
+    let mut newline = false;
     loop {
-        match x.chars().next() {
-            Some(c) if c.is_ascii_digit() => {
-                digits += &c.to_string();
-                x.remove(0);
+        if x[*pos..].starts_with(b"//") {
+            while *pos < x.len() && x[*pos] != b'\n' {
+                *pos += 1;
+            }
+            continue;
+        }
+        if x[*pos..].starts_with(b"/*") {
+            match x[*pos + 2..].windows(2).position(|w| w == b"*/") {
+                Some(i) => *pos += i + 4,
+                None => error("unclosed block comment"),
+            }
+            continue;
+        }
+        match x.get(*pos) {
+            Some(c) if c.is_ascii_whitespace() => {
+                newline |= *c == b'\n';
+                *pos += 1;
             }
             _ => {
                 break;
             }
         }
     }
-    if digits.is_empty() {
-        None
-    } else {
-        Some(digits.parse::<i32>().unwrap())
+    newline
+}
+
+fn keyword(name: &str) -> Option<KeywordKind> {
+    match name {
+        "return" => Some(KeywordKind::TkReturn),
+        "if" => Some(KeywordKind::TkIf),
+        "else" => Some(KeywordKind::TkElse),
+        "while" => Some(KeywordKind::TkWhile),
+        "for" => Some(KeywordKind::TkFor),
+        "void" => Some(KeywordKind::TkVoid),
+        "char" => Some(KeywordKind::TkChar),
+        "short" => Some(KeywordKind::TkShort),
+        "int" => Some(KeywordKind::TkInt),
+        "long" => Some(KeywordKind::TkLong),
+        "sizeof" => Some(KeywordKind::TkSizeof),
+        "static" => Some(KeywordKind::TkStatic),
+        "struct" => Some(KeywordKind::TkStruct),
+        "union" => Some(KeywordKind::TkUnion),
+        "enum" => Some(KeywordKind::TkEnum),
+        "switch" => Some(KeywordKind::TkSwitch),
+        "case" => Some(KeywordKind::TkCase),
+        "default" => Some(KeywordKind::TkDefault),
+        "break" => Some(KeywordKind::TkBreak),
+        "typedef" => Some(KeywordKind::TkTypedef),
+        "extern" => Some(KeywordKind::TkExtern),
+        "const" | "__const" | "__const__" => Some(KeywordKind::TkConst),
+        "volatile" | "__volatile" | "__volatile__" => Some(KeywordKind::TkVolatile),
+        "restrict" | "__restrict" | "__restrict__" => Some(KeywordKind::TkRestrict),
+        "unsigned" => Some(KeywordKind::TkUnsigned),
+        "signed" | "__signed" | "__signed__" => Some(KeywordKind::TkSigned),
+        "float" => Some(KeywordKind::TkFloat),
+        "double" => Some(KeywordKind::TkDouble),
+        "_Bool" => Some(KeywordKind::TkBool),
+        "_Static_assert" => Some(KeywordKind::TkStaticAssert),
+        "_Alignas" => Some(KeywordKind::TkAlignas),
+        "_Alignof" | "__alignof" | "__alignof__" => Some(KeywordKind::TkAlignof),
+        "_Noreturn" => Some(KeywordKind::TkNoreturn),
+        // GNU spellings that system headers use.
+        "inline" | "__inline" | "__inline__" => Some(KeywordKind::TkInline),
+        "__attribute__" | "__attribute" => Some(KeywordKind::TkAttribute),
+        "__extension__" => Some(KeywordKind::TkExtension),
+        "asm" | "__asm" | "__asm__" => Some(KeywordKind::TkAsm),
+        _ => None,
     }
 }
 
-fn tokenize_variant(x: &mut String) -> Option<String> {
-    let mut s: String = "".to_string();
-    loop {
-        match x.chars().next() {
-            Some(c) if 'a' <= c && c <= 'z' => {
-                x.remove(0);
-                s.push(c);
-            }
-            _ => {
-                break;
+// A floating constant has a fraction part or an exponent: `1.5`, `.5`,
+// `1e3`, or the hexadecimal `0x1.8p1`. Its type is double, or float with
+// an `f` suffix and long double with an `l` suffix. The value is read both
+// as a double and in the x87 extended format.
+fn tokenize_float(x: &[u8], pos: &mut usize) -> Option<(f64, [u8; 10], Type)> {
+    let digit_at = |i: usize| x.get(i).is_some_and(|c| c.is_ascii_digit());
+    if !(digit_at(*pos) || (x[*pos] == b'.' && digit_at(*pos + 1))) {
+        return None;
+    }
+    let hex = x[*pos..].starts_with(b"0x") || x[*pos..].starts_with(b"0X");
+    let is_digit = |c: u8| if hex { c.is_ascii_hexdigit() } else { c.is_ascii_digit() };
+    let mut end = if hex { *pos + 2 } else { *pos };
+    let mut mantissa = String::new();
+    while end < x.len() && (is_digit(x[end]) || (x[end] == b'.' && !mantissa.contains('.'))) {
+        mantissa.push(x[end] as char);
+        end += 1;
+    }
+    let exp_char = if hex { b'p' } else { b'e' };
+    let mut exp = String::new();
+    if end < x.len() && x[end].to_ascii_lowercase() == exp_char {
+        // An exponent without digits is left as a suffix for the caller to reject.
+        let mut i = end + 1;
+        if i < x.len() && (x[i] == b'+' || x[i] == b'-') {
+            i += 1;
+        }
+        if i < x.len() && x[i].is_ascii_digit() {
+            exp.push_str(std::str::from_utf8(&x[end + 1..i]).unwrap());
+            while i < x.len() && x[i].is_ascii_digit() {
+                exp.push(x[i] as char);
+                i += 1;
             }
+            end = i;
         }
     }
-    if s.len() != 0 {
-        Some(s)
-    } else {
-        None
+    if !mantissa.contains('.') && exp.is_empty() {
+        return None;
     }
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((&mantissa, ""));
+    let digits = format!("{}{}", int_part, frac_part);
+    let exp_val: i64 = if exp.is_empty() { 0 } else { exp.parse().unwrap() };
+    let x87 = if hex {
+        x87_value(&digits, 16, exp_val - 4 * frac_part.len() as i64, 0)
+    } else {
+        x87_value(&digits, 10, 0, exp_val - frac_part.len() as i64)
+    };
+    let val = if hex {
+        let mut val = 0.0;
+        for c in digits.chars() {
+            val = val * 16.0 + c.to_digit(16).unwrap() as f64;
+        }
+        val * 2f64.powi(exp_val as i32 - 4 * frac_part.len() as i32)
+    } else {
+        let text = if exp.is_empty() { mantissa } else { format!("{}e{}", mantissa, exp) };
+        match text.parse() {
+            Ok(v) => v,
+            Err(_) => error(&format!("invalid floating constant: {}", text)),
+        }
+    };
+    let ty = match x.get(end) {
+        Some(b'f' | b'F') => {
+            end += 1;
+            Type::float()
+        }
+        Some(b'l' | b'L') => {
+            end += 1;
+            Type::ldouble()
+        }
+        _ => Type::double(),
+    };
+    *pos = end;
+    Some((val, x87, ty))
 }
 
-fn tokenize_operator(x: &mut String) -> Option<OperatorKind> {
-    if x.len() >= 2 {
-        if x.starts_with("==") {
-            x.drain(0..2);
-            return Some(OperatorKind::TkEq);
+// Rounds the value digits × 2^exp2 × 10^exp10 to the x87 extended format,
+// to nearest even. The digits are read into an exact big integer, and a
+// negative power of ten divides it with enough quotient bits to round
+// correctly.
+fn x87_value(digits: &str, radix: u32, exp2: i64, exp10: i64) -> [u8; 10] {
+    let digits = digits.trim_start_matches('0');
+    // Out of range even for the smallest subnormal or beyond the largest value.
+    let magnitude = exp10 + digits.len() as i64;
+    if digits.is_empty() || (radix == 10 && magnitude < -4960) {
+        return [0; 10];
+    }
+    if radix == 10 && magnitude > 4940 {
+        return round_x87(1, 16384, false);
+    }
+    let mut n = vec![0u32];
+    for c in digits.chars() {
+        mul_add(&mut n, radix, c.to_digit(radix).unwrap());
+    }
+    if exp10 >= 0 {
+        for _ in 0..exp10 {
+            mul_add(&mut n, 10, 0);
         }
-        if x.starts_with("!=") {
-            x.drain(0..2);
-            return Some(OperatorKind::TkNEq);
+        let drop = bit_len(&n).saturating_sub(128);
+        return round_x87(shr_u128(&n, drop), exp2 + drop as i64, low_bits_nonzero(&n, drop));
+    }
+    let mut d = vec![1u32];
+    for _ in 0..-exp10 {
+        mul_add(&mut d, 10, 0);
+    }
+    // A quotient of at least 66 bits leaves a rounding bit below the 64
+    // significand bits.
+    let scale = (bit_len(&d) + 66).saturating_sub(bit_len(&n));
+    let mut rem = shl_big(&n, scale);
+    let mut q = 0u128;
+    for i in (0..(bit_len(&rem) + 1).saturating_sub(bit_len(&d))).rev() {
+        let t = shl_big(&d, i);
+        if !less_big(&rem, &t) {
+            sub_big(&mut rem, &t);
+            q |= 1 << i;
         }
-        if x.starts_with("<=") {
-            x.drain(0..2);
-            return Some(OperatorKind::TkLe);
+    }
+    round_x87(q, exp2 - scale as i64, rem.iter().any(|&l| l != 0))
+}
+
+// Encodes q × 2^exp, with `sticky` set if nonzero bits below q were lost,
+// as a sign bit, a 15-bit exponent with bias 16383 and a 64-bit significand
+// with an explicit integer bit. Too large a value becomes infinity.
+fn round_x87(q: u128, exp: i64, mut sticky: bool) -> [u8; 10] {
+    if q == 0 {
+        return [0; 10];
+    }
+    let len = 128 - q.leading_zeros() as i64;
+    // The exponent of the significand's lowest bit; subnormals have fewer bits.
+    let mut low = (len - 1 + exp).max(-16382) - 63;
+    let shift = low - exp;
+    let mut m = if shift <= 0 {
+        q << -shift
+    } else if shift > 128 {
+        0
+    } else {
+        let (m, rest) = if shift == 128 { (0, q) } else { (q >> shift, q & ((1 << shift) - 1)) };
+        let half = 1u128 << (shift - 1);
+        sticky |= rest & (half - 1) != 0;
+        if rest & half != 0 && (sticky || m & 1 == 1) {
+            m + 1
+        } else {
+            m
+        }
+    };
+    if m >> 64 != 0 {
+        m >>= 1;
+        low += 1;
+    }
+    let mut biased = if m >> 63 != 0 { low + 63 + 16383 } else { 0 };
+    if biased >= 0x7fff {
+        biased = 0x7fff;
+        m = 1 << 63;
+    }
+    let mut bytes = [0; 10];
+    bytes[..8].copy_from_slice(&(m as u64).to_le_bytes());
+    bytes[8..].copy_from_slice(&(biased as u16).to_le_bytes());
+    bytes
+}
+
+// Big integers for `x87_value`, as little-endian 32-bit limbs.
+
+fn mul_add(n: &mut Vec<u32>, mul: u32, add: u32) {
+    let mut carry = add as u64;
+    for limb in n.iter_mut() {
+        let v = *limb as u64 * mul as u64 + carry;
+        *limb = v as u32;
+        carry = v >> 32;
+    }
+    if carry != 0 {
+        n.push(carry as u32);
+    }
+}
+
+fn bit_len(n: &[u32]) -> usize {
+    match n.iter().rposition(|&l| l != 0) {
+        Some(i) => i * 32 + 32 - n[i].leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+fn bit(n: &[u32], i: usize) -> bool {
+    n.get(i / 32).is_some_and(|l| l >> (i % 32) & 1 == 1)
+}
+
+fn shr_u128(n: &[u32], shift: usize) -> u128 {
+    (0..128).filter(|&i| bit(n, shift + i)).fold(0, |v, i| v | 1 << i)
+}
+
+fn low_bits_nonzero(n: &[u32], len: usize) -> bool {
+    (0..len).any(|i| bit(n, i))
+}
+
+fn shl_big(n: &[u32], shift: usize) -> Vec<u32> {
+    let mut out = vec![0u32; shift / 32];
+    let bits = shift % 32;
+    let mut carry = 0;
+    for &limb in n {
+        out.push(limb << bits | carry);
+        carry = if bits == 0 { 0 } else { limb >> (32 - bits) };
+    }
+    out.push(carry);
+    out
+}
+
+fn less_big(a: &[u32], b: &[u32]) -> bool {
+    for i in (0..a.len().max(b.len())).rev() {
+        let (x, y) = (a.get(i).copied().unwrap_or(0), b.get(i).copied().unwrap_or(0));
+        if x != y {
+            return x < y;
         }
-        if x.starts_with(">=") {
-            x.drain(0..2);
-            return Some(OperatorKind::TkGe);
+    }
+    false
+}
+
+// a -= b, where b <= a.
+fn sub_big(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0;
+    for (i, limb) in a.iter_mut().enumerate() {
+        let v = *limb as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        *limb = v as u32;
+        borrow = (v < 0) as i64;
+    }
+}
+
+// The type of an integer constant is the first of int, long and their
+// unsigned counterparts that can represent its value and is allowed by the
+// suffix. Decimal constants without a `u` suffix are never unsigned.
+fn tokenize_number(x: &[u8], pos: &mut usize) -> Option<(i64, Type)> {
+    if !x[*pos].is_ascii_digit() {
+        return None;
+    }
+    let (radix, start) = if x[*pos..].starts_with(b"0x") || x[*pos..].starts_with(b"0X") {
+        (16, *pos + 2)
+    } else if x[*pos] == b'0' {
+        (8, *pos)
+    } else {
+        (10, *pos)
+    };
+    let mut end = start;
+    while end < x.len() && (x[end] as char).is_digit(radix) {
+        end += 1;
+    }
+    let digits = std::str::from_utf8(&x[start..end]).unwrap();
+    let val = match u64::from_str_radix(digits, radix) {
+        Ok(v) => v,
+        Err(_) => error(&format!("invalid number: {}", digits)),
+    };
+    let (mut is_unsigned, mut is_long) = (false, false);
+    while end < x.len() && matches!(x[end], b'u' | b'U' | b'l' | b'L') {
+        if matches!(x[end], b'u' | b'U') {
+            is_unsigned = true;
+        } else {
+            is_long = true;
         }
+        end += 1;
     }
+    *pos = end;
+    let decimal = radix == 10;
+    let ty = if !is_long && !is_unsigned && val <= i32::MAX as u64 {
+        Type::int()
+    } else if !is_long && (is_unsigned || !decimal) && val <= u32::MAX as u64 {
+        Type::int().make_unsigned()
+    } else if !is_unsigned && val <= i64::MAX as u64 {
+        Type::long()
+    } else {
+        Type::ulong()
+    };
+    Some((val as i64, ty))
+}
 
-    match x.chars().next() {
-        Some(c) => {
-            match c {
-                '+' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkAdd)
-                }
-                '-' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkSub)
-                }
-                '*' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkMul)
-                }
-                '/' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkDiv)
-                }
-                '(' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkPrSt)
-                }
-                ')' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkPrEd)
-                }
-                '{' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkBrSt)
-                }
-                '}' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkBrEd)
-                }
-                '<' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkLt)
-                }
-                '>' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkGt)
-                }
-                '=' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkAssign)
-                }
-                ';' => {
-                    x.remove(0);
-                    Some(OperatorKind::TkExprEnd)
-                }
-                _ => None,
+// Reads the escape sequence after a backslash. Its value is cut to the
+// size of the literal's elements by the caller.
+fn read_escape(x: &[u8], pos: &mut usize) -> u32 {
+    let c = x[*pos];
+    *pos += 1;
+    match c {
+        b'0'..=b'7' => {
+            let mut v = (c - b'0') as u32;
+            let mut n = 1;
+            while n < 3 && *pos < x.len() && (b'0'..=b'7').contains(&x[*pos]) {
+                v = v * 8 + (x[*pos] - b'0') as u32;
+                *pos += 1;
+                n += 1;
+            }
+            v
+        }
+        b'x' => {
+            let mut v: u32 = 0;
+            while *pos < x.len() && x[*pos].is_ascii_hexdigit() {
+                v = v.wrapping_mul(16) + (x[*pos] as char).to_digit(16).unwrap();
+                *pos += 1;
             }
+            v
         }
-        None => {
-            None
+        b'a' => 7,
+        b'b' => 8,
+        b't' => b'\t' as u32,
+        b'n' => b'\n' as u32,
+        b'v' => 11,
+        b'f' => 12,
+        b'r' => b'\r' as u32,
+        b'e' => 27,
+        _ => c as u32,
+    }
+}
+
+// A preprocessing number starts with a digit, or a "." and a digit, and
+// goes on with letters, digits, "_", "." and a sign after an exponent letter.
+fn tokenize_pp_number(x: &[u8], pos: &mut usize) -> bool {
+    let digit_at = |i: usize| x.get(i).is_some_and(|c| c.is_ascii_digit());
+    if !(digit_at(*pos) || (x[*pos] == b'.' && digit_at(*pos + 1))) {
+        return false;
+    }
+    let mut end = *pos + 1;
+    while end < x.len() {
+        let sign = matches!(x[end], b'+' | b'-') && matches!(x[end - 1], b'e' | b'E' | b'p' | b'P');
+        if !(sign || x[end].is_ascii_alphanumeric() || x[end] == b'_' || x[end] == b'.') {
+            break;
         }
+        end += 1;
     }
+    *pos = end;
+    true
 }
 
+// The end of a character constant or string literal that starts at `pos`
+// with the quote `q`, or None if the line ends first.
+fn quoted_end(x: &[u8], pos: usize, q: u8) -> Option<usize> {
+    let mut i = pos + 1;
+    loop {
+        match x.get(i) {
+            Some(c) if *c == q => return Some(i + 1),
+            Some(b'\\') if x.get(i + 1).is_some_and(|c| *c != b'\n') => i += 2,
+            Some(b'\n') | None => return None,
+            Some(_) => i += 1,
+        }
+    }
+}
+
+// A quote without its closing quote on the same line is a token of its own,
+// which is an error only if it is left after preprocessing.
+fn stray_quote(pos: &mut usize) -> Token {
+    *pos += 1;
+    Token::Operator { kind: OperatorKind::TkOther }
+}
+
+// The length of the encoding prefix `u8`, `L`, `u` or `U` of a character
+// constant or string literal at `pos`, 0 if there is none.
+fn literal_prefix(x: &[u8], pos: usize) -> usize {
+    for prefix in [&b"u8"[..], b"L", b"u", b"U"] {
+        if x[pos..].starts_with(prefix) && matches!(x.get(pos + prefix.len()), Some(b'\'' | b'"')) {
+            return prefix.len();
+        }
+    }
+    0
+}
+
+// The element type of a wide literal with the prefix `L`, `u` or `U`:
+// wchar_t, char16_t or char32_t. Other literals have char elements.
+fn literal_type(prefix: &[u8]) -> Type {
+    match prefix {
+        b"L" => Type::int(),
+        b"u" => Type::short().make_unsigned(),
+        b"U" => Type::int().make_unsigned(),
+        _ => Type::char(),
+    }
+}
+
+// The code units of the characters of a literal from `pos` to `end`, in
+// elements of `size` bytes. A narrow literal keeps the bytes of the source;
+// a wide one holds code points, in UTF-16 for char16_t. A universal
+// character name like `\u00e9` is the character it names, in UTF-8 in a
+// narrow literal.
+fn literal_units(x: &[u8], mut pos: usize, end: usize, size: usize) -> Vec<u32> {
+    let mut units = Vec::new();
+    while pos < end {
+        let c = if x[pos] == b'\\' && matches!(x.get(pos + 1), Some(b'u' | b'U')) {
+            let len = if x[pos + 1] == b'u' { 4 } else { 8 };
+            let digits = String::from_utf8_lossy(&x[pos + 2..(pos + 2 + len).min(end)]).into_owned();
+            pos += 2 + len;
+            u32::from_str_radix(&digits, 16).unwrap_or(0xfffd)
+        } else if x[pos] == b'\\' {
+            pos += 1;
+            let c = read_escape(x, &mut pos);
+            units.push(if size == 4 { c } else { c & ((1 << (8 * size)) - 1) });
+            continue;
+        } else if size == 1 {
+            units.push(x[pos] as u32);
+            pos += 1;
+            continue;
+        } else {
+            let len = match x[pos] {
+                0xf0.. => 4,
+                0xe0.. => 3,
+                0xc0.. => 2,
+                _ => 1,
+            };
+            let text = String::from_utf8_lossy(&x[pos..(pos + len).min(end)]).into_owned();
+            pos += len;
+            text.chars().next().unwrap() as u32
+        };
+        match size {
+            1 => units.extend(char::from_u32(c).unwrap_or('\u{fffd}').to_string().bytes().map(u32::from)),
+            2 if c > 0xffff => units.extend([0xd800 + ((c - 0x10000) >> 10), 0xdc00 + (c & 0x3ff)]),
+            _ => units.push(c),
+        }
+    }
+    units
+}
+
+fn tokenize_char(x: &[u8], pos: &mut usize) -> Option<Token> {
+    let start = *pos + literal_prefix(x, *pos);
+    if x[start] != b'\'' {
+        return None;
+    }
+    match quoted_end(x, start, b'\'') {
+        Some(end) => *pos = end,
+        // An unterminated constant leaves its prefix as an identifier.
+        None if start != *pos => return None,
+        None => return Some(stray_quote(pos)),
+    }
+    Some(Token::PpChar)
+}
+
+// The value and type of a character constant. One without a prefix has
+// type int and the value of a char, `u8'x'` is an unsigned char, and the
+// wide ones have their element type.
+fn char_value(x: &[u8]) -> Result<(i64, Type), &'static str> {
+    let quote = literal_prefix(x, 0);
+    let prefix = &x[..quote];
+    let ty = literal_type(prefix);
+    let units = literal_units(x, quote + 1, x.len() - 1, ty.size());
+    let c = match units[..] {
+        [] => return Err("empty character constant"),
+        [c] => c,
+        _ => return Err("multi-character character constant"),
+    };
+    Ok(match prefix {
+        b"" => (c as i8 as i64, Type::int()),
+        b"u8" => (c as u8 as i64, Type::char().make_unsigned()),
+        b"L" => (c as i32 as i64, ty),
+        _ => (c as i64, ty),
+    })
+}
+
+fn tokenize_string(x: &[u8], pos: &mut usize) -> Option<Token> {
+    let start = *pos + literal_prefix(x, *pos);
+    if x[start] != b'"' {
+        return None;
+    }
+    let end = match quoted_end(x, start, b'"') {
+        Some(end) => end,
+        None if start != *pos => return None,
+        None => return Some(stray_quote(pos)),
+    };
+    let ty = literal_type(&x[*pos..start]);
+    let size = ty.size();
+    let val = literal_units(x, start + 1, end - 1, size).iter().flat_map(|c| c.to_le_bytes()[..size].to_vec()).collect();
+    *pos = end;
+    Some(Token::Str { val, ty })
+}
+
+fn tokenize_variant(x: &[u8], pos: &mut usize) -> Option<String> {
+    let start = *pos;
+    while *pos < x.len() && (x[*pos].is_ascii_alphabetic() || x[*pos] == b'_' || (*pos > start && x[*pos].is_ascii_digit())) {
+        *pos += 1;
+    }
+    if *pos != start {
+        Some(String::from_utf8(x[start..*pos].to_vec()).unwrap())
+    } else {
+        None
+    }
+}
+
+fn tokenize_operator(x: &[u8], pos: &mut usize) -> Option<OperatorKind> {
+    let three = [
+        ("...", OperatorKind::TkEllipsis),
+        ("<<=", OperatorKind::TkShlAssign),
+        (">>=", OperatorKind::TkShrAssign),
+    ];
+    for (s, kind) in three.iter() {
+        if x[*pos..].starts_with(s.as_bytes()) {
+            *pos += 3;
+            return Some(kind.clone());
+        }
+    }
+
+    let two = [
+        ("==", OperatorKind::TkEq),
+        ("!=", OperatorKind::TkNEq),
+        ("<=", OperatorKind::TkLe),
+        (">=", OperatorKind::TkGe),
+        ("->", OperatorKind::TkArrow),
+        ("<<", OperatorKind::TkShl),
+        (">>", OperatorKind::TkShr),
+        ("+=", OperatorKind::TkAddAssign),
+        ("-=", OperatorKind::TkSubAssign),
+        ("*=", OperatorKind::TkMulAssign),
+        ("/=", OperatorKind::TkDivAssign),
+        ("%=", OperatorKind::TkModAssign),
+        ("&=", OperatorKind::TkAndAssign),
+        ("|=", OperatorKind::TkOrAssign),
+        ("^=", OperatorKind::TkXorAssign),
+        ("&&", OperatorKind::TkLogAnd),
+        ("||", OperatorKind::TkLogOr),
+        ("++", OperatorKind::TkInc),
+        ("--", OperatorKind::TkDec),
+        ("##", OperatorKind::TkHashHash),
+    ];
+    for (s, kind) in two.iter() {
+        if x[*pos..].starts_with(s.as_bytes()) {
+            *pos += 2;
+            return Some(kind.clone());
+        }
+    }
+
+    let kind = match x[*pos] {
+        b'+' => OperatorKind::TkAdd,
+        b'-' => OperatorKind::TkSub,
+        b'*' => OperatorKind::TkMul,
+        b'/' => OperatorKind::TkDiv,
+        b'%' => OperatorKind::TkMod,
+        b'&' => OperatorKind::TkAnd,
+        b'|' => OperatorKind::TkOr,
+        b'^' => OperatorKind::TkXor,
+        b'~' => OperatorKind::TkTilde,
+        b'!' => OperatorKind::TkNot,
+        b'?' => OperatorKind::TkQuestion,
+        b'(' => OperatorKind::TkPrSt,
+        b')' => OperatorKind::TkPrEd,
+        b'{' => OperatorKind::TkBrSt,
+        b'}' => OperatorKind::TkBrEd,
+        b'[' => OperatorKind::TkSqSt,
+        b']' => OperatorKind::TkSqEd,
+        b'<' => OperatorKind::TkLt,
+        b'>' => OperatorKind::TkGt,
+        b'=' => OperatorKind::TkAssign,
+        b',' => OperatorKind::TkComma,
+        b'.' => OperatorKind::TkDot,
+        b':' => OperatorKind::TkColon,
+        b';' => OperatorKind::TkExprEnd,
+        b'#' => OperatorKind::TkHash,
+        b'@' | b'$' | b'`' | b'\\' => OperatorKind::TkOther,
+        _ => return None,
+    };
+    *pos += 1;
+    Some(kind)
+}
@@ -0,0 +1,417 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeKind {
+    Void,
+    Bool,
+    Char,
+    Short,
+    Int,
+    Long,
+    Float,
+    Double,
+    // The x87 80-bit extended format, stored in 16 bytes.
+    LDouble,
+    Ptr(Box<Type>),
+    Array(Box<Type>, usize),
+    Func {
+        ret: Box<Type>,
+        params: Vec<Type>,
+        variadic: bool,
+    },
+    // Shared so that completing a tag's definition updates every use of it,
+    // including pointers to the struct from inside its own members.
+    Struct(Rc<RefCell<Aggregate>>),
+}
+
+/// A member as declared: its name, its type, the width of a bit-field, and
+/// an alignment other than the type's own. A bit-field given an alignment
+/// is packed.
+pub type MemberDecl = (Option<String>, Type, Option<usize>, Option<usize>);
+
+#[derive(Clone)]
+pub struct Member {
+    pub name: Option<String>,
+    pub ty: Type,
+    pub offset: usize,
+    /// For a bit-field, its bit offset within the storage unit of its type
+    /// that starts at `offset`, and its width.
+    pub bit_field: Option<(usize, usize)>,
+}
+
+pub struct Aggregate {
+    pub tag: Option<String>,
+    pub is_union: bool,
+    pub members: Vec<Member>,
+    pub size: usize,
+    pub align: usize,
+    pub complete: bool,
+}
+
+impl Aggregate {
+    pub fn new(tag: Option<String>, is_union: bool) -> Aggregate {
+        Aggregate {
+            tag,
+            is_union,
+            members: Vec::new(),
+            size: 0,
+            align: 1,
+            complete: false,
+        }
+    }
+
+    /// Assigns member offsets and computes the size and alignment the way
+    /// the System V x86-64 ABI lays out structures and unions. Members are
+    /// given as a name, a type, for bit-fields a width, and an alignment if
+    /// it is not the type's own. Like GCC, a bit-field is placed in the
+    /// storage unit of its type that holds the next free bit, or in the
+    /// following one if it would straddle them; unnamed bit-fields only pad
+    /// and do not affect the alignment. A packed bit-field takes the next
+    /// free bits, counted from the byte that holds the first. The aggregate
+    /// is aligned to at least `min_align`.
+    pub fn layout(&mut self, members: Vec<MemberDecl>, min_align: usize) {
+        // Positions are tracked in bits so that bit-fields can share units.
+        let mut bits = 0;
+        let mut align = 1;
+        let mut size = 0;
+        for (name, ty, width, mem_align) in members {
+            let packed = mem_align.is_some();
+            let mem_align = mem_align.unwrap_or_else(|| ty.align());
+            if self.is_union {
+                bits = 0;
+            }
+            let (offset, bit_field) = match width {
+                Some(width) => {
+                    let unit = ty.size() * 8;
+                    // A zero-width bit-field closes the current unit.
+                    if width == 0 || (!packed && bits / unit != (bits + width - 1) / unit) {
+                        bits = align_to(bits, unit);
+                    }
+                    let offset = if packed { bits / 8 } else { bits / unit * ty.size() };
+                    let bit_offset = bits - offset * 8;
+                    bits += width;
+                    (offset, Some((bit_offset, width)))
+                }
+                None => {
+                    bits = align_to(bits, mem_align * 8);
+                    let offset = bits / 8;
+                    bits += ty.size() * 8;
+                    (offset, None)
+                }
+            };
+            size = size.max(bits.div_ceil(8));
+            if name.is_none() && width.is_some() {
+                continue;
+            }
+            align = align.max(mem_align);
+            self.members.push(Member { name, ty, offset, bit_field });
+        }
+        self.align = align.max(min_align);
+        self.size = align_to(size, self.align);
+        self.complete = true;
+    }
+
+    /// Returns the member indices leading to the named member, descending
+    /// into anonymous struct and union members.
+    pub fn find_member_path(&self, name: &str) -> Option<Vec<usize>> {
+        for (i, mem) in self.members.iter().enumerate() {
+            match &mem.name {
+                Some(n) if n == name => return Some(vec![i]),
+                None => {
+                    if let TypeKind::Struct(agg) = &mem.ty.kind {
+                        if let Some(mut path) = agg.borrow().find_member_path(name) {
+                            path.insert(0, i);
+                            return Some(path);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Finds a member by name, looking through anonymous struct and union
+    /// members. The returned member's offset is relative to this aggregate.
+    pub fn find_member(&self, name: &str) -> Option<Member> {
+        for mem in &self.members {
+            match &mem.name {
+                Some(n) if n == name => return Some(mem.clone()),
+                None => {
+                    if let TypeKind::Struct(agg) = &mem.ty.kind {
+                        if let Some(found) = agg.borrow().find_member(name) {
+                            return Some(Member { offset: mem.offset + found.offset, ..found });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+// Aggregates are compared by identity: two struct types are the same type
+// only if they come from the same declaration.
+impl PartialEq for Aggregate {
+    fn eq(&self, other: &Aggregate) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kw = if self.is_union { "union" } else { "struct" };
+        match &self.tag {
+            Some(tag) => write!(f, "{} {}", kw, tag),
+            None => write!(f, "{} <anonymous>", kw),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Type {
+    pub kind: TypeKind,
+    pub is_unsigned: bool,
+    pub is_const: bool,
+    pub is_volatile: bool,
+}
+
+impl Type {
+    pub fn new(kind: TypeKind) -> Type {
+        Type { kind, is_unsigned: false, is_const: false, is_volatile: false }
+    }
+
+    /// The unsigned counterpart of an integer type.
+    pub fn make_unsigned(self) -> Type {
+        Type { is_unsigned: true, ..self }
+    }
+
+    /// Adds qualifiers to the type. Qualifying an array type qualifies its
+    /// element type instead.
+    pub fn qualified(self, is_const: bool, is_volatile: bool) -> Type {
+        match self.kind {
+            TypeKind::Array(base, len) => Type::array_of(base.qualified(is_const, is_volatile), len),
+            kind => Type {
+                kind,
+                is_unsigned: self.is_unsigned,
+                is_const: self.is_const || is_const,
+                is_volatile: self.is_volatile || is_volatile,
+            },
+        }
+    }
+
+    pub fn void() -> Type {
+        Type::new(TypeKind::Void)
+    }
+
+    /// `_Bool`, an unsigned type whose only values are 0 and 1.
+    pub fn bool() -> Type {
+        Type::new(TypeKind::Bool).make_unsigned()
+    }
+
+    pub fn char() -> Type {
+        Type::new(TypeKind::Char)
+    }
+
+    pub fn short() -> Type {
+        Type::new(TypeKind::Short)
+    }
+
+    pub fn int() -> Type {
+        Type::new(TypeKind::Int)
+    }
+
+    pub fn long() -> Type {
+        Type::new(TypeKind::Long)
+    }
+
+    pub fn float() -> Type {
+        Type::new(TypeKind::Float)
+    }
+
+    pub fn double() -> Type {
+        Type::new(TypeKind::Double)
+    }
+
+    pub fn ldouble() -> Type {
+        Type::new(TypeKind::LDouble)
+    }
+
+    pub fn ulong() -> Type {
+        Type::long().make_unsigned()
+    }
+
+    pub fn pointer_to(base: Type) -> Type {
+        Type::new(TypeKind::Ptr(Box::new(base)))
+    }
+
+    pub fn array_of(base: Type, len: usize) -> Type {
+        Type::new(TypeKind::Array(Box::new(base), len))
+    }
+
+    pub fn func(ret: Type, params: Vec<Type>, variadic: bool) -> Type {
+        Type::new(TypeKind::Func { ret: Box::new(ret), params, variadic })
+    }
+
+    /// The System V `va_list`: a one-element array of the structure
+    /// that tracks the progress through the register save area and the
+    /// arguments passed on the stack.
+    pub fn va_list() -> Type {
+        let mut agg = Aggregate::new(Some("__va_list_tag".to_string()), false);
+        agg.layout(vec![
+            (Some("gp_offset".to_string()), Type::int(), None, None),
+            (Some("fp_offset".to_string()), Type::int(), None, None),
+            (Some("overflow_arg_area".to_string()), Type::pointer_to(Type::void()), None, None),
+            (Some("reg_save_area".to_string()), Type::pointer_to(Type::void()), None, None),
+        ], 1);
+        Type::array_of(Type::new(TypeKind::Struct(Rc::new(RefCell::new(agg)))), 1)
+    }
+
+    pub fn size(&self) -> usize {
+        match &self.kind {
+            TypeKind::Void => 1,
+            TypeKind::Bool | TypeKind::Char => 1,
+            TypeKind::Short => 2,
+            TypeKind::Int | TypeKind::Float => 4,
+            TypeKind::Long | TypeKind::Double | TypeKind::Ptr(_) => 8,
+            TypeKind::LDouble => 16,
+            TypeKind::Array(base, len) => base.size() * len,
+            TypeKind::Func { .. } => 1,
+            TypeKind::Struct(agg) => agg.borrow().size,
+        }
+    }
+
+    pub fn align(&self) -> usize {
+        match &self.kind {
+            TypeKind::Array(base, _) => base.align(),
+            TypeKind::Struct(agg) => agg.borrow().align,
+            _ => self.size(),
+        }
+    }
+
+    pub fn is_struct(&self) -> bool {
+        matches!(self.kind, TypeKind::Struct(_))
+    }
+
+    /// Whether the size of the type is known.
+    pub fn is_complete(&self) -> bool {
+        match &self.kind {
+            TypeKind::Void => false,
+            TypeKind::Struct(agg) => agg.borrow().complete,
+            TypeKind::Array(base, _) => base.is_complete(),
+            _ => true,
+        }
+    }
+
+    /// Whether an object of the type may be assigned to: it is not const,
+    /// and neither is any of its elements or members.
+    pub fn is_modifiable(&self) -> bool {
+        if self.is_const {
+            return false;
+        }
+        match &self.kind {
+            TypeKind::Array(base, _) => base.is_modifiable(),
+            TypeKind::Struct(agg) => agg.borrow().members.iter().all(|m| m.ty.is_modifiable()),
+            _ => true,
+        }
+    }
+
+    pub fn unqualified(&self) -> Type {
+        Type { is_const: false, is_volatile: false, ..self.clone() }
+    }
+
+    /// Whether values of the type are single numbers: arithmetic types and pointers.
+    pub fn is_scalar(&self) -> bool {
+        self.is_arith() || matches!(self.kind, TypeKind::Ptr(_))
+    }
+
+    pub fn is_flonum(&self) -> bool {
+        matches!(self.kind, TypeKind::Float | TypeKind::Double | TypeKind::LDouble)
+    }
+
+    pub fn is_arith(&self) -> bool {
+        self.is_integer() || self.is_flonum()
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self.kind, TypeKind::Bool | TypeKind::Char | TypeKind::Short | TypeKind::Int | TypeKind::Long)
+    }
+
+    /// The integer promotions: integer types narrower than `int` become
+    /// `int`, which can represent all of their values. Qualifiers are dropped.
+    pub fn promoted(&self) -> Type {
+        match self.kind {
+            TypeKind::Bool | TypeKind::Char | TypeKind::Short => Type::int(),
+            TypeKind::Int | TypeKind::Long => Type { is_unsigned: self.is_unsigned, ..Type::new(self.kind.clone()) },
+            _ => self.clone(),
+        }
+    }
+
+    /// The pointed-to type of a pointer, or the element type of an array.
+    pub fn base(&self) -> Option<&Type> {
+        match &self.kind {
+            TypeKind::Ptr(base) | TypeKind::Array(base, _) => Some(base),
+            _ => None,
+        }
+    }
+}
+
+/// Where the System V calling convention passes an argument.
+pub enum ArgLoc {
+    Gp(usize),
+    Fp(usize),
+    /// Offset from the start of the arguments passed in memory.
+    Stack(usize),
+}
+
+/// Assigns arguments of the given types to registers and stack slots.
+/// Integers and pointers take the six general-purpose registers, `float`
+/// and `double` the eight XMM registers, and the rest are passed in memory
+/// in order, where `long double` is 16-byte aligned. Returns the locations
+/// and the size of the memory area.
+pub fn classify_args(tys: &[Type]) -> (Vec<ArgLoc>, usize) {
+    let (mut gp, mut fp, mut stack) = (0, 0, 0);
+    let mut locs = Vec::new();
+    for ty in tys {
+        if ty.kind == TypeKind::LDouble {
+            stack = align_to(stack, 16);
+            locs.push(ArgLoc::Stack(stack));
+            stack += 16;
+        } else if ty.is_flonum() && fp < 8 {
+            locs.push(ArgLoc::Fp(fp));
+            fp += 1;
+        } else if !ty.is_flonum() && gp < 6 {
+            locs.push(ArgLoc::Gp(gp));
+            gp += 1;
+        } else {
+            locs.push(ArgLoc::Stack(stack));
+            stack += 8;
+        }
+    }
+    (locs, stack)
+}
+
+/// The usual arithmetic conversions: the type both operands of a binary
+/// operator are converted to. A floating type wins over an integer type and
+/// `double` over `float`. Otherwise, after promotion, the wider type wins;
+/// between types of equal width, the unsigned one does.
+pub fn common_type(a: &Type, b: &Type) -> Type {
+    if a.is_flonum() || b.is_flonum() {
+        let rank = |t: &Type| if t.is_flonum() { t.size() } else { 0 };
+        return if rank(a) >= rank(b) { a.unqualified() } else { b.unqualified() };
+    }
+    let (a, b) = (a.promoted(), b.promoted());
+    match a.size().cmp(&b.size()) {
+        std::cmp::Ordering::Greater => a,
+        std::cmp::Ordering::Less => b,
+        std::cmp::Ordering::Equal if b.is_unsigned => b,
+        std::cmp::Ordering::Equal => a,
+    }
+}
+
+pub fn align_to(n: usize, align: usize) -> usize {
+    n.div_ceil(align) * align
+}
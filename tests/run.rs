@@ -0,0 +1,462 @@
+//! Compiles C programs with rcc1, links them with the system C compiler and
+//! checks their exit status. Each program returns 0 when every check passes
+//! and otherwise the number of the check that failed.
+
+use std::fs;
+use std::process::Command;
+
+fn run(name: &str, src: &str) -> i32 {
+    run_with_asm(name, src, "")
+}
+
+// Like `run`, but links the program with hand-written assembly.
+fn run_with_asm(name: &str, src: &str, helper: &str) -> i32 {
+    let dir = std::env::temp_dir().join(format!("rcc1-run-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_rcc1")).arg(src).output().unwrap();
+    assert!(out.status.success(), "rcc1 failed: {}", String::from_utf8_lossy(&out.stderr));
+    let asm = dir.join(format!("{}.s", name));
+    let helper_asm = dir.join(format!("{}_helper.s", name));
+    let exe = dir.join(name);
+    fs::write(&asm, &out.stdout).unwrap();
+    fs::write(&helper_asm, helper).unwrap();
+    let cc = Command::new("cc").arg("-o").arg(&exe).arg(&asm).arg(&helper_asm).output().unwrap();
+    assert!(cc.status.success(), "cc failed: {}", String::from_utf8_lossy(&cc.stderr));
+    Command::new(&exe).status().unwrap().code().unwrap()
+}
+
+#[test]
+fn function_pointers() {
+    let src = "int add(int a, int b) { return a + b; }
+               int twice(int x) { return x * 2; }
+               int neg(int x) { return -x; }
+               struct Ops { int (*op)(int, int); int n; };
+               int apply(int (*f)(int), int x) { return f(x); }
+               int (*pick(int i))(int) { return i ? neg : twice; }
+               int main() {
+                 int (*fp)(int) = twice;
+                 if (fp(3) != 6) return 1;
+                 if ((*fp)(4) != 8) return 2;
+                 struct Ops o = {add, 1};
+                 if (o.op(2, 3) != 5) return 3;
+                 struct Ops *p = &o;
+                 if (p->op(4, 5) != 9) return 4;
+                 int (*tab[3])(int) = {twice, neg, &twice};
+                 if (tab[1](7) != -7 || tab[2](1) != 2) return 5;
+                 if (sizeof tab != 24) return 6;
+                 if (apply(neg, 9) != -9) return 7;
+                 if (pick(1)(5) != -5 || pick(0)(5) != 10) return 8;
+                 return 0;
+               }";
+    assert_eq!(run("function_pointers", src), 0);
+}
+
+#[test]
+fn variadic_functions() {
+    // Nine int and ten double arguments leave some of each on the stack.
+    let src = "#include <stdarg.h>
+               long digits(int n, ...) {
+                 va_list ap;
+                 long s = 0;
+                 va_start(ap, n);
+                 for (int i = 0; i < n; i++)
+                   s = s * 10 + va_arg(ap, int);
+                 va_end(ap);
+                 return s;
+               }
+               double mixed(double k, int n, ...) {
+                 va_list ap;
+                 double s = 0;
+                 va_start(ap, n);
+                 for (int i = 0; i < n; i++) {
+                   s = s * k + va_arg(ap, double);
+                   s = s + va_arg(ap, long);
+                 }
+                 va_end(ap);
+                 return s;
+               }
+               int format(char *f, ...) {
+                 va_list ap;
+                 int n = 0;
+                 va_start(ap, f);
+                 for (; *f; f++) {
+                   if (*f == 'i') n = n * 3 + va_arg(ap, int);
+                   else if (*f == 'd') n = n * 3 + (int)va_arg(ap, double);
+                   else if (*f == 'p') n = n * 3 + *va_arg(ap, char *);
+                 }
+                 va_end(ap);
+                 return n;
+               }
+               int main() {
+                 char c = 5;
+                 if (digits(9, 1, 2, 3, 4, 5, 6, 7, 8, 9) != 123456789) return 1;
+                 if (mixed(2.0, 10, 1.0, 1L, 2.0, 2L, 3.0, 3L, 4.0, 4L, 5.0, 5L,
+                           6.0, 6L, 7.0, 7L, 8.0, 8L, 9.0, 9L, 10.5, 10L) != 4072.5) return 2;
+                 if (format(\"ididpidid\", 1, 2.5, 1, 0.9, &c, 2, 1.5, 1, 3.0) != 12138) return 3;
+                 return 0;
+               }";
+    assert_eq!(run("variadic_functions", src), 0);
+}
+
+#[test]
+fn switch_case_conversions() {
+    let src = "int f(unsigned x) {
+                 switch (x - 6) { case -1: return 1; case 4294967294: return 2; default: return 3; }
+               }
+               int g(unsigned long x) {
+                 switch (x) { case -1: return 1; case 0xffffffff: return 2; }
+                 return 0;
+               }
+               int h(short s) {
+                 switch (s) { case -1: return 1; case 65535: return 2; }
+                 return 0;
+               }
+               int main() {
+                 if (f(5) != 1 || f(4) != 2 || f(6) != 3) return 1;
+                 if (g(-1) != 1 || g(0xffffffffUL) != 2) return 2;
+                 if (h(-1) != 1) return 3;
+                 return 0;
+               }";
+    assert_eq!(run("switch_case_conversions", src), 0);
+}
+
+#[test]
+fn floating_to_unsigned_long() {
+    let src = "int main() {
+                 double d = 1e19;
+                 unsigned long u = d;
+                 float f = 1.5e19f;
+                 if (u != 10000000000000000000UL) return 1;
+                 if ((unsigned long)f != 15000000520515485696UL) return 2;
+                 d = 9223372036854775808.0;
+                 if ((unsigned long)d != 9223372036854775808UL) return 3;
+                 d = 12345.9;
+                 if ((unsigned long)d != 12345) return 4;
+                 d = 18446744073709549568.0;
+                 if ((unsigned long)d != 18446744073709549568UL) return 5;
+                 f = 3e9f;
+                 if ((unsigned)f != 3000000000U) return 6;
+                 return 0;
+               }";
+    assert_eq!(run("floating_to_unsigned_long", src), 0);
+}
+
+#[test]
+fn floating_negation() {
+    // Negation flips the sign of zero, in code and in constants.
+    let src = "double gz = -0.0;
+               long double gl = -0.0L;
+               float gf = -(1.5f);
+               int main() {
+                 double z = 0.0;
+                 float f = 0.0f;
+                 long double l = 0.0L;
+                 double n = z / z;
+                 if (!(1 / -0.0 < 0) || !(1 / -z < 0)) return 1;
+                 if (!(1 / -f < 0) || !(1 / -l < 0)) return 2;
+                 if (!(1 / gz < 0) || !(1 / gl < 0) || gf != -1.5f) return 3;
+                 if (-(-2.5) != 2.5 || -z != 0 || -(long double)3 != -3.0L) return 4;
+                 if (-n == -n) return 5;
+                 return 0;
+               }";
+    assert_eq!(run("floating_negation", src), 0);
+}
+
+#[test]
+fn long_double_to_unsigned_long() {
+    let src = "unsigned long g = (unsigned long)1e19L;
+               int main() {
+                 long double x = 1e19L;
+                 unsigned long u;
+                 if ((unsigned long)x != 10000000000000000000UL) return 1;
+                 if ((unsigned long)1e19L != 10000000000000000000UL || g != 10000000000000000000UL) return 2;
+                 x = 18446744073709549568.0L;
+                 if ((unsigned long)x != 18446744073709549568UL) return 3;
+                 x = 9223372036854775808.0L;
+                 if ((unsigned long)x != 9223372036854775808UL) return 4;
+                 x = 123.75L;
+                 if ((unsigned long)x != 123) return 5;
+                 x = 9223372036854774784.0L;
+                 u = x;
+                 if (u != 9223372036854774784UL) return 6;
+                 return 0;
+               }";
+    assert_eq!(run("long_double_to_unsigned_long", src), 0);
+}
+
+#[test]
+fn long_double_precision() {
+    let src = "long double a = 0.1L;
+               int main() {
+                 long double x = 18446744073709551615.0L;
+                 if (0.1L == 0.1 || a == 0.1) return 1;
+                 if (a != 0.1L || -a != -0.1L) return 2;
+                 if ((unsigned long)x != 18446744073709551615UL) return 3;
+                 if ((unsigned long)9223372036854775807.0L != 9223372036854775807UL) return 4;
+                 if (1.18973149535723176502e+4932L <= 1e308) return 5;
+                 if (0x1.fffffffffffffffep0L == 2.0L) return 6;
+                 return 0;
+               }";
+    assert_eq!(run("long_double_precision", src), 0);
+}
+
+#[test]
+fn assignment_operators() {
+    // The target of a compound assignment is evaluated once.
+    let src = "int count;
+               int next(void) { return count++; }
+               int main() {
+                 int a[4] = {1, 2, 3, 4};
+                 int i = 1;
+                 long l[3] = {0, 0, 0};
+                 long *p = l;
+                 int *q = &a[3];
+                 int x = 5;
+                 int y;
+                 int z;
+                 int s = 1;
+                 unsigned char c = 250;
+                 double d = 1;
+                 a[i++] += 10;
+                 if (i != 2 || a[1] != 12 || a[2] != 3) return 1;
+                 a[next()] *= 5;
+                 if (count != 1 || a[0] != 5) return 2;
+                 p += 1;
+                 if ((char *)p - (char *)l != 8 || p - l != 1) return 3;
+                 p -= 1;
+                 if (p != l) return 4;
+                 y = x++;
+                 if (y != 5 || x != 6) return 5;
+                 y = ++x;
+                 if (y != 7 || x != 7) return 6;
+                 y = x--;
+                 if (y != 7 || x != 6) return 7;
+                 z = (x = 1, x + 41);
+                 if (z != 42 || x != 1) return 8;
+                 c += 10;
+                 if (c != 4) return 9;
+                 s <<= 4; s >>= 1; s |= 1; s ^= 3; s &= 14; s %= 7;
+                 if (s != 3) return 10;
+                 d += 0.5; d *= 2; d -= 1; d /= 4;
+                 if (d != 0.5) return 11;
+                 q--;
+                 *q++ = 9;
+                 if (a[2] != 9 || q != &a[3]) return 12;
+                 return 0;
+               }";
+    assert_eq!(run("assignment_operators", src), 0);
+}
+
+#[test]
+fn skipped_groups_and_warnings() {
+    // Only directive names are read in a skipped group.
+    let src = "#warning don't do this
+               #if 0
+               `é ‘quoted’ isn't \\ $x 1.2.3
+               #elif 1
+               int main() { return 0; }
+               #endif";
+    let out = Command::new(env!("CARGO_BIN_EXE_rcc1")).arg(src).output().unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("#warning don't do this"));
+    assert_eq!(run("skipped_groups_and_warnings", src), 0);
+}
+
+#[test]
+fn system_headers() {
+    // Every bundled header, and the glibc headers around them.
+    let mut names: Vec<String> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/include"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    for name in ["assert.h", "ctype.h", "errno.h", "fenv.h", "inttypes.h", "locale.h", "math.h", "setjmp.h", "signal.h",
+                 "stdio.h", "stdlib.h", "string.h", "time.h", "uchar.h", "unistd.h", "wchar.h", "wctype.h"] {
+        names.push(name.to_string());
+    }
+    let mut src: String = names.iter().map(|n| format!("#include <{}>\n", n)).collect();
+    src.push_str("int main() {
+                    wchar_t w[8];
+                    if (wcslen(L\"wide\") != 4 || strlen(\"narrow\") != 6) return 1;
+                    if (swprintf(w, 8, L\"%d\", INT_MAX % 1000) != 3 || wcscmp(w, L\"647\")) return 2;
+                    if (!iswalpha(L'x') || towupper(L'q') != L'Q') return 3;
+                    return 0;
+                  }");
+    assert_eq!(run("system_headers", &src), 0);
+}
+
+#[test]
+fn wide_literals() {
+    let src = "#include <uchar.h>
+               #include <stddef.h>
+               wchar_t g[] = L\"h\\u00e9\" \"y\";
+               char16_t h[] = u\"a\\U0001F600\";
+               char32_t k[] = U\"€x\";
+               char n[] = u8\"é\";
+               struct S { int n; wchar_t s[4]; } s = { 1, L\"ab\" };
+               int main() {
+                 wchar_t *p = L\"wide\" L\"r\";
+                 if (sizeof g != 16 || g[1] != 0xe9 || g[2] != 'y' || g[3] != 0) return 1;
+                 if (sizeof h != 8 || h[1] != 0xd83d || h[2] != 0xde00) return 2;
+                 if (sizeof k != 12 || k[0] != 0x20ac || k[1] != 'x') return 3;
+                 if (sizeof n != 3 || n[0] != (char)0xc3 || n[1] != (char)0xa9) return 4;
+                 if (p[4] != 'r' || p[5] != 0 || sizeof(L\"ab\" \"c\") != 16 || sizeof(u\"x\") != 4) return 5;
+                 if (L'é' != 233 || sizeof L'a' != 4 || sizeof u'a' != 2 || U'\\xffffffff' < 0 || L'\\xffffffff' != -1) return 6;
+                 if (u8'a' != 97 || s.s[1] != 'b' || s.s[2] != 0) return 7;
+                 return 0;
+               }";
+    assert_eq!(run("wide_literals", src), 0);
+}
+
+#[test]
+fn aligned_members() {
+    let src = "#include <stdalign.h>
+               #include <stddef.h>
+               struct S { alignas(8) char c; char d; alignas(16) int i; };
+               struct T { char c; struct S s; };
+               int main() {
+                 struct T t;
+                 if (sizeof(struct S) != 32 || alignof(struct S) != 16) return 1;
+                 if (offsetof(struct S, d) != 1 || offsetof(struct S, i) != 16) return 2;
+                 if (offsetof(struct T, s) != 16 || sizeof t != 48) return 3;
+                 if ((long)&t.s.i % 16 != 0) return 4;
+                 return 0;
+               }";
+    assert_eq!(run("aligned_members", src), 0);
+}
+
+#[test]
+fn system_limits() {
+    let src = "#include <limits.h>
+               #include <unistd.h>
+               int main() {
+                 char buf[PATH_MAX];
+                 if (PATH_MAX != 4096 || _POSIX_PATH_MAX != 256) return 1;
+                 if (INT_MAX != 2147483647 || CHAR_BIT != 8 || LLONG_MIN >= 0) return 2;
+                 if (!getcwd(buf, sizeof buf) || buf[0] != '/') return 3;
+                 return 0;
+               }";
+    assert_eq!(run("system_limits", src), 0);
+}
+
+#[test]
+fn packed_and_aligned_attributes() {
+    // <stdio.h> would define `__attribute__` away for other compilers.
+    let src = "#include <stdio.h>
+               #include <stddef.h>
+               struct __attribute__((packed)) P { char c; int i; };
+               struct A { char c; } __attribute__((aligned(16)));
+               struct B { char a; int x : 4; int y : 9; } __attribute__((__packed__));
+               struct M { char c; long l __attribute__((aligned(32))); };
+               typedef struct { int x; } __attribute__((aligned(64))) T;
+               int v __attribute__((aligned(32)));
+               int main() {
+                 char c __attribute__((aligned(16)));
+                 struct P p[2];
+                 struct B b;
+                 if (sizeof(struct P) != 5 || _Alignof(struct P) != 1 || offsetof(struct P, i) != 1) return 1;
+                 if (sizeof(struct A) != 16 || _Alignof(struct A) != 16) return 2;
+                 if (sizeof b != 3 || offsetof(struct M, l) != 32 || sizeof(T) != 64) return 3;
+                 if ((long)&v % 32 != 0 || (long)&c % 16 != 0) return 4;
+                 p[1].c = 1; p[1].i = -5; b.x = -3; b.y = 200;
+                 if (p[1].i != -5 || p[1].c != 1 || b.x != -3 || b.y != 200) return 5;
+                 return 0;
+               }";
+    assert_eq!(run("packed_and_aligned_attributes", src), 0);
+}
+
+#[test]
+fn bit_field_promotion() {
+    let src = "struct S { unsigned x : 5; unsigned long y : 5; unsigned z : 32; unsigned short w : 3; } s;
+               int main() {
+                 s.x = 1; s.y = 1; s.z = 1; s.w = 1;
+                 if (!(s.x - 5 < 0) || !(s.y - 5 < 0) || s.z - 5 < 0) return 1;
+                 if (sizeof(s.y + 0) != 4 || (s.w -= 2) != 7 || !(s.w - 8 < 0)) return 2;
+                 if (!((1 ? s.x : 0) - 5 < 0) || (s.x >> 1) - 1 != -1) return 3;
+                 switch (s.x - 2) { case -1: return 0; }
+                 return 4;
+               }";
+    assert_eq!(run("bit_field_promotion", src), 0);
+}
+
+#[test]
+fn for_loop_declarations() {
+    let src = "int main() {
+                 int i = 100, n = 0;
+                 for (int i = 0, j = 10; i < j; i++, j--)
+                   n += i;
+                 for (int i = 0; i < 3; i++) {
+                   int i = 7;
+                   n += i;
+                 }
+                 for (long k = 0; k < 2; k++)
+                   for (int k = 5; k < 6; k++)
+                     n += k;
+                 return i == 100 && n == 10 + 21 + 10 ? 0 : 1;
+               }";
+    assert_eq!(run("for_loop_declarations", src), 0);
+}
+
+#[test]
+fn argument_count() {
+    let compile = |src: &str| Command::new(env!("CARGO_BIN_EXE_rcc1")).arg(src).output().unwrap();
+    for (src, msg) in [
+        ("int f(int a, int b); int main() { return f(1); }", "too few arguments to function call, expected 2, have 1"),
+        ("int f(void); int main() { return f(1); }", "too many arguments to function call, expected 0, have 1"),
+        ("int main() { int (*p)(int, ...) = 0; return p(); }", "too few arguments to function call, expected 1, have 0"),
+    ] {
+        let out = compile(src);
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains(msg), "{}", src);
+    }
+    // A declaration without a prototype leaves the calls unchecked.
+    let src = "int f();
+               int g(int n, ...) { return n; }
+               int main() { return f(1, 2) + g(3, 4, 5) == 6 ? 0 : 1; }
+               int f(int a, int b) { return a + b; }";
+    assert_eq!(run("argument_count", src), 0);
+}
+
+#[test]
+fn tag_kinds() {
+    let compile = |src: &str| Command::new(env!("CARGO_BIN_EXE_rcc1")).arg(src).output().unwrap();
+    for src in ["enum E { A }; struct E x;", "struct S { int a; }; union S u;", "struct S; enum S { B };", "union U; struct U { int a; };"] {
+        let out = compile(src);
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("defined as wrong kind of tag"), "{}", src);
+    }
+    // An inner scope may declare the tag again with another keyword.
+    let src = "enum E { A = 3 };
+               struct S { int a; };
+               struct S;
+               int main() {
+                 struct S s = {A};
+                 struct E { int a, b; } e;
+                 union S;
+                 union S { char c; } u;
+                 return s.a == 3 && sizeof e == 8 && sizeof u == 1 ? 0 : 1;
+               }";
+    assert_eq!(run("tag_kinds", src), 0);
+}
+
+#[test]
+fn bool_return_values() {
+    // A callee returning `_Bool` sets only al and may leave the rest of eax dirty.
+    let helper = ".intel_syntax noprefix
+                  .globl dirty_true
+                  dirty_true: mov eax, 0x12345601; ret
+                  .globl dirty_false
+                  dirty_false: mov eax, 0xffffff00; ret
+                  .section .note.GNU-stack,\"\",@progbits
+                  ";
+    let src = "_Bool dirty_true(void);
+               _Bool dirty_false(void);
+               int main() {
+                 int t = dirty_true(), f = dirty_false();
+                 long l = dirty_true();
+                 if (t != 1 || f != 0 || l != 1) return 1;
+                 if (dirty_false() || !dirty_true() || dirty_true() + dirty_true() != 2) return 2;
+                 return 0;
+               }";
+    assert_eq!(run_with_asm("bool_return_values", src, helper), 0);
+}